@@ -0,0 +1,215 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// I/O size for sequential operations
+const SEQ_BLOCK: usize = 128 * 1024;
+
+/// I/O size for random reads
+const RAND_BLOCK: usize = 4096;
+
+/// Files created per metadata run
+const METADATA_FILES: usize = 1000;
+
+/// Workload run by `run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchOp {
+    SeqWrite,
+    SeqRead,
+    RandRead,
+    Metadata,
+}
+
+impl FromStr for BenchOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seq-write" => Ok(BenchOp::SeqWrite),
+            "seq-read" => Ok(BenchOp::SeqRead),
+            "rand-read" => Ok(BenchOp::RandRead),
+            "metadata" => Ok(BenchOp::Metadata),
+            _ => Err(format!(
+                "unknown op '{}' (expected seq-write, seq-read, rand-read or metadata)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BenchOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BenchOp::SeqWrite => "seq-write",
+            BenchOp::SeqRead => "seq-read",
+            BenchOp::RandRead => "rand-read",
+            BenchOp::Metadata => "metadata",
+        })
+    }
+}
+
+/// Throughput and latency of one benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub op: BenchOp,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Per-operation latencies, sorted ascending
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1024.0 * 1024.0) / self.elapsed.as_secs_f64()
+    }
+
+    /// Latency at percentile `p` (0-100)
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let idx = ((self.latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.latencies[idx]
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} ops in {:.2?}",
+            self.op,
+            self.latencies.len(),
+            self.elapsed
+        )?;
+        if self.bytes > 0 {
+            writeln!(f, "  throughput: {:.1} MB/s", self.mb_per_sec())?;
+        }
+        writeln!(f, "  rate:       {:.0} op/s", self.ops_per_sec())?;
+        write!(
+            f,
+            "  latency:    p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )
+    }
+}
+
+/// Scratch directory removed when dropped, even if the run fails
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            tracing::warn!("failed to clean up {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Run a workload of `size` bytes in a scratch directory under `path`.
+/// Works against any filesystem, not only sia-fuse mounts.
+pub fn run(path: &Path, op: BenchOp, size: u64) -> io::Result<BenchReport> {
+    let scratch = ScratchDir(path.join(format!(".sia-fuse-bench-{}", std::process::id())));
+    fs::create_dir(&scratch.0)?;
+    let file = scratch.0.join("data");
+
+    let mut latencies = Vec::new();
+    let mut bytes = 0;
+    let start;
+
+    match op {
+        BenchOp::SeqWrite => {
+            start = Instant::now();
+            bytes = write_file(&file, size, &mut latencies)?;
+        }
+        BenchOp::SeqRead => {
+            write_file(&file, size, &mut Vec::new())?;
+            let mut f = File::open(&file)?;
+            let mut buf = vec![0u8; SEQ_BLOCK];
+            start = Instant::now();
+            loop {
+                let t = Instant::now();
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                latencies.push(t.elapsed());
+                bytes += n as u64;
+            }
+        }
+        BenchOp::RandRead => {
+            write_file(&file, size, &mut Vec::new())?;
+            let mut f = File::open(&file)?;
+            let mut buf = vec![0u8; RAND_BLOCK];
+            let blocks = (size / RAND_BLOCK as u64).max(1);
+            let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+            start = Instant::now();
+            for _ in 0..blocks {
+                let t = Instant::now();
+                f.seek(SeekFrom::Start(rng.next() % blocks * RAND_BLOCK as u64))?;
+                bytes += f.read(&mut buf)? as u64;
+                latencies.push(t.elapsed());
+            }
+        }
+        BenchOp::Metadata => {
+            start = Instant::now();
+            for i in 0..METADATA_FILES {
+                let name = scratch.0.join(format!("f{}", i));
+                let t = Instant::now();
+                File::create(&name)?;
+                fs::metadata(&name)?;
+                fs::remove_file(&name)?;
+                latencies.push(t.elapsed());
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    Ok(BenchReport {
+        op,
+        bytes,
+        elapsed,
+        latencies,
+    })
+}
+
+/// Write `size` bytes sequentially and sync, recording per-block latency
+fn write_file(path: &Path, size: u64, latencies: &mut Vec<Duration>) -> io::Result<u64> {
+    let mut f = File::create(path)?;
+    let block = vec![0xa5u8; SEQ_BLOCK];
+    let mut written = 0;
+
+    while written < size {
+        let len = (size - written).min(SEQ_BLOCK as u64) as usize;
+        let t = Instant::now();
+        f.write_all(&block[..len])?;
+        latencies.push(t.elapsed());
+        written += len as u64;
+    }
+    f.sync_all()?;
+
+    Ok(written)
+}
+
+/// Small deterministic generator so runs are reproducible
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,189 @@
+use crate::error::{Result, SiaFuseError};
+use fuser::MountOption;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Default configuration directory, before `~` expansion
+pub const DEFAULT_CONFIG_DIR: &str = "~/.config/sia-fuse";
+
+/// Name of the configuration file inside the configuration directory
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Configuration file written by `sia-fuse init`
+pub const DEFAULT_CONFIG: &str = r#"# sia-fuse configuration
+#
+# Command-line flags given to `sia-fuse mount` override these values.
+
+# Address of the renterd node to store files in
+# renterd_url = "http://localhost:9980"
+
+# renterd API password
+# renterd_password = ""
+
+# Bucket holding the filesystem's objects
+# bucket = "default"
+
+# Show these buckets as top-level directories instead of mounting just
+# `bucket`; mkdir at the root then creates a bucket
+# buckets = ["photos", "backups"]
+
+# Show every bucket in the node as a top-level directory
+# all_buckets = false
+
+# Bytes of a sequentially written file gathered into each upload to
+# renterd, and downloaded at a time for reads (default 4 MiB)
+# chunk_size = 4194304
+
+# Bytes of chunks downloaded from renterd kept in memory for reads
+# (default 256 MiB; 0 downloads whole files instead)
+# read_cache_size = 268435456
+
+# Chunks downloaded ahead of a file being read sequentially (default 4;
+# 0 disables readahead)
+# readahead_chunks = 4
+
+# Encrypt file content before it is uploaded, with a key derived from this
+# passphrase. The salt is stored in the bucket; every mount of the bucket
+# needs the same passphrase, and losing it loses the content.
+# passphrase = ""
+
+# Retries of a renterd request safe to repeat failing with a server error,
+# 429, timeout or failure to connect (default 3), and the wait in ms before
+# the first retry, doubling for each further one (default 100)
+# max_retries = 3
+# retry_base_ms = 100
+
+# Seconds after which a file's size and mtime are checked against renterd
+# when read, to see changes made by other clients (never when unset), and
+# seconds after an upload during which the local ones are kept regardless,
+# as renterd may still report the old ones (default 30)
+# attr_refresh_secs = 60
+# trust_local_secs = 30
+
+# Requests to renterd in flight at once, shared by all file operations;
+# further ones queue in arrival order (default 8)
+# backend_connections = 8
+
+# Downloads and uploads in flight at once, each within backend_connections,
+# so a burst of one doesn't hold up the other (unlimited when unset)
+# max_concurrent_reads = 4
+# max_concurrent_writes = 2
+
+# Bytes per second uploaded to and downloaded from renterd, across all
+# transfers (unlimited when unset; `sia-fuse throttle` changes them while
+# mounted)
+# upload_limit = 1048576
+# download_limit = 4194304
+
+# Directory for locally cached data
+# cache_dir = "~/.cache/sia-fuse"
+
+# Total bytes of file content the mount may hold; writes past it fail
+# with "No space left on device". Unlimited when unset.
+# capacity = 10737418240
+
+# FUSE mount options applied to every mount, e.g. ["allow_other", "noatime"]
+# default_mount_options = []
+"#;
+
+/// Settings read from `config.toml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub renterd_url: Option<String>,
+    pub renterd_password: Option<String>,
+    pub bucket: Option<String>,
+    pub buckets: Vec<String>,
+    pub all_buckets: bool,
+    pub chunk_size: Option<u64>,
+    pub read_cache_size: Option<u64>,
+    pub readahead_chunks: Option<u64>,
+    pub passphrase: Option<String>,
+    pub max_retries: Option<u32>,
+    pub retry_base_ms: Option<u64>,
+    pub attr_refresh_secs: Option<u64>,
+    pub trust_local_secs: Option<u64>,
+    pub backend_connections: Option<usize>,
+    pub max_concurrent_reads: Option<usize>,
+    pub max_concurrent_writes: Option<usize>,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub cache_dir: Option<PathBuf>,
+    pub capacity: Option<u64>,
+    pub default_mount_options: Vec<String>,
+}
+
+impl Config {
+    /// Parse the configuration file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SiaFuseError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| SiaFuseError::Config(format!("{}: {}", path.display(), e)))?;
+
+        config.cache_dir = config.cache_dir.map(|dir| expand_tilde(&dir));
+        Ok(config)
+    }
+
+    /// Load `path`, or the default configuration file when `path` is None.
+    /// A missing default file yields the defaults; a missing explicit file
+    /// is an error.
+    pub fn load_or_default(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::load(&expand_tilde(path)),
+            None => {
+                let path = default_path();
+                if path.exists() {
+                    Self::load(&path)
+                } else {
+                    Ok(Self::default())
+                }
+            }
+        }
+    }
+
+    /// `default_mount_options` as FUSE mount options
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        self.default_mount_options
+            .iter()
+            .map(|option| parse_mount_option(option))
+            .collect()
+    }
+}
+
+/// Path of the configuration file in the default directory
+pub fn default_path() -> PathBuf {
+    expand_tilde(Path::new(DEFAULT_CONFIG_DIR)).join(CONFIG_FILE)
+}
+
+/// Replace a leading `~` with the home directory. Paths for other users'
+/// homes (`~user`) and paths without `~` are returned unchanged.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path.to_path_buf();
+    };
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn parse_mount_option(option: &str) -> MountOption {
+    match option {
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "noatime" => MountOption::NoAtime,
+        "atime" => MountOption::Atime,
+        "nosuid" => MountOption::NoSuid,
+        "nodev" => MountOption::NoDev,
+        "noexec" => MountOption::NoExec,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        "dirsync" => MountOption::DirSync,
+        other => MountOption::CUSTOM(other.to_string()),
+    }
+}
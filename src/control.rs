@@ -0,0 +1,431 @@
+use crate::fuse_impl::{FileHandles, SiaFuseFilesystem, ROOT_INODE};
+use crate::storage::{self, Inode, Owner, Storage};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Request sent to a running mount over its control socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Drop the kernel's cached entry, attributes and data for a path
+    /// (relative to the mount root)
+    Invalidate { path: String },
+
+    /// Change the bytes per second the backend may upload and download,
+    /// where given (0 for unlimited), and report the limits in force
+    Throttle {
+        #[serde(default)]
+        upload: Option<u64>,
+        #[serde(default)]
+        download: Option<u64>,
+    },
+
+    /// Report whether the mount takes changes and how much awaits upload
+    Status,
+
+    /// List the open file handles as JSON
+    Handles,
+
+    /// Create an empty file at a path (relative to the mount root) for
+    /// the connecting user, with `mode` less `umask`. Under auto-mkdir its
+    /// missing parent directories are created too.
+    Create { path: String, mode: u32, umask: u32 },
+}
+
+/// Reply to a `ControlRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl ControlResponse {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+        }
+    }
+}
+
+/// An open file handle, as listed by `ControlRequest::Handles`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandleInfo {
+    pub fh: u64,
+    pub ino: Inode,
+    pub path: Option<String>, // None once the file is unlinked
+    pub flags: i32,           // open(2) flags
+    pub uid: u32,             // Who opened it
+    pub read_offset: u64,     // Where the last read ended
+    pub write_offset: u64,    // Where the last write ended
+}
+
+/// Kernel cache invalidation, as provided by `fuser::Notifier`
+pub trait KernelCache: Send + 'static {
+    fn inval_entry(&self, parent: Inode, name: &OsStr) -> io::Result<()>;
+    fn inval_inode(&self, ino: Inode) -> io::Result<()>;
+}
+
+impl KernelCache for fuser::Notifier {
+    fn inval_entry(&self, parent: Inode, name: &OsStr) -> io::Result<()> {
+        fuser::Notifier::inval_entry(self, parent, name)
+    }
+
+    fn inval_inode(&self, ino: Inode) -> io::Result<()> {
+        fuser::Notifier::inval_inode(self, ino, 0, 0)
+    }
+}
+
+/// Location of the control socket for a mount point. The name is derived
+/// from the canonical mount path so clients can find it from any path
+/// inside the mount. Sockets live in a directory private to the user (see
+/// `socket_dir`), so only the user who mounted can reach them.
+pub fn socket_path(mountpoint: &Path) -> PathBuf {
+    let hash = blake3::hash(mountpoint.as_os_str().as_encoded_bytes());
+    socket_dir().join(format!("{}.sock", &hash.to_hex()[..16]))
+}
+
+/// Directory holding the user's control sockets: `sia-fuse` under
+/// `$XDG_RUNTIME_DIR`, or a per-user directory in the temp dir without one
+fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("sia-fuse"),
+        _ => std::env::temp_dir().join(format!("sia-fuse-{}", Owner::current().uid)),
+    }
+}
+
+/// Create `dir` with mode 0700 if missing, and make sure it is a real
+/// directory only we can enter. In a shared temp dir another user could
+/// have made it first.
+fn ensure_private_dir(dir: &Path) -> io::Result<()> {
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != Owner::current().uid || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory of this user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Find the control socket of the mount containing `path`, returning it
+/// together with `path` relative to the mount root
+pub fn find_socket(path: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    let path = std::path::absolute(path)?;
+
+    for ancestor in path.ancestors() {
+        let mountpoint = ancestor
+            .canonicalize()
+            .unwrap_or_else(|_| ancestor.to_path_buf());
+        let socket = socket_path(&mountpoint);
+        if socket.exists() {
+            let relative = path.strip_prefix(ancestor).unwrap_or(&path).to_path_buf();
+            return Ok((socket, relative));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} is not inside a sia-fuse mount", path.display()),
+    ))
+}
+
+/// Send a single request to a control socket and wait for the reply
+pub fn send(socket: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Serves control requests for a mounted filesystem on a Unix socket
+pub struct ControlServer<K> {
+    storage: Arc<dyn Storage>,
+    cache: K,
+    handles: FileHandles,
+    fs: Option<SiaFuseFilesystem>, // Creates files for `ControlRequest::Create`
+}
+
+impl<K: KernelCache> ControlServer<K> {
+    pub fn new(storage: Arc<dyn Storage>, cache: K) -> Self {
+        Self {
+            storage,
+            cache,
+            handles: FileHandles::default(),
+            fs: None,
+        }
+    }
+
+    /// List the handles in `handles`, the filesystem's table, for
+    /// `ControlRequest::Handles`
+    pub fn with_handles(mut self, handles: FileHandles) -> Self {
+        self.handles = handles;
+        self
+    }
+
+    /// Serve `ControlRequest::Create` through `fs`, which should share the
+    /// mounted filesystem's storage and options
+    pub fn with_filesystem(mut self, fs: SiaFuseFilesystem) -> Self {
+        self.fs = Some(fs);
+        self
+    }
+
+    /// Bind the socket with mode 0600 and serve requests on a background
+    /// thread. The directory from `socket_path` is created on first use.
+    pub fn spawn(self, socket: &Path) -> io::Result<JoinHandle<()>> {
+        let dir = socket_dir();
+        if socket.parent() == Some(dir.as_path()) {
+            ensure_private_dir(&dir)?;
+        }
+
+        // A socket left behind by a crashed mount would make bind fail.
+        // Only remove it if it is a socket of ours.
+        if let Ok(meta) = std::fs::symlink_metadata(socket) {
+            if !meta.file_type().is_socket() || meta.uid() != Owner::current().uid {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not our socket", socket.display()),
+                ));
+            }
+            if UnixStream::connect(socket).is_err() {
+                std::fs::remove_file(socket)?;
+            }
+        }
+
+        let listener = UnixListener::bind(socket)?;
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+        tracing::info!("Control socket listening at {}", socket.display());
+
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.serve(stream) {
+                            tracing::warn!("control connection failed: {}", e);
+                        }
+                    }
+                    Err(e) => tracing::warn!("control socket accept failed: {}", e),
+                }
+            }
+        }))
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => self.handle(request, peer_owner(&stream)?),
+            Err(e) => ControlResponse::error(format!("invalid request: {}", e)),
+        };
+
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        (&stream).write_all(reply.as_bytes())
+    }
+
+    /// Execute a single control request sent by `caller`
+    pub fn handle(&self, request: ControlRequest, caller: Owner) -> ControlResponse {
+        tracing::debug!("control request: {:?}", request);
+
+        match request {
+            ControlRequest::Invalidate { path } => match self.invalidate(&path) {
+                Ok(ino) => ControlResponse::ok(format!("invalidated {} (ino {})", path, ino)),
+                Err(e) => ControlResponse::error(format!("{}: {}", path, e)),
+            },
+            ControlRequest::Throttle { upload, download } => self.throttle(upload, download),
+            ControlRequest::Status => self.status(),
+            ControlRequest::Handles => match serde_json::to_string_pretty(&self.handles()) {
+                Ok(json) => ControlResponse::ok(json),
+                Err(e) => ControlResponse::error(format!("cannot encode handles: {}", e)),
+            },
+            ControlRequest::Create { path, mode, umask } => self.create(caller, &path, mode, umask),
+        }
+    }
+
+    /// The open file handles, in the order they were opened
+    pub fn handles(&self) -> Vec<HandleInfo> {
+        let mut handles: Vec<HandleInfo> = self
+            .handles
+            .lock()
+            .iter()
+            .map(|(&fh, handle)| HandleInfo {
+                fh,
+                ino: handle.ino,
+                path: None,
+                flags: handle.flags,
+                uid: handle.uid,
+                read_offset: handle.next_read,
+                write_offset: handle.next_write,
+            })
+            .collect();
+        handles.sort_by_key(|handle| handle.fh);
+
+        // Resolved after the table is unlocked, as storage calls can block
+        for handle in &mut handles {
+            handle.path = self
+                .storage
+                .path_of(handle.ino)
+                .map(|path| path.to_string_lossy().into_owned());
+        }
+        handles
+    }
+
+    /// Describe the storage's state
+    fn status(&self) -> ControlResponse {
+        let stats = self.storage.stats();
+        let state = match stats.read_only {
+            true => "read-only: the backend refuses changes",
+            false => "read-write",
+        };
+        ControlResponse::ok(format!(
+            "{}; {} files ({} bytes) awaiting upload",
+            state, stats.pending_uploads, stats.dirty_bytes
+        ))
+    }
+
+    /// Apply new bandwidth limits and describe the ones in force
+    fn throttle(&self, upload: Option<u64>, download: Option<u64>) -> ControlResponse {
+        let Some(bandwidth) = self.storage.bandwidth() else {
+            return ControlResponse::error("the storage backend has no bandwidth limits");
+        };
+        if let Some(rate) = upload {
+            bandwidth.upload.set_rate(rate);
+        }
+        if let Some(rate) = download {
+            bandwidth.download.set_rate(rate);
+        }
+        if upload.is_some() || download.is_some() {
+            tracing::info!(
+                "bandwidth limits set to {} up, {} down",
+                describe_rate(bandwidth.upload.rate()),
+                describe_rate(bandwidth.download.rate())
+            );
+        }
+
+        ControlResponse::ok(format!(
+            "upload: {}, download: {}",
+            describe_rate(bandwidth.upload.rate()),
+            describe_rate(bandwidth.download.rate())
+        ))
+    }
+
+    /// Create a file for `caller` as the filesystem's create would, then
+    /// drop the kernel's cached attributes of the directories on its path,
+    /// whose sizes and link counts may have changed
+    fn create(&self, caller: Owner, path: &str, mode: u32, umask: u32) -> ControlResponse {
+        let Some(fs) = &self.fs else {
+            return ControlResponse::error("this mount takes no creates");
+        };
+        let owner = fs.creator(caller.uid, caller.gid);
+        let attr = match fs.create_file_at(owner, ROOT_INODE, path, mode, umask) {
+            Ok(attr) => attr,
+            Err(e) => return ControlResponse::error(format!("{}: {}", path, e)),
+        };
+
+        let dirs = storage::split_path(path).map_or("", |(dirs, _)| dirs);
+        let mut dir = Some(ROOT_INODE);
+        let mut names = dirs.split('/').filter(|c| !c.is_empty());
+        while let Some(ino) = dir {
+            if let Err(e) = self.cache.inval_inode(ino) {
+                tracing::debug!("failed to invalidate ino {}: {}", ino, e);
+            }
+            dir = names
+                .next()
+                .and_then(|name| self.storage.lookup(ino, name))
+                .map(|attr| attr.ino);
+        }
+        ControlResponse::ok(format!("created {} (ino {})", path, attr.ino))
+    }
+
+    /// Resolve a path from the root and drop everything the kernel has
+    /// cached for it: the directory entry and the inode's attributes/data
+    fn invalidate(&self, path: &str) -> io::Result<Inode> {
+        let mut parent = None;
+        let mut ino = 1;
+
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let attr = self
+                .storage
+                .lookup(ino, name)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            parent = Some((ino, name));
+            ino = attr.ino;
+        }
+
+        if let Some((parent, name)) = parent {
+            self.cache.inval_entry(parent, OsStr::new(name))?;
+        }
+        self.cache.inval_inode(ino)?;
+
+        Ok(ino)
+    }
+}
+
+/// User and group of the process at the other end of `stream`
+#[cfg(target_os = "linux")]
+fn peer_owner(stream: &UnixStream) -> io::Result<Owner> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let found = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if found != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Owner {
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// User and group of the process at the other end of `stream`
+#[cfg(not(target_os = "linux"))]
+fn peer_owner(stream: &UnixStream) -> io::Result<Owner> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Owner { uid, gid })
+}
+
+fn describe_rate(rate: u64) -> String {
+    match rate {
+        0 => "unlimited".to_string(),
+        rate => format!("{} bytes/s", rate),
+    }
+}
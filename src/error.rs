@@ -0,0 +1,166 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors returned by the sia-fuse library
+#[derive(Debug, Error)]
+pub enum SiaFuseError {
+    /// No inode or directory entry by that name/number
+    #[error("no such file or directory")]
+    NotFound,
+
+    /// A directory entry with that name already exists
+    #[error("file exists")]
+    AlreadyExists,
+
+    /// A directory was expected
+    #[error("not a directory")]
+    NotADirectory,
+
+    /// A non-directory was expected
+    #[error("is a directory")]
+    IsADirectory,
+
+    /// The directory still has entries
+    #[error("directory not empty")]
+    NotEmpty,
+
+    /// The target is in use, e.g. the root directory
+    #[error("device or resource busy")]
+    Busy,
+
+    /// The caller may not perform the operation
+    #[error("permission denied")]
+    PermissionDenied,
+
+    /// The operation is not allowed on this kind of inode
+    #[error("operation not permitted")]
+    NotPermitted,
+
+    /// A path component is longer than the filesystem allows
+    #[error("file name too long")]
+    NameTooLong,
+
+    /// The storage quota is exhausted
+    #[error("no space left on device")]
+    NoSpace,
+
+    /// The filesystem doesn't implement the requested variant of an
+    /// operation, e.g. an fallocate mode
+    #[error("operation not supported: {0}")]
+    NotSupported(String),
+
+    /// SEEK_DATA/SEEK_HOLE found nothing at or after the offset
+    #[error("no data or hole past the offset")]
+    NoSeekTarget,
+
+    /// The file handle isn't open for the operation, e.g. a write through
+    /// a read-only handle
+    #[error("bad file descriptor")]
+    BadFileHandle,
+
+    /// A rename would move an entry between places the backend keeps
+    /// apart, e.g. two renterd buckets
+    #[error("invalid cross-device link")]
+    CrossDevice,
+
+    /// The file is generated by the filesystem and can't be changed
+    #[error("read-only file")]
+    ReadOnly,
+
+    /// The request itself is malformed
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// Mounting or serving the FUSE session failed
+    #[error("failed to mount filesystem")]
+    Mount(#[source] io::Error),
+
+    /// Unmounting failed, or the means to unmount on a signal couldn't be
+    /// set up
+    #[error("failed to unmount filesystem")]
+    Unmount(#[source] io::Error),
+
+    /// The configuration could not be loaded or is invalid
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    /// Stored content failed authentication: it was altered, truncated or
+    /// encrypted with another key
+    #[error("corrupted content: {0}")]
+    Corrupted(String),
+
+    /// The storage backend reported a failure
+    #[error("backend error: {0}")]
+    Backend(String),
+
+    /// The storage backend answered with an HTTP error status
+    #[error("backend returned HTTP {status}: {message}")]
+    Http { status: u16, message: String },
+
+    /// The storage backend didn't answer in time
+    #[error("backend timed out: {0}")]
+    TimedOut(String),
+
+    /// The storage backend couldn't be reached, or dropped the connection
+    #[error("backend unreachable: {0}")]
+    Unreachable(String),
+
+    /// The sqlite database behind the storage failed
+    #[cfg(feature = "sqlite")]
+    #[error("database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl SiaFuseError {
+    /// The errno reported to the kernel for this error
+    pub fn errno(&self) -> i32 {
+        match self {
+            SiaFuseError::NotFound => libc::ENOENT,
+            SiaFuseError::AlreadyExists => libc::EEXIST,
+            SiaFuseError::NotADirectory => libc::ENOTDIR,
+            SiaFuseError::IsADirectory => libc::EISDIR,
+            SiaFuseError::NotEmpty => libc::ENOTEMPTY,
+            SiaFuseError::Busy => libc::EBUSY,
+            SiaFuseError::PermissionDenied => libc::EACCES,
+            SiaFuseError::NotPermitted => libc::EPERM,
+            SiaFuseError::NameTooLong => libc::ENAMETOOLONG,
+            SiaFuseError::NoSpace => libc::ENOSPC,
+            SiaFuseError::NotSupported(_) => libc::EOPNOTSUPP,
+            SiaFuseError::NoSeekTarget => libc::ENXIO,
+            SiaFuseError::BadFileHandle => libc::EBADF,
+            SiaFuseError::CrossDevice => libc::EXDEV,
+            SiaFuseError::ReadOnly => libc::EROFS,
+            SiaFuseError::InvalidArgument(_) => libc::EINVAL,
+            SiaFuseError::Mount(_)
+            | SiaFuseError::Unmount(_)
+            | SiaFuseError::Config(_)
+            | SiaFuseError::Corrupted(_)
+            | SiaFuseError::Backend(_)
+            | SiaFuseError::Unreachable(_) => libc::EIO,
+            SiaFuseError::Http { status, .. } => http_status_to_errno(*status),
+            // Callers of file operations don't expect ETIMEDOUT
+            SiaFuseError::TimedOut(_) => libc::EIO,
+            #[cfg(feature = "sqlite")]
+            SiaFuseError::Database(_) => libc::EIO,
+            SiaFuseError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        }
+    }
+}
+
+/// The errno for a failed HTTP request to the storage backend: missing
+/// objects, refused credentials, conflicts and a full node keep their
+/// meaning, anything else is an I/O error
+pub fn http_status_to_errno(status: u16) -> i32 {
+    match status {
+        404 => libc::ENOENT,
+        401 | 403 => libc::EACCES,
+        409 => libc::EEXIST,
+        507 => libc::ENOSPC,
+        _ => libc::EIO,
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SiaFuseError>;
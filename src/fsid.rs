@@ -0,0 +1,61 @@
+use crate::error::{Result, SiaFuseError};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Identity of a filesystem, kept across remounts, written as a
+/// hyphenated UUID such as `0c9a5a4e-5f59-4d1b-9b76-3f2b7e0f6a41`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FsUuid([u8; 16]);
+
+impl FsUuid {
+    /// A random (version 4) UUID
+    pub fn generate() -> Result<Self> {
+        let mut bytes = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        bytes[6] = (bytes[6] & 0x0f) | 0x40; // Version 4
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl FromStr for FsUuid {
+    type Err = SiaFuseError;
+
+    /// Parse the hyphenated 8-4-4-4-12 form, in either case
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || SiaFuseError::InvalidArgument(format!("invalid UUID '{}'", s));
+        let groups: Vec<&str> = s.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if lengths != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+
+        let hex = groups.concat();
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for FsUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -1,69 +1,1202 @@
-use crate::storage::{FileKind, Inode, InMemoryStorage};
+use crate::error::{Result, SiaFuseError};
+use crate::fsid::FsUuid;
+use crate::metrics::{Metrics, Op, OpTimer};
+use crate::storage::{self, DirEntry, FileAttr, FileKind, InMemoryStorage, Inode, Owner, Storage};
+use chrono::{DateTime, Utc};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    FileType, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyLseek, ReplyOpen, ReplyStatfs,
+    ReplyWrite, ReplyXattr, Request,
 };
+use parking_lot::Mutex;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-const TTL: Duration = Duration::from_secs(1);
+/// Default time the kernel may cache attributes and directory entries
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+/// Block size reported by statfs, matching `blksize` in `to_fuser_attr`
+const BLOCK_SIZE: u64 = 4096;
+
+/// Longest accepted path component, in bytes (NAME_MAX)
+const MAX_NAME_LEN: usize = 255;
+
+/// Extended attribute reporting when an inode was last synced with the backend
+const XATTR_SYNCED: &str = "user.sia.synced";
+
+/// Extended attribute reporting the bytes stored for a file, which can be
+/// less than its logical size
+const XATTR_ALLOCATED: &str = "user.sia.allocated";
+
+/// Extended attribute reporting the size of the parts a file's content is
+/// uploaded in, for backends that upload in chunks. Setting it on an empty
+/// file picks that file's chunk size.
+const XATTR_CHUNK_SIZE: &str = "user.sia.chunk_size";
+
+/// Extended attribute reporting how many stored objects share a file's
+/// content, for backends that deduplicate
+const XATTR_DEDUP_REFS: &str = "user.sia.dedup_refs";
+
+/// Extended attributes reporting how the backend stores a file: its object
+/// key, health, redundancy and contract set. They are fetched from the
+/// backend when read, so they aren't listed.
+const XATTR_KEY: &str = "user.sia.key";
+const XATTR_HEALTH: &str = "user.sia.health";
+const XATTR_REDUNDANCY: &str = "user.sia.redundancy";
+const XATTR_CONTRACT_SET: &str = "user.sia.contract_set";
+
+/// Root inode
+pub(crate) const ROOT_INODE: Inode = 1;
+
+/// Synthetic read-only file at the root listing every file's checksum
+const CHECKSUMS_NAME: &str = ".checksums";
+const CHECKSUMS_INODE: Inode = u64::MAX - 1;
+const CHECKSUMS_COOKIE: u64 = i64::MAX as u64;
+
+/// Synthetic read-only file at the root holding filesystem statistics as
+/// JSON. There with `stats`, but only listed with `show_control_files`.
+const STATS_NAME: &str = ".sia-stats";
+const STATS_INODE: Inode = u64::MAX - 2;
+const STATS_COOKIE: u64 = i64::MAX as u64 - 1;
+
+/// Mount-time behavior switches
+#[derive(Debug, Clone)]
+pub struct FsOptions {
+    /// Umask applied to newly created files and directories in place of
+    /// the caller's
+    pub umask: Option<u32>,
+    /// Apply the umask the kernel passes with each create/mkdir request
+    /// instead of the configured `umask`
+    pub umask_from_request: bool,
+    /// Names reserved for control files that users may not create
+    pub reserved_names: Vec<String>,
+    /// Expose a `.checksums` file at the root listing file checksums
+    pub checksums: bool,
+    /// Expose a `.sia-stats` file at the root holding statistics as JSON
+    pub stats: bool,
+    /// List control files such as `.sia-stats` in the root directory
+    pub show_control_files: bool,
+    /// Bump mtime when a file is opened for writing, before any bytes are
+    /// written. Helps sync tools spot touched files, but makes mtime-driven
+    /// build tools (make, ninja) see files opened read-write as changed.
+    pub touch_on_open_write: bool,
+    /// How long the kernel may cache attributes. Zero disables caching so
+    /// every stat reaches the filesystem.
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache name lookups. Zero disables caching.
+    pub entry_ttl: Duration,
+    /// Read every write back from storage and compare it to what was sent.
+    /// Expensive; meant for debugging the storage path. Reads pass write-back
+    /// buffers on first, so under a `CacheLayer` this writes through.
+    pub write_verify: bool,
+    /// With `write_verify`, fail mismatched writes with EIO instead of
+    /// only logging them
+    pub write_verify_strict: bool,
+    /// Group stamped on files and directories created by uid 0
+    pub root_create_gid: Option<u32>,
+    /// Mode given to files created without any permission bits, which
+    /// would otherwise be inaccessible
+    pub default_file_mode: u32,
+    /// Create missing parent directories of a file being created by path
+    /// (over the control socket), like S3 key prefixes, instead of failing
+    /// with ENOENT
+    pub auto_mkdir: bool,
+    /// Largest write the kernel may send in one request, or None for
+    /// fuser's 16 MiB. `write` stores the request buffer as it comes, so
+    /// this only bounds how much one request holds.
+    pub max_write: Option<u32>,
+    /// Identity of the filesystem, reported in `.sia-stats`. The kernel
+    /// picks statfs's f_fsid itself, so it can't be reported there.
+    pub uuid: Option<FsUuid>,
+}
+
+impl Default for FsOptions {
+    fn default() -> Self {
+        Self {
+            umask: None,
+            umask_from_request: false,
+            reserved_names: Vec::new(),
+            checksums: false,
+            stats: false,
+            show_control_files: false,
+            touch_on_open_write: false,
+            attr_ttl: DEFAULT_TTL,
+            entry_ttl: DEFAULT_TTL,
+            write_verify: false,
+            write_verify_strict: false,
+            root_create_gid: None,
+            default_file_mode: 0o644,
+            auto_mkdir: false,
+            max_write: None,
+            uuid: None,
+        }
+    }
+}
+
+/// A file opened with open or create
+#[derive(Debug, Clone)]
+pub struct FileHandle {
+    pub ino: Inode,
+    pub flags: i32,                 // open(2) flags, less O_CREAT/O_EXCL/O_NOCTTY
+    pub uid: u32,                   // Who opened it
+    pub next_read: u64,             // Where the last read ended, to spot sequential reads
+    pub next_write: u64,            // Where the last write ended
+    pub content: Option<Arc<[u8]>>, // A control file's content as of open
+}
+
+/// Open file handles by number, shared with the control socket so they
+/// can be listed while mounted
+pub type FileHandles = Arc<Mutex<HashMap<u64, FileHandle>>>;
+
+impl FileHandle {
+    /// `O_RDONLY`, `O_WRONLY` or `O_RDWR`
+    pub fn access_mode(&self) -> i32 {
+        self.flags & libc::O_ACCMODE
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.access_mode() != libc::O_RDONLY
+    }
+}
 
 pub struct SiaFuseFilesystem {
-    storage: InMemoryStorage,
+    storage: Arc<dyn Storage>,
+    options: FsOptions,
+    metrics: Arc<Metrics>,
+    next_fh: u64,
+    dir_handles: HashMap<u64, Vec<DirEntry>>, // Listings snapshotted by opendir
+    file_handles: FileHandles,
+}
+
+impl Default for SiaFuseFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SiaFuseFilesystem {
     pub fn new() -> Self {
+        Self::with_options(FsOptions::default())
+    }
+
+    pub fn with_options(options: FsOptions) -> Self {
+        Self::with_storage(Box::new(InMemoryStorage::new()), options)
+    }
+
+    /// Serve an existing storage, e.g. one configured with limits or
+    /// backed by a database
+    pub fn with_storage(storage: Box<dyn Storage>, options: FsOptions) -> Self {
         tracing::info!("Initializing SiaFuseFilesystem");
         Self {
-            storage: InMemoryStorage::new(),
+            storage: storage.into(),
+            options,
+            metrics: Arc::new(Metrics::new()),
+            next_fh: 1,
+            dir_handles: HashMap::new(),
+            file_handles: FileHandles::default(),
+        }
+    }
+
+    /// Another front end onto this filesystem's storage, options and open
+    /// files, e.g. for a `ControlServer` to create files by the same rules
+    /// while a session owns this one. It must not open files itself.
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            options: self.options.clone(),
+            metrics: self.metrics.clone(),
+            next_fh: 0,
+            dir_handles: HashMap::new(),
+            file_handles: self.file_handles.clone(),
+        }
+    }
+
+    /// Shared handle to the underlying storage
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Operation counters, e.g. for a `MetricsServer`
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Table of open file handles, e.g. for a `ControlServer`
+    pub fn file_handles(&self) -> &FileHandles {
+        &self.file_handles
+    }
+
+    /// Hand out a file handle number that isn't in use
+    fn allocate_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    /// Record a file newly opened by `uid` and return its handle. The
+    /// storage keeps the file alive, even unlinked, until the handle is
+    /// released.
+    fn add_file_handle(&mut self, uid: u32, ino: Inode, flags: i32) -> u64 {
+        let fh = self.allocate_fh();
+        self.file_handles.lock().insert(
+            fh,
+            FileHandle {
+                ino,
+                flags,
+                uid,
+                next_read: 0,
+                next_write: 0,
+                content: None,
+            },
+        );
+        self.storage.hold(ino);
+        fh
+    }
+
+    /// Open a control file, generating the content its reads are served
+    /// from until it is released
+    fn add_control_handle(&mut self, uid: u32, ino: Inode, flags: i32) -> Result<u64> {
+        let content = self.control_content(ino).ok_or(SiaFuseError::NotFound)?;
+        let fh = self.allocate_fh();
+        self.file_handles.lock().insert(
+            fh,
+            FileHandle {
+                ino,
+                flags,
+                uid,
+                next_read: 0,
+                next_write: 0,
+                content: Some(content.into()),
+            },
+        );
+        Ok(fh)
+    }
+
+    /// State of the file opened as `fh`
+    pub fn file_handle(&self, fh: u64) -> Option<FileHandle> {
+        self.file_handles.lock().get(&fh).cloned()
+    }
+
+    /// Note a read of `len` bytes at `offset` through `fh`, reading ahead
+    /// when it carries on where the handle's last read ended
+    pub(crate) fn track_read(&mut self, fh: u64, offset: u64, len: usize) {
+        let (ino, readahead) = {
+            let mut handles = self.file_handles.lock();
+            let Some(handle) = handles.get_mut(&fh) else {
+                return;
+            };
+            let sequential = offset == handle.next_read;
+            handle.next_read = offset + len as u64;
+            (
+                handle.ino,
+                sequential && len > 0 && handle.content.is_none(),
+            )
+        };
+        if readahead {
+            self.storage.readahead(ino, offset + len as u64);
         }
     }
 
-    fn inode_to_path(&self, _ino: Inode) -> String {
-        // For POC, we don't track full paths yet
-        format!("inode_{}", _ino)
+    /// Forget the file opened as `fh`, as on release. An unlinked file is
+    /// deleted once its last handle goes.
+    pub(crate) fn release_file(&mut self, fh: u64) -> Result<()> {
+        let handle = self
+            .file_handles
+            .lock()
+            .remove(&fh)
+            .ok_or(SiaFuseError::BadFileHandle)?;
+        if handle.content.is_some() {
+            return Ok(());
+        }
+        self.storage.release(handle.ino)
+    }
+
+    /// Entries of the directory opened as `fh`, as of opendir. Handles that
+    /// weren't opened here fall back to the current listing.
+    fn open_dir_entries(&self, ino: Inode, fh: u64) -> Option<Cow<'_, [DirEntry]>> {
+        match self.dir_handles.get(&fh) {
+            Some(entries) => Some(Cow::Borrowed(entries)),
+            None => self.dir_entries(ino).map(Cow::Owned),
+        }
+    }
+
+    /// Check a name about to be created against the reserved control-file
+    /// names, logging why it was refused
+    fn is_reserved(&self, name: &str) -> bool {
+        let reserved = self.options.reserved_names.iter().any(|r| r == name)
+            || (self.options.checksums && name == CHECKSUMS_NAME)
+            || (self.options.stats && name == STATS_NAME);
+        if reserved {
+            tracing::warn!("refusing to create '{}': name is reserved", name);
+        }
+        reserved
+    }
+
+    /// Validate a name about to be added to a directory
+    fn check_new_name(&self, name: &str) -> Result<()> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(SiaFuseError::NameTooLong);
+        }
+        if self.is_reserved(name) {
+            return Err(SiaFuseError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// The owner of a node created by `uid` (with group `gid`), applying
+    /// `root_create_gid` to nodes created by root
+    pub(crate) fn creator(&self, uid: u32, gid: u32) -> Owner {
+        let gid = match self.options.root_create_gid {
+            Some(root_gid) if uid == 0 => root_gid,
+            _ => gid,
+        };
+        Owner { uid, gid }
+    }
+
+    /// Read back a just-written range and check it matches `data`
+    fn verify_write(&self, ino: Inode, offset: usize, data: &[u8]) -> bool {
+        let mut matches = false;
+        self.storage
+            .read_with(ino, offset, data.len(), &mut |stored| {
+                matches = stored == data
+            });
+        if !matches {
+            self.metrics.add_verify_mismatch();
+            tracing::error!(
+                "write verification failed for ino {} at offset {} ({} bytes)",
+                ino,
+                offset,
+                data.len()
+            );
+        }
+        matches
+    }
+
+    /// Entries of a directory, including synthetic control files
+    pub(crate) fn dir_entries(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        let mut entries = self.storage.read_dir(ino)?;
+        if self.options.stats && self.options.show_control_files && ino == ROOT_INODE {
+            entries.push(DirEntry {
+                ino: STATS_INODE,
+                name: STATS_NAME.to_string(),
+                kind: FileKind::File,
+                cookie: STATS_COOKIE,
+            });
+        }
+        if self.options.checksums && ino == ROOT_INODE {
+            entries.push(DirEntry {
+                ino: CHECKSUMS_INODE,
+                name: CHECKSUMS_NAME.to_string(),
+                kind: FileKind::File,
+                cookie: CHECKSUMS_COOKIE,
+            });
+        }
+        Some(entries)
+    }
+
+    /// Render the `.checksums` listing, one `<path> <blake3>` line per file,
+    /// from the current state of the tree
+    fn checksums_listing(&self) -> Vec<u8> {
+        let mut listing = String::new();
+        for (path, ino) in self.storage.file_paths() {
+            if let Some(hash) = self.storage.checksum(ino) {
+                listing.push_str(&format!("{} {}\n", path, hash.to_hex()));
+            }
+        }
+        listing.into_bytes()
+    }
+
+    /// Whether `ino` is one of the synthetic control files
+    fn is_control_file(&self, ino: Inode) -> bool {
+        (self.options.checksums && ino == CHECKSUMS_INODE)
+            || (self.options.stats && ino == STATS_INODE)
+    }
+
+    /// Current content of a control file, generated on every call
+    fn control_content(&self, ino: Inode) -> Option<Vec<u8>> {
+        match ino {
+            CHECKSUMS_INODE if self.options.checksums => Some(self.checksums_listing()),
+            STATS_INODE if self.options.stats => Some(
+                self.metrics
+                    .stats_json(&self.storage.stats(), self.options.uuid)
+                    .into_bytes(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Content of a control file as seen through `fh`: the snapshot taken
+    /// by open, or the current content without a handle
+    fn control_data(&self, fh: u64, ino: Inode) -> Option<Arc<[u8]>> {
+        match self.file_handles.lock().get(&fh) {
+            Some(FileHandle {
+                ino: opened,
+                content: Some(content),
+                ..
+            }) if *opened == ino => Some(content.clone()),
+            _ => self.control_content(ino).map(Arc::from),
+        }
+    }
+
+    /// Refuse changes to a control file
+    fn check_not_control(&self, ino: Inode) -> Result<()> {
+        if self.is_control_file(ino) {
+            return Err(SiaFuseError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Attributes of a synthetic control file, owned by the root's owner.
+    /// The size is reported as 0: the content only exists once opened, and
+    /// reads bypass the page cache and go on to EOF.
+    fn control_attr(&self, ino: Inode) -> Option<FileAttr> {
+        let root = self.storage.get_attr(ROOT_INODE)?;
+        let now = Utc::now();
+
+        Some(FileAttr {
+            ino,
+            size: 0,
+            kind: FileKind::File,
+            perm: 0o444,
+            nlink: 1,
+            uid: root.uid,
+            gid: root.gid,
+            rdev: 0,
+            flags: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        })
+    }
+
+    /// Permission bits for a new inode, without the file type bits. The
+    /// configured umask replaces the caller's unless `umask_from_request`
+    /// is set; with none configured the caller's applies.
+    fn apply_umask(&self, mode: u32, request_umask: u32) -> u16 {
+        let umask = match self.options.umask {
+            Some(umask) if !self.options.umask_from_request => umask,
+            _ => request_umask,
+        };
+        (mode & !umask & 0o7777) as u16
+    }
+}
+
+/// The fields a setattr call asks to change
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AttrChanges {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<DateTime<Utc>>,
+    pub mtime: Option<DateTime<Utc>>,
+    pub times_now: bool, // The times given are the current time (UTIME_NOW)
+    pub fh: Option<u64>, // Handle the change is made through, as by ftruncate
+}
+
+impl AttrChanges {
+    fn is_empty(&self) -> bool {
+        self.mode.is_none()
+            && self.uid.is_none()
+            && self.gid.is_none()
+            && self.size.is_none()
+            && self.atime.is_none()
+            && self.mtime.is_none()
+    }
+}
+
+/// Filesystem operations with the kernel plumbing stripped off. The FUSE
+/// handlers below and `TestSession` both go through these.
+impl SiaFuseFilesystem {
+    pub(crate) fn lookup_entry(&self, parent: Inode, name: &str) -> Result<FileAttr> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(SiaFuseError::NameTooLong);
+        }
+        let attr = match name {
+            CHECKSUMS_NAME if self.options.checksums && parent == ROOT_INODE => {
+                self.control_attr(CHECKSUMS_INODE)
+            }
+            STATS_NAME if self.options.stats && parent == ROOT_INODE => {
+                self.control_attr(STATS_INODE)
+            }
+            _ => self.storage.lookup(parent, name),
+        };
+        attr.ok_or(SiaFuseError::NotFound)
+    }
+
+    pub(crate) fn attr(&self, ino: Inode) -> Result<FileAttr> {
+        let attr = if self.is_control_file(ino) {
+            self.control_attr(ino)
+        } else {
+            self.storage.get_attr(ino)
+        };
+        attr.ok_or(SiaFuseError::NotFound)
+    }
+
+    /// Copy out up to `size` bytes at `offset`, through `fh` if the file
+    /// is open (0 otherwise)
+    pub(crate) fn read_data(
+        &self,
+        fh: u64,
+        ino: Inode,
+        offset: usize,
+        size: usize,
+    ) -> Result<Vec<u8>> {
+        if let Some(content) = self.control_data(fh, ino) {
+            let start = std::cmp::min(offset, content.len());
+            let end = std::cmp::min(start.saturating_add(size), content.len());
+            return Ok(content[start..end].to_vec());
+        }
+        self.check_not_special(ino)?;
+        self.storage
+            .read(ino, offset, size)
+            .ok_or_else(|| self.read_failed(ino))
+    }
+
+    /// Push a file's changes to the backend. Control files have none.
+    pub(crate) fn sync_file(&self, ino: Inode) -> Result<()> {
+        if self.is_control_file(ino) {
+            return Ok(());
+        }
+        self.storage.sync(ino)
+    }
+
+    /// The whole content of a file. Control files report size 0, so theirs
+    /// is generated rather than read up to the size.
+    pub(crate) fn read_to_end(&self, ino: Inode) -> Result<Vec<u8>> {
+        if let Some(content) = self.control_content(ino) {
+            return Ok(content);
+        }
+        let size = self.attr(ino)?.size;
+        self.read_data(0, ino, 0, size as usize)
+    }
+
+    /// Why a read of `ino` got nothing: the file is gone, or its content
+    /// couldn't be loaded (e.g. a failed download), which is an I/O error
+    fn read_failed(&self, ino: Inode) -> SiaFuseError {
+        match self.storage.get_attr(ino) {
+            Some(_) => SiaFuseError::Backend(format!("cannot read inode {}", ino)),
+            None => SiaFuseError::NotFound,
+        }
+    }
+
+    /// Open a file with open(2) `flags` on behalf of `owner`/`gid`, checking
+    /// the file's permissions against the access mode and truncating it
+    /// for O_TRUNC, and return its handle
+    pub(crate) fn open_file(&mut self, uid: u32, gid: u32, ino: Inode, flags: i32) -> Result<u64> {
+        // Control files are generated here and read from the handle, so a
+        // reader sees one consistent snapshot
+        if self.is_control_file(ino) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+                return Err(SiaFuseError::ReadOnly);
+            }
+            return self.add_control_handle(uid, ino, flags);
+        }
+        self.check_not_special(ino)?;
+        let attr = self.storage.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        let access = flags & libc::O_ACCMODE;
+
+        let mut mask = match access {
+            libc::O_RDONLY => libc::R_OK,
+            libc::O_WRONLY => libc::W_OK,
+            _ => libc::R_OK | libc::W_OK,
+        };
+        if flags & libc::O_TRUNC != 0 {
+            mask |= libc::W_OK;
+        }
+        self.check_access(uid, gid, ino, mask)?;
+
+        if flags & libc::O_TRUNC != 0 {
+            self.drop_setid(uid, ino)?;
+            // Truncating an empty file still counts as a change
+            if attr.size == 0 {
+                self.storage.touch(ino)?;
+            } else {
+                self.storage.truncate(ino, 0)?;
+            }
+        } else if self.options.touch_on_open_write && access != libc::O_RDONLY {
+            self.storage.touch(ino)?;
+        }
+
+        Ok(self.add_file_handle(uid, ino, flags))
+    }
+
+    /// Fail unless `fh` may be written through. Handles this filesystem
+    /// didn't hand out, such as 0, are let through.
+    pub(crate) fn check_writable(&self, fh: u64) -> Result<()> {
+        match self.file_handles.lock().get(&fh) {
+            Some(handle) if !handle.is_writable() => Err(SiaFuseError::BadFileHandle),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write through the handle `fh` on behalf of `owner`: refused for
+    /// read-only handles, and for O_APPEND handles at the end of the file
+    /// whatever `offset` says. An empty write changes nothing, not even the
+    /// modification time.
+    pub(crate) fn write_handle(
+        &self,
+        uid: u32,
+        fh: u64,
+        ino: Inode,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.check_writable(fh)?;
+        self.drop_setid(uid, ino)?;
+        let append = self
+            .file_handle(fh)
+            .is_some_and(|handle| handle.flags & libc::O_APPEND != 0);
+        let (written, end) = if append {
+            let written = self.append_data(ino, data)?;
+            let size = self.storage.get_attr(ino).map_or(0, |attr| attr.size);
+            (written, size)
+        } else {
+            let written = self.write_data(ino, offset, data)?;
+            (written, (offset + written) as u64)
+        };
+        if let Some(handle) = self.file_handles.lock().get_mut(&fh) {
+            handle.next_write = end;
+        }
+        Ok(written)
+    }
+
+    /// Append to a file atomically, so concurrent appenders never
+    /// overwrite each other
+    pub(crate) fn append_data(&self, ino: Inode, data: &[u8]) -> Result<usize> {
+        self.check_not_control(ino)?;
+        self.check_not_special(ino)?;
+        let (offset, written) = self.storage.append(ino, data)?;
+        if self.options.write_verify
+            && !self.verify_write(ino, offset, &data[..written])
+            && self.options.write_verify_strict
+        {
+            return Err(SiaFuseError::Backend(
+                "write verification failed".to_string(),
+            ));
+        }
+        Ok(written)
+    }
+
+    fn write_data(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        self.check_not_control(ino)?;
+        self.check_not_special(ino)?;
+        let written = self.storage.write(ino, offset, data)?;
+        if self.options.write_verify
+            && !self.verify_write(ino, offset, &data[..written])
+            && self.options.write_verify_strict
+        {
+            return Err(SiaFuseError::Backend(
+                "write verification failed".to_string(),
+            ));
+        }
+        Ok(written)
+    }
+
+    /// Clear a regular file's set-user-ID bit, and its set-group-ID bit if
+    /// the group may execute it, before `uid` modifies it, unless `uid` is
+    /// root. Missing and generated files are left to the
+    /// write to report.
+    pub(crate) fn drop_setid(&self, uid: u32, ino: Inode) -> Result<()> {
+        let Some(mut attr) = self.storage.get_attr(ino) else {
+            return Ok(());
+        };
+        if uid == 0 || attr.kind != FileKind::File {
+            return Ok(());
+        }
+        let perm = clear_setid(attr.perm);
+        if perm != attr.perm {
+            attr.perm = perm;
+            self.storage.set_attr(ino, attr)?;
+        }
+        Ok(())
+    }
+
+    /// Refuse the parts of a setattr `uid` (with group `gid`) may not make
+    /// to `attr`: only root or the owner may chmod, only root may change
+    /// the owner, and the owner may only change the group to their own.
+    /// Resizing takes write access or a writable handle; setting explicit
+    /// times takes ownership, and setting them to now ownership or write
+    /// access.
+    fn check_attr_changes(
+        &self,
+        uid: u32,
+        gid: u32,
+        attr: &FileAttr,
+        changes: &AttrChanges,
+    ) -> Result<()> {
+        if uid == 0 {
+            return Ok(());
+        }
+        let owner = uid == attr.uid;
+        let chmod = changes.mode.is_some() && !owner;
+        let chown = changes.uid.is_some_and(|u| !owner || u != attr.uid);
+        let chgrp = changes
+            .gid
+            .is_some_and(|g| !owner || (g != attr.gid && g != gid));
+
+        if chmod || chown || chgrp {
+            return Err(SiaFuseError::NotPermitted);
+        }
+
+        let writable_handle = changes.fh.is_some_and(|fh| {
+            self.file_handle(fh)
+                .is_some_and(|handle| handle.ino == attr.ino && handle.is_writable())
+        });
+        if changes.size.is_some() && !writable_handle {
+            self.check_access(uid, gid, attr.ino, libc::W_OK)?;
+        }
+
+        if (changes.atime.is_some() || changes.mtime.is_some()) && !owner {
+            if !changes.times_now {
+                return Err(SiaFuseError::NotPermitted);
+            }
+            self.check_access(uid, gid, attr.ino, libc::W_OK)?;
+        }
+        Ok(())
+    }
+
+    /// Create a file or directory on behalf of `owner`
+    pub(crate) fn create_node(
+        &self,
+        owner: Owner,
+        parent: Inode,
+        name: &str,
+        kind: FileKind,
+        mode: u32,
+        umask: u32,
+    ) -> Result<FileAttr> {
+        self.check_new_name(name)?;
+
+        // A mode with no permission bits at all is treated as unspecified;
+        // explicitly restrictive modes such as 0400 are kept
+        let mode = if kind == FileKind::File && mode & 0o7777 == 0 {
+            tracing::debug!("create of '{}' without a mode, using default", name);
+            self.options.default_file_mode
+        } else {
+            mode
+        };
+
+        let perm = self.apply_umask(mode, umask);
+        match kind {
+            FileKind::File => self
+                .storage
+                .create_file(parent, name.to_string(), perm, owner),
+            FileKind::Directory => self
+                .storage
+                .create_dir(parent, name.to_string(), perm, owner),
+            FileKind::Symlink => Err(SiaFuseError::InvalidArgument(
+                "symlinks need a target".to_string(),
+            )),
+            _ => Err(SiaFuseError::InvalidArgument(
+                "special files are created with mknod".to_string(),
+            )),
+        }
+    }
+
+    /// Create a file at a `/`-separated `path` below `parent`. Under
+    /// `auto_mkdir`, missing directories on the way are created too.
+    pub(crate) fn create_file_at(
+        &self,
+        owner: Owner,
+        parent: Inode,
+        path: &str,
+        mode: u32,
+        umask: u32,
+    ) -> Result<FileAttr> {
+        let (dir, name) = storage::split_path(path)?;
+        let mkdir = |parent, name: &str| {
+            tracing::debug!("auto-mkdir: creating '{}'", name);
+            self.create_node(owner, parent, name, FileKind::Directory, 0o777, umask)
+        };
+        let mkdir = self.options.auto_mkdir.then_some(&mkdir as _);
+        let parent = storage::walk_dirs(self.storage.as_ref(), parent, dir, mkdir)?;
+
+        self.create_node(owner, parent, name, FileKind::File, mode, umask)
+    }
+
+    /// Create a regular file, FIFO, socket or device node on behalf of
+    /// `uid`, taking the type from `mode`'s format bits as mknod(2) does
+    pub(crate) fn make_node(
+        &self,
+        owner: Owner,
+        parent: Inode,
+        name: &str,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+    ) -> Result<FileAttr> {
+        let kind = match mode & libc::S_IFMT {
+            0 | libc::S_IFREG => {
+                return self.create_node(owner, parent, name, FileKind::File, mode, umask)
+            }
+            libc::S_IFIFO => FileKind::Fifo,
+            libc::S_IFCHR => FileKind::CharDevice,
+            libc::S_IFBLK => FileKind::BlockDevice,
+            libc::S_IFSOCK => FileKind::Socket,
+            format => {
+                return Err(SiaFuseError::InvalidArgument(format!(
+                    "mknod cannot create file type {:#o}",
+                    format
+                )))
+            }
+        };
+        self.check_new_name(name)?;
+
+        let perm = self.apply_umask(mode, umask);
+        self.storage
+            .create_special(parent, name.to_string(), kind, perm, rdev, owner)
+    }
+
+    /// Refuse content I/O on FIFOs, sockets and device nodes, which the
+    /// kernel normally serves without asking the filesystem
+    fn check_not_special(&self, ino: Inode) -> Result<()> {
+        match self.storage.get_attr(ino) {
+            Some(attr) if attr.kind.is_special() => Err(SiaFuseError::InvalidArgument(format!(
+                "inode {} is a {:?} node",
+                ino, attr.kind
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a symlink on behalf of `owner`
+    pub(crate) fn create_symlink(
+        &self,
+        owner: Owner,
+        parent: Inode,
+        name: &str,
+        target: &str,
+    ) -> Result<FileAttr> {
+        self.check_new_name(name)?;
+
+        self.storage
+            .create_symlink(parent, name.to_string(), target, owner)
+    }
+
+    pub(crate) fn link_entry(
+        &self,
+        ino: Inode,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<FileAttr> {
+        self.check_new_name(newname)?;
+        if self.is_control_file(ino) {
+            return Err(SiaFuseError::NotPermitted);
+        }
+        self.storage.link(ino, newparent, newname)
+    }
+
+    /// Move or swap a directory entry according to the rename(2) `flags`
+    pub(crate) fn rename_entry(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+        flags: u32,
+    ) -> Result<()> {
+        self.check_new_name(newname)?;
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(SiaFuseError::InvalidArgument(
+                    "RENAME_EXCHANGE and RENAME_NOREPLACE are exclusive".to_string(),
+                ));
+            }
+            self.storage.exchange(parent, name, newparent, newname)
+        } else if flags & libc::RENAME_NOREPLACE != 0 {
+            self.storage
+                .rename_noreplace(parent, name, newparent, newname)
+        } else if flags != 0 {
+            Err(SiaFuseError::InvalidArgument(format!(
+                "unsupported rename flags {:#x}",
+                flags
+            )))
+        } else {
+            self.storage.rename(parent, name, newparent, newname)
+        }
+    }
+
+    /// Check whether `uid`/`gid` may access `ino` as `mask` (a combination of
+    /// `R_OK`, `W_OK` and `X_OK`; `F_OK` only checks existence). Root may
+    /// read and write anything, and execute anything with an execute bit.
+    pub(crate) fn check_access(&self, uid: u32, gid: u32, ino: Inode, mask: i32) -> Result<()> {
+        let attr = self.attr(ino)?;
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        if mask == 0 {
+            return Ok(());
+        }
+
+        if uid == 0 {
+            let executable = attr.kind == FileKind::Directory || attr.perm & 0o111 != 0;
+            return if mask & libc::X_OK as u16 == 0 || executable {
+                Ok(())
+            } else {
+                Err(SiaFuseError::PermissionDenied)
+            };
+        }
+
+        // Only the most specific class applies: an owner denied by the
+        // owner bits isn't rescued by the group or other bits
+        let granted = if uid == attr.uid {
+            attr.perm >> 6
+        } else if gid == attr.gid {
+            attr.perm >> 3
+        } else {
+            attr.perm
+        } & 0o7;
+
+        if mask & !granted == 0 {
+            Ok(())
+        } else {
+            Err(SiaFuseError::PermissionDenied)
+        }
+    }
+
+    /// fallocate(2): mode 0 extends the file to cover the range without
+    /// storing anything, `FALLOC_FL_KEEP_SIZE` alone is a no-op since there
+    /// is nothing to reserve, and `PUNCH_HOLE | KEEP_SIZE` deallocates the
+    /// range
+    pub(crate) fn allocate(&self, ino: Inode, offset: i64, length: i64, mode: i32) -> Result<()> {
+        if offset < 0 || length <= 0 {
+            return Err(SiaFuseError::InvalidArgument(format!(
+                "bad fallocate range {}+{}",
+                offset, length
+            )));
+        }
+        self.check_not_control(ino)?;
+        let attr = self.storage.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        if attr.kind == FileKind::Directory {
+            return Err(SiaFuseError::IsADirectory);
+        }
+
+        let (offset, length) = (offset as u64, length as u64);
+        match mode {
+            0 => {
+                let end = offset.saturating_add(length);
+                if end > attr.size {
+                    self.storage.truncate(ino, end)?;
+                }
+                Ok(())
+            }
+            libc::FALLOC_FL_KEEP_SIZE => Ok(()),
+            m if m == libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE => {
+                self.storage.punch_hole(ino, offset, length)
+            }
+            m => Err(SiaFuseError::NotSupported(format!(
+                "fallocate mode {:#x}",
+                m
+            ))),
+        }
+    }
+
+    /// copy_file_range(2). Like Linux, an overlapping copy within one file
+    /// is refused; copies are cut short at the source's end.
+    pub(crate) fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: i64,
+        ino_out: Inode,
+        off_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<usize> {
+        if off_in < 0 || off_out < 0 || flags != 0 {
+            return Err(SiaFuseError::InvalidArgument(format!(
+                "bad copy_file_range arguments {}, {}, flags {:#x}",
+                off_in, off_out, flags
+            )));
+        }
+        self.check_not_control(ino_out)?;
+        for ino in [ino_in, ino_out] {
+            if self.is_control_file(ino) {
+                continue;
+            }
+            let attr = self.storage.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+            if attr.kind == FileKind::Directory {
+                return Err(SiaFuseError::IsADirectory);
+            }
+            self.check_not_special(ino)?;
+        }
+
+        // The reply carries a 32-bit count; callers loop on short copies
+        let len = std::cmp::min(len, u32::MAX as u64) as usize;
+        let (off_in, off_out) = (off_in as usize, off_out as usize);
+        if ino_in == ino_out
+            && off_in < off_out.saturating_add(len)
+            && off_out < off_in.saturating_add(len)
+        {
+            return Err(SiaFuseError::InvalidArgument(
+                "overlapping copy within one file".to_string(),
+            ));
+        }
+
+        if self.is_control_file(ino_in) {
+            let data = self.read_data(0, ino_in, off_in, len)?;
+            return self.write_data(ino_out, off_out, &data);
+        }
+        self.storage
+            .copy_range(ino_in, off_in, ino_out, off_out, len)
+    }
+
+    /// lseek(2) with SEEK_DATA or SEEK_HOLE. The kernel resolves the other
+    /// whence values itself.
+    pub(crate) fn seek(&self, fh: u64, ino: Inode, offset: i64, whence: i32) -> Result<i64> {
+        if offset < 0 {
+            return Err(SiaFuseError::InvalidArgument(format!(
+                "negative seek offset {}",
+                offset
+            )));
+        }
+        let control_len = self
+            .control_data(fh, ino)
+            .map(|content| content.len() as u64);
+        let found = match (whence, control_len) {
+            (libc::SEEK_DATA, Some(len)) => Some(offset as u64).filter(|&o| o < len),
+            (libc::SEEK_HOLE, Some(len)) => (len > offset as u64).then_some(len),
+            (libc::SEEK_DATA, None) => self.storage.seek_data(ino, offset as u64)?,
+            (libc::SEEK_HOLE, None) => self.storage.seek_hole(ino, offset as u64)?,
+            _ => {
+                return Err(SiaFuseError::InvalidArgument(format!(
+                    "unsupported whence {}",
+                    whence
+                )))
+            }
+        };
+        found.map(|o| o as i64).ok_or(SiaFuseError::NoSeekTarget)
+    }
+
+    /// Apply the mode, owner, size and timestamp parts of a setattr made
+    /// by `uid`/`gid`
+    pub(crate) fn update_attr(
+        &self,
+        uid: u32,
+        gid: u32,
+        ino: Inode,
+        changes: AttrChanges,
+    ) -> Result<FileAttr> {
+        self.check_not_control(ino)?;
+        let old = self.storage.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        self.check_attr_changes(uid, gid, &old, &changes)?;
+
+        // Resize the content first; the attributes are re-read below so the
+        // new size and mtime are picked up
+        if let Some(size) = changes.size {
+            self.drop_setid(uid, ino)?;
+            self.storage.truncate(ino, size)?;
+        }
+
+        let mut attr = self.storage.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+
+        // Update attributes
+        if let Some(m) = changes.mode {
+            attr.perm = m as u16;
+            // Only members of the file's group may make it set-group-ID
+            if uid != 0 && gid != changes.gid.unwrap_or(attr.gid) {
+                attr.perm &= !(libc::S_ISGID as u16);
+            }
+        }
+        if let Some(u) = changes.uid {
+            attr.uid = u;
+        }
+        if let Some(g) = changes.gid {
+            attr.gid = g;
+        }
+        // A file given away doesn't keep running as its old owner
+        if (changes.uid.is_some() || changes.gid.is_some()) && attr.kind == FileKind::File {
+            attr.perm = clear_setid(attr.perm);
+        }
+        if let Some(t) = changes.atime {
+            attr.atime = t;
+        }
+        if let Some(t) = changes.mtime {
+            attr.mtime = t;
+        }
+        if !changes.is_empty() {
+            attr.ctime = self.storage.now();
+        }
+
+        self.storage.set_attr(ino, attr.clone())?;
+        Ok(attr)
     }
 }
 
 impl Filesystem for SiaFuseFilesystem {
+    fn init(
+        &mut self,
+        _req: &Request,
+        config: &mut KernelConfig,
+    ) -> std::result::Result<(), libc::c_int> {
+        // Fill directory listings and their attributes in one round trip
+        if config
+            .add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS)
+            .is_err()
+        {
+            tracing::debug!("kernel does not support readdirplus");
+        }
+        // Have O_TRUNC passed to open rather than sent as a separate setattr
+        if config
+            .add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC)
+            .is_err()
+        {
+            tracing::debug!("kernel does not support atomic O_TRUNC");
+        }
+        if let Some(max_write) = self.options.max_write {
+            if let Err(nearest) = config.set_max_write(max_write) {
+                tracing::warn!("max_write {} not supported, using {}", max_write, nearest);
+                let _ = config.set_max_write(nearest);
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        let pending = self.storage.stats().pending_uploads;
+        match self.storage.sync_all() {
+            Ok(()) => tracing::info!("Flushed {} inodes with pending writes on unmount", pending),
+            Err(e) => tracing::error!("failed to sync storage on unmount: {}", e),
+        }
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        tracing::debug!(
-            "lookup(parent={}, name={})",
-            parent,
-            name.to_string_lossy()
-        );
+        let mut timer = self.metrics.start(Op::Lookup).ino(parent);
+        tracing::debug!("lookup(parent={}, name={})", parent, name.to_string_lossy());
 
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
-                reply.error(libc::EINVAL);
+                reply.error(timer.fail(libc::EINVAL));
                 return;
             }
         };
 
-        match self.storage.lookup(parent, name_str) {
-            Some(attr) => {
+        match self.lookup_entry(parent, name_str) {
+            Ok(attr) => {
                 tracing::debug!("lookup found: ino={}", attr.ino);
-                reply.entry(&TTL, &attr.to_fuser_attr(), 0);
+                reply.entry(&self.options.entry_ttl, &attr.to_fuser_attr(), 0);
             }
-            None => {
+            Err(e) => {
                 tracing::debug!("lookup not found");
-                reply.error(libc::ENOENT);
+                reply.error(timer.fail(e.errno()));
             }
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let mut timer = self.metrics.start(Op::Getattr).ino(ino);
         tracing::debug!("getattr(ino={})", ino);
 
-        match self.storage.get_attr(ino) {
-            Some(attr) => {
-                reply.attr(&TTL, &attr.to_fuser_attr());
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&self.options.attr_ttl, &attr.to_fuser_attr()),
+            Err(e) => reply.error(timer.fail(e.errno())),
         }
     }
 
@@ -71,31 +1204,96 @@ impl Filesystem for SiaFuseFilesystem {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
+        let mut timer = self
+            .metrics
+            .start(Op::Read)
+            .ino(ino)
+            .range(offset, size as u64);
         tracing::debug!("read(ino={}, offset={}, size={})", ino, offset, size);
 
-        match self.storage.read(ino, offset as usize, size as usize) {
-            Some(data) => {
-                tracing::debug!("read {} bytes", data.len());
-                reply.data(&data);
+        // Nothing to copy; don't touch storage or the access time
+        if size == 0 {
+            reply.data(&[]);
+            return;
+        }
+
+        if self.is_control_file(ino) {
+            match self.read_data(fh, ino, offset as usize, size as usize) {
+                Ok(data) => {
+                    self.metrics.add_read(data.len());
+                    timer.transferred(data.len());
+                    reply.data(&data);
+                    self.track_read(fh, offset as u64, data.len());
+                }
+                Err(e) => reply.error(timer.fail(e.errno())),
+            }
+            return;
+        }
+
+        if let Err(e) = self.check_not_special(ino) {
+            reply.error(timer.fail(e.errno()));
+            return;
+        }
+
+        // Reads covering the whole file (e.g. `cat` on a small file) skip
+        // the offset/size slicing and take the content as-is
+        let whole_file = offset == 0
+            && self
+                .storage
+                .get_attr(ino)
+                .is_some_and(|attr| size as u64 >= attr.size);
+
+        if whole_file {
+            match self.storage.read_all(ino) {
+                Some(mut data) => {
+                    // The file may have grown since we checked its size
+                    data.truncate(size as usize);
+                    tracing::debug!("read {} bytes", data.len());
+                    self.metrics.add_read(data.len());
+                    timer.transferred(data.len());
+                    reply.data(&data);
+                    self.track_read(fh, offset as u64, data.len());
+                }
+                None => reply.error(timer.fail(self.read_failed(ino).errno())),
             }
+            return;
+        }
+
+        // Reply straight from the stored bytes instead of copying the range
+        // into an intermediate buffer first
+        let mut reply = Some(reply);
+        let mut read = 0;
+        self.storage
+            .read_with(ino, offset as usize, size as usize, &mut |data| {
+                if let Some(reply) = reply.take() {
+                    tracing::debug!("read {} bytes", data.len());
+                    self.metrics.add_read(data.len());
+                    read = data.len();
+                    reply.data(data);
+                }
+            });
+
+        match reply {
+            Some(reply) => reply.error(timer.fail(self.read_failed(ino).errno())),
             None => {
-                reply.error(libc::ENOENT);
+                timer.transferred(read);
+                self.track_read(fh, offset as u64, read);
             }
         }
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -103,68 +1301,158 @@ impl Filesystem for SiaFuseFilesystem {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        let mut timer = self
+            .metrics
+            .start(Op::Write)
+            .ino(ino)
+            .range(offset, data.len() as u64);
         tracing::debug!("write(ino={}, offset={}, len={})", ino, offset, data.len());
 
-        match self.storage.write(ino, offset as usize, data) {
-            Some(written) => {
+        match self.write_handle(req.uid(), fh, ino, offset as usize, data) {
+            Ok(written) => {
                 tracing::debug!("wrote {} bytes", written);
+                self.metrics.add_written(written);
+                timer.transferred(written);
                 reply.written(written as u32);
             }
+            Err(e) => {
+                tracing::debug!("write failed: {}", e);
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    /// Snapshot the listing so the whole readdir sequence sees one
+    /// consistent set of entries, however the directory changes meanwhile
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let mut timer = self.metrics.start(Op::Opendir).ino(ino);
+        tracing::debug!("opendir(ino={})", ino);
+
+        match self.storage.get_attr(ino) {
+            Some(attr) if attr.kind != FileKind::Directory => {
+                reply.error(timer.fail(libc::ENOTDIR));
+                return;
+            }
             None => {
-                reply.error(libc::ENOENT);
+                reply.error(timer.fail(libc::ENOENT));
+                return;
             }
+            Some(_) => {}
         }
+
+        let Some(entries) = self.dir_entries(ino) else {
+            reply.error(timer.fail(libc::ENOENT));
+            return;
+        };
+        let fh = self.allocate_fh();
+        self.dir_handles.insert(fh, entries);
+        reply.opened(fh, 0);
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let _timer = self.metrics.start(Op::Releasedir).ino(ino);
+        tracing::debug!("releasedir(ino={}, fh={})", ino, fh);
+
+        self.dir_handles.remove(&fh);
+        reply.ok();
     }
 
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        tracing::debug!("readdir(ino={}, offset={})", ino, offset);
+        let mut timer = self.metrics.start(Op::Readdir).ino(ino);
+        tracing::debug!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
 
-        let entries = match self.storage.read_dir(ino) {
+        let entries = match self.open_dir_entries(ino, fh) {
             Some(e) => e,
             None => {
-                reply.error(libc::ENOENT);
+                reply.error(timer.fail(libc::ENOENT));
                 return;
             }
         };
 
-        let mut current_offset = offset;
-
         // Add . and .. entries
-        if offset == 0 {
-            if reply.add(ino, 1, FileType::Directory, ".") {
-                reply.ok();
-                return;
+        if offset == 0 && reply.add(ino, 1, FileType::Directory, ".") {
+            reply.ok();
+            return;
+        }
+
+        if offset <= 1 && reply.add(ino, 2, FileType::Directory, "..") {
+            reply.ok();
+            return;
+        }
+
+        // Each entry's offset is its cookie, so the next call resumes right
+        // after the last entry the kernel received even if entries were
+        // added or removed in between
+        for entry in entries.iter().filter(|e| e.cookie as i64 > offset) {
+            if reply.add(
+                entry.ino,
+                entry.cookie as i64,
+                entry.kind.to_fuser_type(),
+                &entry.name,
+            ) {
+                break;
             }
-            current_offset += 1;
         }
 
-        if offset <= 1 {
-            if reply.add(ino, 2, FileType::Directory, "..") {
-                reply.ok();
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let mut timer = self.metrics.start(Op::Readdirplus).ino(ino);
+        tracing::debug!("readdirplus(ino={}, fh={}, offset={})", ino, fh, offset);
+
+        let (entries, dir_attr) = match (self.open_dir_entries(ino, fh), self.storage.get_attr(ino))
+        {
+            (Some(e), Some(attr)) => (e, attr.to_fuser_attr()),
+            _ => {
+                reply.error(timer.fail(libc::ENOENT));
                 return;
             }
-            current_offset += 1;
+        };
+        let ttl = &self.options.entry_ttl;
+
+        if offset == 0 && reply.add(ino, 1, ".", ttl, &dir_attr, 0) {
+            reply.ok();
+            return;
+        }
+
+        if offset <= 1 && reply.add(ino, 2, "..", ttl, &dir_attr, 0) {
+            reply.ok();
+            return;
         }
 
-        // Add actual entries
-        for (i, entry) in entries.iter().enumerate() {
-            let entry_offset = i as i64 + 2; // Skip . and ..
-            if entry_offset < offset {
+        // Offsets are the entries' cookies, so an entry skipped below
+        // doesn't shift the offsets of the ones after it
+        for entry in entries.iter().filter(|e| e.cookie as i64 > offset) {
+            let attr = self.attr(entry.ino).ok();
+
+            // Removed between listing the directory and reading its attrs
+            let Some(attr) = attr else {
+                tracing::debug!("readdirplus: '{}' vanished, skipping", entry.name);
                 continue;
-            }
+            };
 
             if reply.add(
                 entry.ino,
-                entry_offset + 1,
-                entry.kind.to_fuser_type(),
+                entry.cookie as i64,
                 &entry.name,
+                ttl,
+                &attr.to_fuser_attr(),
+                0,
             ) {
                 break;
             }
@@ -175,14 +1463,15 @@ impl Filesystem for SiaFuseFilesystem {
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
-        _flags: i32,
+        umask: u32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
+        let mut timer = self.metrics.start(Op::Create).ino(parent);
         tracing::debug!(
             "create(parent={}, name={}, mode={})",
             parent,
@@ -191,33 +1480,41 @@ impl Filesystem for SiaFuseFilesystem {
         );
 
         let name_str = match name.to_str() {
-            Some(s) => s.to_string(),
+            Some(s) => s,
             None => {
-                reply.error(libc::EINVAL);
+                reply.error(timer.fail(libc::EINVAL));
                 return;
             }
         };
 
-        match self.storage.create_file(parent, name_str, mode as u16) {
-            Some(attr) => {
+        match self.create_file_at(
+            self.creator(req.uid(), req.gid()),
+            parent,
+            name_str,
+            mode,
+            umask,
+        ) {
+            Ok(attr) => {
                 tracing::debug!("created file: ino={}", attr.ino);
-                reply.created(&TTL, &attr.to_fuser_attr(), 0, 0, 0);
+                let fh = self.add_file_handle(req.uid(), attr.ino, flags);
+                reply.created(&self.options.entry_ttl, &attr.to_fuser_attr(), 0, fh, 0);
             }
-            None => {
-                reply.error(libc::EIO);
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
             }
         }
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
+        let mut timer = self.metrics.start(Op::Mkdir).ino(parent);
         tracing::debug!(
             "mkdir(parent={}, name={}, mode={})",
             parent,
@@ -226,139 +1523,659 @@ impl Filesystem for SiaFuseFilesystem {
         );
 
         let name_str = match name.to_str() {
-            Some(s) => s.to_string(),
+            Some(s) => s,
             None => {
-                reply.error(libc::EINVAL);
+                reply.error(timer.fail(libc::EINVAL));
                 return;
             }
         };
 
-        match self.storage.create_dir(parent, name_str, mode as u16) {
-            Some(attr) => {
+        match self.create_node(
+            self.creator(req.uid(), req.gid()),
+            parent,
+            name_str,
+            FileKind::Directory,
+            mode,
+            umask,
+        ) {
+            Ok(attr) => {
                 tracing::debug!("created directory: ino={}", attr.ino);
-                reply.entry(&TTL, &attr.to_fuser_attr(), 0);
+                reply.entry(&self.options.entry_ttl, &attr.to_fuser_attr(), 0);
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let mut timer = self.metrics.start(Op::Mknod).ino(parent);
+        tracing::debug!(
+            "mknod(parent={}, name={}, mode={:#o}, rdev={})",
+            parent,
+            name.to_string_lossy(),
+            mode,
+            rdev
+        );
+
+        let name_str = match name.to_str() {
+            Some(s) => s,
+            None => {
+                reply.error(timer.fail(libc::EINVAL));
+                return;
+            }
+        };
+
+        match self.make_node(
+            self.creator(req.uid(), req.gid()),
+            parent,
+            name_str,
+            mode,
+            umask,
+            rdev,
+        ) {
+            Ok(attr) => {
+                tracing::debug!("created {:?} node: ino={}", attr.kind, attr.ino);
+                reply.entry(&self.options.entry_ttl, &attr.to_fuser_attr(), 0);
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let mut timer = self.metrics.start(Op::Symlink).ino(parent);
+        tracing::debug!(
+            "symlink(parent={}, name={}, target={})",
+            parent,
+            link_name.to_string_lossy(),
+            target.display()
+        );
+
+        let (name_str, target_str) = match (link_name.to_str(), target.to_str()) {
+            (Some(n), Some(t)) => (n, t),
+            _ => {
+                reply.error(timer.fail(libc::EINVAL));
+                return;
+            }
+        };
+
+        match self.create_symlink(
+            self.creator(req.uid(), req.gid()),
+            parent,
+            name_str,
+            target_str,
+        ) {
+            Ok(attr) => {
+                tracing::debug!("created symlink: ino={}", attr.ino);
+                reply.entry(&self.options.entry_ttl, &attr.to_fuser_attr(), 0);
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
             }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let mut timer = self.metrics.start(Op::Readlink).ino(ino);
+        tracing::debug!("readlink(ino={})", ino);
+
+        match self.storage.readlink(ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let mut timer = self.metrics.start(Op::Link).ino(ino);
+        tracing::debug!(
+            "link(ino={}, newparent={}, newname={})",
+            ino,
+            newparent,
+            newname.to_string_lossy()
+        );
+
+        let name_str = match newname.to_str() {
+            Some(s) => s,
             None => {
-                reply.error(libc::EIO);
+                reply.error(timer.fail(libc::EINVAL));
+                return;
+            }
+        };
+
+        match self.link_entry(ino, newparent, name_str) {
+            Ok(attr) => {
+                tracing::debug!("linked: ino={} nlink={}", attr.ino, attr.nlink);
+                reply.entry(&self.options.entry_ttl, &attr.to_fuser_attr(), 0);
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
             }
         }
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let mut timer = self.metrics.start(Op::Unlink).ino(parent);
         tracing::debug!("unlink(parent={}, name={})", parent, name.to_string_lossy());
 
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
-                reply.error(libc::EINVAL);
+                reply.error(timer.fail(libc::EINVAL));
                 return;
             }
         };
 
-        if self.storage.unlink(parent, name_str) {
-            tracing::debug!("unlinked successfully");
-            reply.ok();
-        } else {
-            reply.error(libc::ENOENT);
+        match self.storage.unlink(parent, name_str) {
+            Ok(()) => {
+                tracing::debug!("unlinked successfully");
+                reply.ok();
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
+            }
         }
     }
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let mut timer = self.metrics.start(Op::Rmdir).ino(parent);
         tracing::debug!("rmdir(parent={}, name={})", parent, name.to_string_lossy());
 
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
-                reply.error(libc::EINVAL);
+                reply.error(timer.fail(libc::EINVAL));
                 return;
             }
         };
 
-        if self.storage.rmdir(parent, name_str) {
-            tracing::debug!("removed directory successfully");
-            reply.ok();
-        } else {
-            reply.error(libc::ENOTEMPTY);
+        match self.storage.rmdir(parent, name_str) {
+            Ok(()) => {
+                tracing::debug!("removed directory successfully");
+                reply.ok();
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let mut timer = self.metrics.start(Op::Rename).ino(parent);
+        tracing::debug!(
+            "rename(parent={}, name={}, newparent={}, newname={}, flags={:#x})",
+            parent,
+            name.to_string_lossy(),
+            newparent,
+            newname.to_string_lossy(),
+            flags
+        );
+
+        let (name_str, newname_str) = match (name.to_str(), newname.to_str()) {
+            (Some(n), Some(nn)) => (n, nn),
+            _ => {
+                reply.error(timer.fail(libc::EINVAL));
+                return;
+            }
+        };
+
+        match self.rename_entry(parent, name_str, newparent, newname_str, flags) {
+            Ok(()) => {
+                tracing::debug!("renamed successfully");
+                reply.ok();
+            }
+            Err(e) => {
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let mut timer = self.metrics.start(Op::Open).ino(ino);
+        tracing::debug!("open(ino={}, flags={})", ino, flags);
+
+        match self.open_file(req.uid(), req.gid(), ino, flags) {
+            // Control files report size 0, so bypass the page cache and
+            // read until EOF
+            Ok(fh) if self.is_control_file(ino) => reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO),
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let mut timer = self.metrics.start(Op::Flush).ino(ino);
+        tracing::debug!("flush(ino={})", ino);
+
+        match self.sync_file(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
         }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        tracing::debug!("open(ino={}, flags={})", ino, _flags);
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let mut timer = self.metrics.start(Op::Fsync).ino(ino);
+        tracing::debug!("fsync(ino={}, datasync={})", ino, datasync);
 
-        // For POC, we always allow opens
-        reply.opened(0, 0);
+        match self.sync_file(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
     }
 
     fn release(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
+        let mut timer = self.metrics.start(Op::Release).ino(ino);
         tracing::debug!("release(ino={})", ino);
-        reply.ok();
+
+        // Usually a no-op after flush, but flush isn't guaranteed to have
+        // run. Sync before the handle goes, which may delete the file.
+        let synced = self.sync_file(ino);
+        if let Err(e) = self.release_file(fh) {
+            tracing::debug!("release of fh {} failed: {}", fh, e);
+        }
+        match synced {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
     }
 
-    fn setattr(
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let mut timer = self
+            .metrics
+            .start(Op::Fallocate)
+            .ino(ino)
+            .range(offset, length as u64);
+        tracing::debug!(
+            "fallocate(ino={}, offset={}, length={}, mode={:#x})",
+            ino,
+            offset,
+            length,
+            mode
+        );
+
+        match self.allocate(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn lseek(
         &mut self,
         _req: &Request,
         ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let mut timer = self.metrics.start(Op::Lseek).ino(ino);
+        tracing::debug!("lseek(ino={}, offset={}, whence={})", ino, offset, whence);
+
+        match self.seek(fh, ino, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let mut timer = self
+            .metrics
+            .start(Op::CopyFileRange)
+            .ino(ino_in)
+            .range(offset_in, len);
+        tracing::debug!(
+            "copy_file_range(ino_in={}, offset_in={}, ino_out={}, offset_out={}, len={})",
+            ino_in,
+            offset_in,
+            ino_out,
+            offset_out,
+            len
+        );
+
+        let copied = match self.file_handle(fh_out) {
+            // Like Linux, refuse to copy into a file opened for appending
+            Some(handle) if handle.flags & libc::O_APPEND != 0 => Err(SiaFuseError::BadFileHandle),
+            _ => self
+                .check_writable(fh_out)
+                .and_then(|()| self.drop_setid(req.uid(), ino_out))
+                .and_then(|()| self.copy_range(ino_in, offset_in, ino_out, offset_out, len, flags)),
+        };
+        match copied {
+            Ok(copied) => {
+                self.metrics.add_written(copied);
+                timer.transferred(copied);
+                reply.written(copied as u32);
+            }
+            Err(e) => {
+                tracing::debug!("copy_file_range failed: {}", e);
+                reply.error(timer.fail(e.errno()));
+            }
+        }
+    }
+
+    /// There are no physical blocks behind a file, so report a synthetic
+    /// identity mapping (block `idx` maps to `idx`) for tools that insist on
+    /// FIBMAP. Blocks past EOF map to 0, like a hole.
+    fn bmap(&mut self, _req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let mut timer = self.metrics.start(Op::Bmap).ino(ino);
+        tracing::debug!("bmap(ino={}, blocksize={}, idx={})", ino, blocksize, idx);
+
+        match self.storage.get_attr(ino) {
+            Some(attr) if attr.kind == FileKind::Directory => reply.error(timer.fail(libc::EINVAL)),
+            Some(_) if blocksize == 0 => reply.error(timer.fail(libc::EINVAL)),
+            Some(attr) if idx < attr.size.div_ceil(blocksize as u64) => reply.bmap(idx),
+            Some(_) => reply.bmap(0),
+            None => reply.error(timer.fail(libc::ENOENT)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
         _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<std::time::SystemTime>,
         _chgtime: Option<std::time::SystemTime>,
         _bkuptime: Option<std::time::SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        let mut timer = self.metrics.start(Op::Setattr).ino(ino);
         tracing::debug!("setattr(ino={}, size={:?})", ino, size);
 
-        let mut attr = match self.storage.get_attr(ino) {
-            Some(a) => a,
-            None => {
-                reply.error(libc::ENOENT);
+        let times_now = [atime, mtime]
+            .iter()
+            .flatten()
+            .all(|t| matches!(t, fuser::TimeOrNow::Now));
+        let now = self.storage.now();
+        let atime = atime.map(|t| time_or_now(t, now));
+        let mtime = mtime.map(|t| time_or_now(t, now));
+
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            times_now,
+            fh,
+        };
+
+        match self.update_attr(req.uid(), req.gid(), ino, changes) {
+            Ok(attr) => reply.attr(&self.options.attr_ttl, &attr.to_fuser_attr()),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let _timer = self.metrics.start(Op::Statfs);
+        tracing::debug!("statfs()");
+
+        let stats = self.storage.stats();
+        let blocks = stats.capacity_bytes / BLOCK_SIZE;
+        let free = stats.capacity_bytes.saturating_sub(stats.used_bytes) / BLOCK_SIZE;
+        let ffree = stats.max_inodes.saturating_sub(stats.used_inodes);
+
+        reply.statfs(
+            blocks,
+            free,
+            free,
+            stats.max_inodes,
+            ffree,
+            BLOCK_SIZE as u32,
+            MAX_NAME_LEN as u32,
+            BLOCK_SIZE as u32,
+        );
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let mut timer = self.metrics.start(Op::Access).ino(ino);
+        tracing::debug!("access(ino={}, mask={:#o})", ino, mask);
+
+        match self.check_access(req.uid(), req.gid(), ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let mut timer = self.metrics.start(Op::Getxattr).ino(ino);
+        tracing::debug!(
+            "getxattr(ino={}, name={}, size={})",
+            ino,
+            name.to_string_lossy(),
+            size
+        );
+
+        let value = match name.to_str() {
+            Some(XATTR_SYNCED) => match self.storage.last_synced(ino) {
+                Some(synced) => synced.to_rfc3339(),
+                None => {
+                    reply.error(timer.fail(libc::ENOENT));
+                    return;
+                }
+            },
+            Some(XATTR_ALLOCATED) => match self.storage.allocated_size(ino) {
+                Some(allocated) => allocated.to_string(),
+                None => {
+                    reply.error(timer.fail(libc::ENOENT));
+                    return;
+                }
+            },
+            Some(XATTR_CHUNK_SIZE) => match self.storage.chunk_size(ino) {
+                Some(chunk_size) => chunk_size.to_string(),
+                None if self.storage.get_attr(ino).is_none() => {
+                    reply.error(timer.fail(libc::ENOENT));
+                    return;
+                }
+                None => {
+                    reply.error(timer.fail(libc::ENODATA));
+                    return;
+                }
+            },
+            Some(XATTR_DEDUP_REFS) => match self.storage.dedup_refs(ino) {
+                Some(refs) => refs.to_string(),
+                None if self.storage.get_attr(ino).is_none() => {
+                    reply.error(timer.fail(libc::ENOENT));
+                    return;
+                }
+                None => {
+                    reply.error(timer.fail(libc::ENODATA));
+                    return;
+                }
+            },
+            Some(name @ (XATTR_KEY | XATTR_HEALTH | XATTR_REDUNDANCY | XATTR_CONTRACT_SET)) => {
+                let value = self.storage.stored_object(ino).map(|object| {
+                    object.and_then(|object| match name {
+                        XATTR_KEY => Some(object.key),
+                        XATTR_HEALTH => Some(object.health.to_string()),
+                        XATTR_REDUNDANCY => object.redundancy.map(|r| r.to_string()),
+                        _ => object.contract_set,
+                    })
+                });
+                match value {
+                    Ok(Some(value)) => value,
+                    Ok(None) => {
+                        reply.error(timer.fail(libc::ENODATA));
+                        return;
+                    }
+                    Err(e) => {
+                        reply.error(timer.fail(e.errno()));
+                        return;
+                    }
+                }
+            }
+            _ => {
+                reply.error(timer.fail(libc::ENODATA));
                 return;
             }
         };
 
-        // Update attributes
-        if let Some(m) = mode {
-            attr.perm = m as u16;
+        reply_xattr(reply, &mut timer, size, value.as_bytes());
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let mut timer = self.metrics.start(Op::Setxattr).ino(ino);
+        tracing::debug!(
+            "setxattr(ino={}, name={}, size={})",
+            ino,
+            name.to_string_lossy(),
+            value.len()
+        );
+
+        if name.to_str() != Some(XATTR_CHUNK_SIZE) {
+            reply.error(timer.fail(libc::ENOTSUP));
+            return;
         }
-        if let Some(u) = uid {
-            attr.uid = u;
+        let result = self
+            .check_access(req.uid(), req.gid(), ino, libc::W_OK)
+            .and_then(|()| {
+                std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| {
+                        SiaFuseError::InvalidArgument("chunk size must be a number".to_string())
+                    })
+            })
+            .and_then(|chunk_size| self.storage.set_chunk_size(ino, chunk_size));
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(timer.fail(e.errno())),
         }
-        if let Some(g) = gid {
-            attr.gid = g;
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let mut timer = self.metrics.start(Op::Listxattr).ino(ino);
+        tracing::debug!("listxattr(ino={}, size={})", ino, size);
+
+        if self.storage.get_attr(ino).is_none() {
+            reply.error(timer.fail(libc::ENOENT));
+            return;
         }
-        if let Some(s) = size {
-            attr.size = s;
-            // Truncate file if needed
-            if attr.kind == FileKind::File {
-                let current_data = self.storage.read(ino, 0, usize::MAX).unwrap_or_default();
-                if (s as usize) < current_data.len() {
-                    let truncated = &current_data[..s as usize];
-                    self.storage.write(ino, 0, truncated);
-                } else if (s as usize) > current_data.len() {
-                    // Extend with zeros
-                    let mut extended = current_data;
-                    extended.resize(s as usize, 0);
-                    self.storage.write(ino, 0, &extended);
-                }
-            }
+
+        // Names are returned as a sequence of NUL-terminated strings
+        let mut names = Vec::new();
+        let chunk_size = self.storage.chunk_size(ino).map(|_| XATTR_CHUNK_SIZE);
+        let dedup_refs = self.storage.dedup_refs(ino).map(|_| XATTR_DEDUP_REFS);
+        for name in [XATTR_SYNCED, XATTR_ALLOCATED]
+            .into_iter()
+            .chain(chunk_size)
+            .chain(dedup_refs)
+        {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
         }
 
-        self.storage.set_attr(ino, attr.clone());
-        reply.attr(&TTL, &attr.to_fuser_attr());
+        reply_xattr(reply, &mut timer, size, &names);
+    }
+}
+
+/// `perm` without the set-user-ID bit, and without the set-group-ID bit
+/// unless the group can't execute (where it marks mandatory locking)
+fn clear_setid(perm: u16) -> u16 {
+    let mut perm = perm & !(libc::S_ISUID as u16);
+    if perm & libc::S_IXGRP as u16 != 0 {
+        perm &= !(libc::S_ISGID as u16);
+    }
+    perm
+}
+
+/// Resolve a setattr timestamp, where `Now` means the time of the request
+fn time_or_now(time: fuser::TimeOrNow, now: DateTime<Utc>) -> DateTime<Utc> {
+    match time {
+        fuser::TimeOrNow::Now => now,
+        fuser::TimeOrNow::SpecificTime(t) => DateTime::<Utc>::from(t),
+    }
+}
+
+/// Reply to an xattr request, honoring the size-probe convention where a
+/// zero `size` asks for the length of the value rather than the value itself
+fn reply_xattr(reply: ReplyXattr, timer: &mut OpTimer, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(timer.fail(libc::ERANGE));
+    } else {
+        reply.data(data);
     }
 }
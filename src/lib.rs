@@ -1,5 +1,20 @@
+pub mod bench;
+pub mod config;
+pub mod control;
+pub mod error;
+pub mod fsid;
 pub mod fuse_impl;
+pub mod logging;
+pub mod metrics;
+pub mod mount;
+#[cfg(feature = "renterd")]
+pub mod renterd;
 pub mod storage;
+pub mod test_session;
+pub mod throttle;
 
-pub use fuse_impl::SiaFuseFilesystem;
-pub use storage::{FileKind, Inode, InMemoryStorage};
+pub use error::{Result, SiaFuseError};
+pub use fuse_impl::{FsOptions, SiaFuseFilesystem};
+pub use mount::{mount, unmount};
+pub use storage::{FileKind, InMemoryStorage, Inode, Storage, StorageStats};
+pub use test_session::{test_session, TestSession};
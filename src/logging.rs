@@ -0,0 +1,66 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use tracing::callsite::Identifier;
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::{Context, Filter, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Target of the event logged for every FUSE operation handled. Only the
+/// `--trace-file` layer records these.
+pub const TRACE_TARGET: &str = "sia_fuse_trace";
+
+/// Per-layer filter that thins out per-op debug logging on busy mounts.
+/// Only every n-th debug/trace event from each call site is emitted, so each
+/// op type is sampled independently; info, warnings and errors always pass.
+pub struct DebugSampler {
+    rate: u64,
+    counts: Mutex<HashMap<Identifier, u64>>,
+}
+
+impl DebugSampler {
+    /// Create a sampler emitting one in `rate` debug events per call site.
+    /// A rate of 0 or 1 disables sampling.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<S> Filter<S> for DebugSampler {
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        if self.rate <= 1 || !meta.is_event() || *meta.level() < Level::DEBUG {
+            return true;
+        }
+
+        let mut counts = self.counts.lock();
+        let count = counts.entry(meta.callsite()).or_insert(0);
+        let emit = count.is_multiple_of(self.rate);
+        *count += 1;
+        emit
+    }
+}
+
+/// Layer appending the per-operation events to `path` as JSON lines, at
+/// whatever level the rest of the logging runs
+pub fn trace_layer<S>(path: &Path) -> io::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_target(false)
+        .with_level(false)
+        .with_writer(std::sync::Mutex::<File>::new(file))
+        .with_filter(Targets::new().with_target(TRACE_TARGET, Level::TRACE)))
+}
@@ -1,12 +1,27 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-mod fuse_impl;
-mod storage;
-
-use fuse_impl::SiaFuseFilesystem;
+use sia_fuse_rs::bench::{self, BenchOp};
+use sia_fuse_rs::config::{self, Config};
+use sia_fuse_rs::control::{self, ControlRequest};
+use sia_fuse_rs::fsid::FsUuid;
+use sia_fuse_rs::logging::{self, DebugSampler, TRACE_TARGET};
+use sia_fuse_rs::metrics::MetricsServer;
+use sia_fuse_rs::mount;
+#[cfg(feature = "renterd")]
+use sia_fuse_rs::renterd::{self, KeyEncoding, OutagePolicy, RenterdClient};
+#[cfg(feature = "renterd")]
+use sia_fuse_rs::storage::SiaStorage;
+#[cfg(feature = "sqlite")]
+use sia_fuse_rs::storage::SqliteStorage;
+use sia_fuse_rs::storage::{
+    self, CacheLayer, LocalMirrorStorage, PassthroughStorage, UnionStorage,
+};
+use sia_fuse_rs::{FsOptions, InMemoryStorage, SiaFuseFilesystem, Storage};
 
 #[derive(Parser)]
 #[command(name = "sia-fuse")]
@@ -17,25 +32,375 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+// Parsed once at startup, so Mount carrying every flag inline is fine
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Mount Sia filesystem
     Mount {
         /// Mount point directory
         mountpoint: PathBuf,
 
+        /// Configuration file [default: ~/.config/sia-fuse/config.toml]
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+
         /// Enable debug logging
         #[arg(short, long)]
         debug: bool,
 
+        /// Only log one in N per-operation debug lines
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        debug_sample: u64,
+
+        /// Append a JSON line per FUSE operation (arguments, result and
+        /// latency) to this file, whatever the log level
+        #[arg(long, value_name = "PATH")]
+        trace_file: Option<PathBuf>,
+
         /// Allow other users to access the filesystem
         #[arg(long)]
         allow_other: bool,
+
+        /// Umask (octal) applied to new files and directories instead of
+        /// the caller's
+        #[arg(long, value_parser = parse_octal)]
+        umask: Option<u32>,
+
+        /// Use each caller's umask instead of --umask
+        #[arg(long)]
+        umask_from_request: bool,
+
+        /// Reserve a file name for control files (repeatable)
+        #[arg(long = "reserved-name", value_name = "NAME")]
+        reserved_names: Vec<String>,
+
+        /// Expose a read-only .checksums file listing every file's BLAKE3 hash
+        #[arg(long)]
+        checksums: bool,
+
+        /// Expose a read-only .sia-stats file holding statistics as JSON
+        #[arg(long)]
+        stats: bool,
+
+        /// List control files such as .sia-stats in the mount's root
+        #[arg(long)]
+        show_control_files: bool,
+
+        /// Update a file's mtime as soon as it is opened for writing
+        #[arg(long)]
+        touch_on_open_write: bool,
+
+        /// How long (ms) the kernel may cache file attributes; 0 disables
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        attr_ttl: u64,
+
+        /// How long (ms) the kernel may cache name lookups; 0 disables
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        entry_ttl: u64,
+
+        /// Total bytes of file content the mount may hold (reported by df)
+        #[arg(long, value_name = "BYTES")]
+        capacity: Option<u64>,
+
+        /// Don't update access times when files are read
+        #[arg(long)]
+        noatime: bool,
+
+        /// Refuse new entries in directories holding this many (0 = unlimited)
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        max_dir_entries: usize,
+
+        /// Refuse to create more than this many files and directories (0 = unlimited)
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        max_inodes: u64,
+
+        /// Buffer writes, merging small ones, and upload in the background;
+        /// flush and fsync still wait for the upload
+        #[arg(long)]
+        write_back: bool,
+
+        /// With --write-back, block writes once this many files await upload
+        #[arg(long, value_name = "N", default_value_t = storage::DEFAULT_MAX_DIRTY)]
+        max_dirty_files: usize,
+
+        /// With --write-back, block writes while this many bytes await
+        /// upload, until three quarters of them are uploaded
+        /// [default: unlimited]
+        #[arg(long, value_name = "BYTES")]
+        max_dirty_bytes: Option<u64>,
+
+        /// With --write-back, pass a file's buffered writes on and upload it
+        /// once it has gone this long (ms) without a write
+        #[arg(long, value_name = "MS", default_value_t = storage::DEFAULT_FLUSH_INTERVAL.as_millis() as u64)]
+        flush_interval: u64,
+
+        /// Serve Prometheus metrics over HTTP at this address, e.g.
+        /// 127.0.0.1:9100
+        #[arg(long, value_name = "ADDR")]
+        metrics_addr: Option<SocketAddr>,
+
+        /// Round stored mtime/ctime down to this granularity in ms (e.g. 2000
+        /// for FAT); 0 keeps full precision
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        time_resolution: u64,
+
+        /// Read back every write and log mismatches (slow, for debugging).
+        /// With --write-back, each read-back passes the file's buffered
+        /// writes on, so writes effectively go straight through.
+        #[arg(long)]
+        write_verify: bool,
+
+        /// With --write-verify, fail mismatched writes with EIO
+        #[arg(long, requires = "write_verify")]
+        write_verify_strict: bool,
+
+        /// Group to give files and directories created by root
+        #[arg(long, value_name = "GID")]
+        root_create_gid: Option<u32>,
+
+        /// Mode (octal) for files created with no permission bits
+        #[arg(long, value_parser = parse_octal, default_value = "644")]
+        default_file_mode: u32,
+
+        /// Create missing parent directories of files created by path with
+        /// `sia-fuse create`, instead of failing with ENOENT
+        #[arg(long)]
+        auto_mkdir: bool,
+
+        /// Largest write the kernel may send in one request, in bytes
+        /// (default 16 MiB)
+        #[arg(long, value_name = "BYTES")]
+        max_write: Option<u32>,
+
+        /// Keep the filesystem in this sqlite database instead of in memory
+        #[cfg(feature = "sqlite")]
+        #[arg(long, value_name = "PATH")]
+        db: Option<PathBuf>,
+
+        /// With --db, delete inodes no directory entry leads to once they
+        /// have gone this long (s) unchanged
+        #[cfg(feature = "sqlite")]
+        #[arg(long, value_name = "SECS", default_value_t = storage::DEFAULT_ORPHAN_GRACE.as_secs())]
+        orphan_grace: u64,
+
+        /// With --db, give the filesystem this UUID, kept for later mounts
+        #[cfg(feature = "sqlite")]
+        #[arg(long, value_name = "UUID")]
+        uuid: Option<FsUuid>,
+
+        /// Store files in the renterd node at this URL (e.g. http://localhost:9980)
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "URL")]
+        renterd_url: Option<String>,
+
+        /// renterd API password
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "PASSWORD")]
+        renterd_password: Option<String>,
+
+        /// Bytes of a sequentially written file gathered into each upload
+        /// to renterd, and downloaded at a time for reads [default: 4 MiB]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "BYTES")]
+        chunk_size: Option<u64>,
+
+        /// Bytes of chunks downloaded from renterd kept in memory for
+        /// reads; 0 downloads whole files instead [default: 256 MiB]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "BYTES")]
+        read_cache_size: Option<u64>,
+
+        /// Chunks downloaded ahead of a file being read sequentially; 0
+        /// disables readahead [default: 4]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "N")]
+        readahead_chunks: Option<u64>,
+
+        /// Ask renterd for a file's size and mtime when its attributes are
+        /// read and were last checked this many seconds ago, to pick up
+        /// changes made by other clients [default: never]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "SECS")]
+        attr_refresh: Option<u64>,
+
+        /// With --attr-refresh, keep a file's local attributes for this
+        /// many seconds after its changes are uploaded, while renterd may
+        /// still report older ones [default: 30]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "SECS")]
+        trust_local: Option<u64>,
+
+        /// Retry renterd requests that are safe to repeat and fail with a
+        /// server error, 429, timeout or failure to connect this many
+        /// times [default: 3]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "N")]
+        max_retries: Option<u32>,
+
+        /// Wait this long (ms) before retrying a renterd request, doubling
+        /// for each further retry [default: 100]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "MS")]
+        retry_base_ms: Option<u64>,
+
+        /// How file names map to renterd object keys: percent encodes `%`,
+        /// `#`, `?` and control characters, plain uses names unchanged
+        /// (for buckets whose names contain `%` from before encoding)
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "ENCODING", default_value = "percent")]
+        key_encoding: KeyEncoding,
+
+        /// While renterd is down, fail reads of content not cached with an
+        /// I/O error, or block them until it is back; changes are held in
+        /// the cache and uploaded once it is
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "POLICY", default_value = "fail")]
+        on_backend_down: OutagePolicy,
+
+        /// Keep at most this many requests to renterd in flight at once;
+        /// further ones wait their turn [default: 8]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "N")]
+        backend_connections: Option<usize>,
+
+        /// Keep at most this many downloads from renterd in flight at
+        /// once; further reads queue [default: unlimited]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "N")]
+        max_concurrent_reads: Option<usize>,
+
+        /// Keep at most this many uploads to renterd in flight at once;
+        /// further writes queue [default: unlimited]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "N")]
+        max_concurrent_writes: Option<usize>,
+
+        /// Upload to renterd at most this many bytes per second, across
+        /// all transfers; only background uploads wait for it, fsync and
+        /// close go at full speed [default: unlimited]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "BYTES")]
+        upload_limit: Option<u64>,
+
+        /// Download from renterd at most this many bytes per second,
+        /// across all transfers; only readahead waits for it, reads go at
+        /// full speed [default: unlimited]
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "BYTES")]
+        download_limit: Option<u64>,
+
+        /// Show these renterd buckets (comma-separated) as top-level
+        /// directories instead of mounting a single bucket
+        #[cfg(feature = "renterd")]
+        #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+        buckets: Vec<String>,
+
+        /// Show every renterd bucket as a top-level directory
+        #[cfg(feature = "renterd")]
+        #[arg(long)]
+        all_buckets: bool,
+
+        /// Mirror the filesystem onto this local directory, for working
+        /// without a renterd node
+        #[arg(long, value_name = "PATH")]
+        mirror_dir: Option<PathBuf>,
+
+        /// Show a host directory, read-only, as a directory in the mount's
+        /// root, e.g. local=/srv/files for /local
+        #[arg(long, value_name = "NAME=DIR", value_parser = parse_overlay)]
+        overlay: Option<(String, PathBuf)>,
+    },
+
+    /// Unmount a mounted sia-fuse filesystem
+    Unmount {
+        /// Mount point directory
+        mountpoint: PathBuf,
+    },
+
+    /// Drop the kernel's cached data for a path inside a mount
+    Invalidate {
+        /// File or directory inside a mounted sia-fuse filesystem
+        path: PathBuf,
+    },
+
+    /// Show or change the bandwidth limits of a mount's transfers
+    Throttle {
+        /// Any path inside a mounted sia-fuse filesystem
+        path: PathBuf,
+
+        /// Bytes per second to upload at most; 0 removes the limit
+        #[arg(long, value_name = "BYTES")]
+        upload: Option<u64>,
+
+        /// Bytes per second to download at most; 0 removes the limit
+        #[arg(long, value_name = "BYTES")]
+        download: Option<u64>,
+    },
+
+    /// Show whether a mount takes changes and how much awaits upload
+    Status {
+        /// Any path inside a mounted sia-fuse filesystem
+        path: PathBuf,
+    },
+
+    /// List a mount's open file handles as JSON
+    Handles {
+        /// Any path inside a mounted sia-fuse filesystem
+        path: PathBuf,
+    },
+
+    /// Create an empty file inside a mount, and its missing parent
+    /// directories if the mount has --auto-mkdir
+    Create {
+        /// File to create inside a mounted sia-fuse filesystem
+        path: PathBuf,
+
+        /// Mode (octal) for the file, less the umask
+        #[arg(long, value_parser = parse_octal, default_value = "666")]
+        mode: u32,
+    },
+
+    /// Measure throughput and latency of a mounted filesystem
+    Bench {
+        /// Directory to run the workload in (any filesystem)
+        path: PathBuf,
+
+        /// Amount of data to write or read, in MiB
+        #[arg(long, value_name = "MIB", default_value_t = 64)]
+        size: u64,
+
+        /// Workload: seq-write, seq-read, rand-read or metadata
+        #[arg(long, default_value = "seq-write")]
+        op: BenchOp,
+    },
+
+    /// Check a sqlite database for dangling entries, orphaned inodes and
+    /// wrong link counts; exits non-zero if any are found
+    #[cfg(feature = "sqlite")]
+    Fsck {
+        /// Database file, as passed to `mount --db` (must not be mounted)
+        db: PathBuf,
+
+        /// Delete dangling entries and correct link counts
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Print the UUID of a sqlite database, or change it
+    #[cfg(feature = "sqlite")]
+    Uuid {
+        /// Database file, as passed to `mount --db` (must not be mounted)
+        db: PathBuf,
+
+        /// Replace the UUID with this one
+        #[arg(long, value_name = "UUID")]
+        set: Option<FsUuid>,
     },
 
     /// Initialize configuration
     Init {
         /// Configuration directory
-        #[arg(short, long, default_value = "~/.config/sia-fuse")]
+        #[arg(short, long, default_value = config::DEFAULT_CONFIG_DIR)]
         config_dir: PathBuf,
     },
 
@@ -49,22 +414,107 @@ fn main() -> Result<()> {
     match cli.command {
         Commands::Mount {
             mountpoint,
+            config,
             debug,
+            debug_sample,
+            trace_file,
             allow_other,
+            umask,
+            umask_from_request,
+            reserved_names,
+            checksums,
+            stats,
+            show_control_files,
+            touch_on_open_write,
+            attr_ttl,
+            entry_ttl,
+            capacity,
+            noatime,
+            max_dir_entries,
+            max_inodes,
+            write_back,
+            max_dirty_files,
+            max_dirty_bytes,
+            flush_interval,
+            metrics_addr,
+            time_resolution,
+            write_verify,
+            write_verify_strict,
+            root_create_gid,
+            default_file_mode,
+            auto_mkdir,
+            max_write,
+            #[cfg(feature = "sqlite")]
+            db,
+            #[cfg(feature = "sqlite")]
+            orphan_grace,
+            #[cfg(feature = "sqlite")]
+                uuid: set_uuid,
+            #[cfg(feature = "renterd")]
+            renterd_url,
+            #[cfg(feature = "renterd")]
+            renterd_password,
+            #[cfg(feature = "renterd")]
+            chunk_size,
+            #[cfg(feature = "renterd")]
+            read_cache_size,
+            #[cfg(feature = "renterd")]
+            readahead_chunks,
+            #[cfg(feature = "renterd")]
+            attr_refresh,
+            #[cfg(feature = "renterd")]
+            trust_local,
+            #[cfg(feature = "renterd")]
+            max_retries,
+            #[cfg(feature = "renterd")]
+            retry_base_ms,
+            #[cfg(feature = "renterd")]
+            key_encoding,
+            #[cfg(feature = "renterd")]
+            on_backend_down,
+            #[cfg(feature = "renterd")]
+            backend_connections,
+            #[cfg(feature = "renterd")]
+            max_concurrent_reads,
+            #[cfg(feature = "renterd")]
+            max_concurrent_writes,
+            #[cfg(feature = "renterd")]
+            upload_limit,
+            #[cfg(feature = "renterd")]
+            download_limit,
+            #[cfg(feature = "renterd")]
+            buckets,
+            #[cfg(feature = "renterd")]
+            all_buckets,
+            mirror_dir,
+            overlay,
         } => {
             // Initialize logging
             let filter = if debug {
-                EnvFilter::new("sia_fuse_rs=debug")
+                EnvFilter::new("sia_fuse=debug,sia_fuse_rs=debug")
             } else {
-                EnvFilter::new("sia_fuse_rs=info")
+                EnvFilter::new("sia_fuse=info,sia_fuse_rs=info")
+            };
+            // Per-operation events only go to the trace file
+            let filter = filter.add_directive(format!("{}=off", TRACE_TARGET).parse()?);
+            let trace = match &trace_file {
+                Some(path) => Some(logging::trace_layer(path).map_err(|e| {
+                    anyhow::anyhow!("cannot open trace file {}: {}", path.display(), e)
+                })?),
+                None => None,
             };
 
             tracing_subscriber::registry()
-                .with(fmt::layer())
-                .with(filter)
+                .with(
+                    fmt::layer()
+                        .with_filter(DebugSampler::new(debug_sample))
+                        .with_filter(filter),
+                )
+                .with(trace)
                 .init();
 
             tracing::info!("Starting sia-fuse v{}", env!("CARGO_PKG_VERSION"));
+            let config = Config::load_or_default(config.as_deref())?;
             tracing::info!("Mounting at: {}", mountpoint.display());
 
             // Create mountpoint if it doesn't exist
@@ -74,11 +524,178 @@ fn main() -> Result<()> {
             }
 
             // Create filesystem
-            let fs = SiaFuseFilesystem::new();
+            let storage = match capacity.or(config.capacity) {
+                Some(capacity) => InMemoryStorage::with_quota(capacity),
+                None => InMemoryStorage::new(),
+            };
+            let storage = storage
+                .with_max_dir_entries(max_dir_entries)
+                .with_max_inodes(max_inodes)
+                .with_noatime(noatime)
+                .with_time_resolution(Duration::from_millis(time_resolution));
+
+            // Optional persistent backends keep the in-memory store as
+            // their cache, so they share its limits
+            let (backend, uuid): (Option<Box<dyn Storage>>, Option<FsUuid>) = (None, None);
+            #[cfg(feature = "sqlite")]
+            let (backend, uuid) = match db {
+                Some(db) => {
+                    tracing::info!("Using database at {}", db.display());
+                    let sqlite = SqliteStorage::open(&db, storage.clone())?;
+                    sqlite.reclaim_orphans(Duration::from_secs(orphan_grace))?;
+                    if let Some(uuid) = set_uuid {
+                        sqlite.set_uuid(uuid)?;
+                    }
+                    let uuid = sqlite.uuid()?;
+                    tracing::info!("Filesystem UUID {}", uuid);
+                    (Some(Box::new(sqlite) as Box<dyn Storage>), Some(uuid))
+                }
+                None if set_uuid.is_some() => anyhow::bail!("--uuid needs --db"),
+                None => (backend, uuid),
+            };
+            #[cfg(feature = "renterd")]
+            let backend = match renterd_url.or(config.renterd_url.clone()) {
+                Some(_) if backend.is_some() => {
+                    anyhow::bail!("renterd cannot be combined with --db")
+                }
+                Some(url) => {
+                    tracing::info!("Using renterd at {}", url);
+                    let password = renterd_password
+                        .or(config.renterd_password.clone())
+                        .unwrap_or_default();
+                    let retry_base = match retry_base_ms.or(config.retry_base_ms) {
+                        Some(ms) => Duration::from_millis(ms),
+                        None => renterd::DEFAULT_RETRY_BASE,
+                    };
+                    let mut client = RenterdClient::new(&url, &password)?
+                        .with_bandwidth(
+                            upload_limit.or(config.upload_limit).unwrap_or(0),
+                            download_limit.or(config.download_limit).unwrap_or(0),
+                        )
+                        .with_retries(
+                            max_retries
+                                .or(config.max_retries)
+                                .unwrap_or(renterd::DEFAULT_MAX_RETRIES),
+                            retry_base,
+                        )
+                        .with_connections(
+                            backend_connections
+                                .or(config.backend_connections)
+                                .unwrap_or(renterd::DEFAULT_CONNECTIONS),
+                        )
+                        .with_transfer_limits(
+                            max_concurrent_reads
+                                .or(config.max_concurrent_reads)
+                                .unwrap_or(0),
+                            max_concurrent_writes
+                                .or(config.max_concurrent_writes)
+                                .unwrap_or(0),
+                        )
+                        .with_key_encoding(key_encoding)
+                        .with_outage_policy(on_backend_down);
+                    if let Some(bucket) = &config.bucket {
+                        client = client.with_bucket(bucket);
+                    }
+                    let buckets = match buckets {
+                        buckets if !buckets.is_empty() => Some(buckets),
+                        _ if !config.buckets.is_empty() => Some(config.buckets.clone()),
+                        _ if all_buckets || config.all_buckets => Some(Vec::new()),
+                        _ => None,
+                    };
+                    let mut sia = match buckets {
+                        Some(buckets) => {
+                            SiaStorage::open_buckets(client, &buckets, storage.clone())?
+                        }
+                        None => SiaStorage::open(client, storage.clone())?,
+                    };
+                    if let Some(passphrase) = &config.passphrase {
+                        sia = sia.with_passphrase(passphrase)?;
+                    }
+                    if let Some(chunk_size) = chunk_size.or(config.chunk_size) {
+                        sia = sia.with_chunk_size(chunk_size);
+                    }
+                    if let Some(size) = read_cache_size.or(config.read_cache_size) {
+                        sia = sia.with_read_cache_size(size);
+                    }
+                    if let Some(chunks) = readahead_chunks.or(config.readahead_chunks) {
+                        sia = sia.with_readahead_chunks(chunks);
+                    }
+                    if let Some(secs) = attr_refresh.or(config.attr_refresh_secs) {
+                        sia = sia.with_attr_refresh(Duration::from_secs(secs));
+                    }
+                    if let Some(secs) = trust_local.or(config.trust_local_secs) {
+                        sia = sia.with_trust_local(Duration::from_secs(secs));
+                    }
+                    Some(Box::new(sia) as Box<dyn Storage>)
+                }
+                None => backend,
+            };
+            #[cfg(not(feature = "renterd"))]
+            if config.renterd_url.is_some() {
+                tracing::warn!(
+                    "renterd_url is configured but this build lacks the renterd feature"
+                );
+            }
+            let backend = match mirror_dir {
+                Some(_) if backend.is_some() => {
+                    anyhow::bail!("--mirror-dir cannot be combined with another backend")
+                }
+                Some(dir) => {
+                    tracing::info!("Mirroring to {}", dir.display());
+                    let mirror = LocalMirrorStorage::open(&dir, storage.clone())?;
+                    Some(Box::new(mirror) as Box<dyn Storage>)
+                }
+                None => backend,
+            };
+            let storage = match backend {
+                Some(backend) => backend,
+                None => Box::new(storage),
+            };
+            let storage: Box<dyn Storage> = if write_back {
+                let cache = CacheLayer::new(storage)?
+                    .with_max_dirty(max_dirty_files)
+                    .with_flush_interval(Duration::from_millis(flush_interval));
+                match max_dirty_bytes {
+                    Some(bytes) => Box::new(cache.with_max_dirty_bytes(bytes)),
+                    None => Box::new(cache),
+                }
+            } else {
+                storage
+            };
+            let storage: Box<dyn Storage> = match overlay {
+                Some((name, dir)) => {
+                    tracing::info!("Showing {} as /{}", dir.display(), name);
+                    let host = PassthroughStorage::open(&dir)?;
+                    Box::new(UnionStorage::new(storage, Box::new(host), &name))
+                }
+                None => storage,
+            };
+
+            let fs = SiaFuseFilesystem::with_storage(
+                storage,
+                FsOptions {
+                    umask,
+                    umask_from_request,
+                    reserved_names,
+                    checksums,
+                    stats,
+                    show_control_files,
+                    touch_on_open_write,
+                    attr_ttl: Duration::from_millis(attr_ttl),
+                    entry_ttl: Duration::from_millis(entry_ttl),
+                    write_verify,
+                    write_verify_strict,
+                    root_create_gid,
+                    default_file_mode,
+                    auto_mkdir,
+                    max_write,
+                    uuid,
+                },
+            );
 
             // Mount options
             let mut options = vec![
-                fuser::MountOption::FSName("sia-fuse".to_string()),
+                fuser::MountOption::FSName(mount::FS_NAME.to_string()),
                 fuser::MountOption::RW,
                 fuser::MountOption::AutoUnmount,
             ];
@@ -86,23 +703,146 @@ fn main() -> Result<()> {
             if allow_other {
                 options.push(fuser::MountOption::AllowOther);
             }
+            options.extend(config.mount_options());
+
+            // Stopped when dropped after the filesystem unmounts
+            let metrics = match metrics_addr {
+                Some(addr) => Some(MetricsServer::spawn(
+                    addr,
+                    fs.metrics().clone(),
+                    fs.storage().clone(),
+                )?),
+                None => None,
+            };
 
             tracing::info!("Mounting filesystem...");
-            tracing::info!("Press Ctrl+C to unmount");
 
             // Mount the filesystem (this blocks until unmount)
-            fuser::mount2(fs, mountpoint, &options)?;
+            sia_fuse_rs::mount(fs, &mountpoint, &options)?;
+            drop(metrics);
 
             tracing::info!("Filesystem unmounted");
         }
 
+        Commands::Unmount { mountpoint } => {
+            sia_fuse_rs::unmount(&mountpoint)?;
+            println!("Unmounted {}", mountpoint.display());
+        }
+
+        Commands::Invalidate { path } => {
+            let (socket, relative) = control::find_socket(&path)?;
+            let response = control::send(
+                &socket,
+                &ControlRequest::Invalidate {
+                    path: relative.to_string_lossy().into_owned(),
+                },
+            )?;
+
+            if !response.ok {
+                anyhow::bail!(response.message);
+            }
+            println!("{}", response.message);
+        }
+
+        Commands::Throttle {
+            path,
+            upload,
+            download,
+        } => {
+            let (socket, _) = control::find_socket(&path)?;
+            let response = control::send(&socket, &ControlRequest::Throttle { upload, download })?;
+
+            if !response.ok {
+                anyhow::bail!(response.message);
+            }
+            println!("{}", response.message);
+        }
+
+        Commands::Status { path } => {
+            let (socket, _) = control::find_socket(&path)?;
+            let response = control::send(&socket, &ControlRequest::Status)?;
+
+            if !response.ok {
+                anyhow::bail!(response.message);
+            }
+            println!("{}", response.message);
+        }
+
+        Commands::Handles { path } => {
+            let (socket, _) = control::find_socket(&path)?;
+            let response = control::send(&socket, &ControlRequest::Handles)?;
+
+            if !response.ok {
+                anyhow::bail!(response.message);
+            }
+            println!("{}", response.message);
+        }
+
+        Commands::Create { path, mode } => {
+            let (socket, relative) = control::find_socket(&path)?;
+            // Read the umask the only way there is, by setting it
+            let umask = unsafe { libc::umask(0) };
+            unsafe { libc::umask(umask) };
+            #[allow(clippy::useless_conversion)] // mode_t is u16 on macOS
+            let umask = u32::from(umask);
+            let response = control::send(
+                &socket,
+                &ControlRequest::Create {
+                    path: relative.to_string_lossy().into_owned(),
+                    mode,
+                    umask,
+                },
+            )?;
+
+            if !response.ok {
+                anyhow::bail!(response.message);
+            }
+            println!("{}", response.message);
+        }
+
+        Commands::Bench { path, size, op } => {
+            let report = bench::run(&path, op, size * 1024 * 1024)?;
+            println!("{}", report);
+        }
+
+        #[cfg(feature = "sqlite")]
+        Commands::Fsck { db, repair } => {
+            let report = SqliteStorage::fsck(&db, repair)?;
+            println!("{}", report);
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+        }
+
+        #[cfg(feature = "sqlite")]
+        Commands::Uuid { db, set } => {
+            if !db.exists() {
+                anyhow::bail!("no database at {}", db.display());
+            }
+            let sqlite = SqliteStorage::open(&db, InMemoryStorage::new())?;
+            if let Some(uuid) = set {
+                sqlite.set_uuid(uuid)?;
+            }
+            println!("{}", sqlite.uuid()?);
+        }
+
         Commands::Init { config_dir } => {
+            let config_dir = config::expand_tilde(&config_dir);
             println!("Initializing sia-fuse configuration...");
             println!("Config directory: {}", config_dir.display());
 
             // Create config directory
             std::fs::create_dir_all(&config_dir)?;
 
+            // Keep an existing config rather than resetting the user's edits
+            let config_file = config_dir.join(config::CONFIG_FILE);
+            if config_file.exists() {
+                println!("Keeping existing {}", config_file.display());
+            } else {
+                std::fs::write(&config_file, config::DEFAULT_CONFIG)?;
+                println!("Wrote {}", config_file.display());
+            }
+
             println!();
             println!("Configuration initialized successfully!");
             println!();
@@ -123,3 +863,19 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Parse an overlay given as `NAME=DIR`
+fn parse_overlay(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, dir)) if !name.is_empty() && !name.contains('/') && !dir.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected NAME=DIR, got '{}'", value)),
+    }
+}
+
+/// Parse an octal mode value such as `022` or `0o022`
+fn parse_octal(value: &str) -> Result<u32, String> {
+    let digits = value.trim_start_matches("0o");
+    u32::from_str_radix(digits, 8).map_err(|e| format!("invalid octal value '{}': {}", value, e))
+}
@@ -0,0 +1,516 @@
+use crate::fsid::FsUuid;
+use crate::logging::TRACE_TARGET;
+use crate::storage::{Storage, StorageStats};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// FUSE operations that are counted and timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lookup,
+    Getattr,
+    Setattr,
+    Fallocate,
+    Lseek,
+    CopyFileRange,
+    Read,
+    Write,
+    Open,
+    Flush,
+    Fsync,
+    Release,
+    Create,
+    Mkdir,
+    Mknod,
+    Symlink,
+    Readlink,
+    Link,
+    Unlink,
+    Rmdir,
+    Rename,
+    Opendir,
+    Readdir,
+    Readdirplus,
+    Releasedir,
+    Statfs,
+    Access,
+    Bmap,
+    Getxattr,
+    Setxattr,
+    Listxattr,
+}
+
+impl Op {
+    const ALL: [Op; 31] = [
+        Op::Lookup,
+        Op::Getattr,
+        Op::Setattr,
+        Op::Fallocate,
+        Op::Lseek,
+        Op::CopyFileRange,
+        Op::Read,
+        Op::Write,
+        Op::Open,
+        Op::Flush,
+        Op::Fsync,
+        Op::Release,
+        Op::Create,
+        Op::Mkdir,
+        Op::Mknod,
+        Op::Symlink,
+        Op::Readlink,
+        Op::Link,
+        Op::Unlink,
+        Op::Rmdir,
+        Op::Rename,
+        Op::Opendir,
+        Op::Readdir,
+        Op::Readdirplus,
+        Op::Releasedir,
+        Op::Statfs,
+        Op::Access,
+        Op::Bmap,
+        Op::Getxattr,
+        Op::Setxattr,
+        Op::Listxattr,
+    ];
+
+    /// Label value used in the exported metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Lookup => "lookup",
+            Op::Getattr => "getattr",
+            Op::Setattr => "setattr",
+            Op::Fallocate => "fallocate",
+            Op::Lseek => "lseek",
+            Op::CopyFileRange => "copy_file_range",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Open => "open",
+            Op::Flush => "flush",
+            Op::Fsync => "fsync",
+            Op::Release => "release",
+            Op::Create => "create",
+            Op::Mkdir => "mkdir",
+            Op::Mknod => "mknod",
+            Op::Symlink => "symlink",
+            Op::Readlink => "readlink",
+            Op::Link => "link",
+            Op::Unlink => "unlink",
+            Op::Rmdir => "rmdir",
+            Op::Rename => "rename",
+            Op::Opendir => "opendir",
+            Op::Readdir => "readdir",
+            Op::Readdirplus => "readdirplus",
+            Op::Releasedir => "releasedir",
+            Op::Statfs => "statfs",
+            Op::Access => "access",
+            Op::Bmap => "bmap",
+            Op::Getxattr => "getxattr",
+            Op::Setxattr => "setxattr",
+            Op::Listxattr => "listxattr",
+        }
+    }
+}
+
+/// Operation counters shared between the filesystem and the metrics server
+#[derive(Debug)]
+pub struct Metrics {
+    ops: [AtomicU64; Op::ALL.len()],
+    op_nanos: [AtomicU64; Op::ALL.len()],
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    verify_mismatches: AtomicU64, // Writes that read back differently
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            ops: std::array::from_fn(|_| AtomicU64::new(0)),
+            op_nanos: std::array::from_fn(|_| AtomicU64::new(0)),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            verify_mismatches: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an operation, timing it until the returned guard is dropped
+    pub fn start(self: &Arc<Self>, op: Op) -> OpTimer {
+        OpTimer {
+            metrics: self.clone(),
+            op,
+            started: Instant::now(),
+            ino: None,
+            offset: None,
+            size: None,
+            errno: 0,
+            bytes: None,
+        }
+    }
+
+    pub fn add_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a write whose read-back didn't match (see `--write-verify`)
+    pub fn add_verify_mismatch(&self) {
+        self.verify_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of times `op` has been handled
+    pub fn op_count(&self, op: Op) -> u64 {
+        self.ops[op as usize].load(Ordering::Relaxed)
+    }
+
+    /// The counters and the storage's cache and upload figures in the
+    /// Prometheus text exposition format
+    pub fn render(&self, stats: &StorageStats) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP sia_fuse_ops_total FUSE operations handled.\n");
+        out.push_str("# TYPE sia_fuse_ops_total counter\n");
+        for op in Op::ALL {
+            let _ = writeln!(
+                out,
+                "sia_fuse_ops_total{{op=\"{}\"}} {}",
+                op.name(),
+                self.op_count(op)
+            );
+        }
+
+        out.push_str("# HELP sia_fuse_op_duration_seconds Time spent handling FUSE operations.\n");
+        out.push_str("# TYPE sia_fuse_op_duration_seconds summary\n");
+        for op in Op::ALL {
+            let nanos = self.op_nanos[op as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "sia_fuse_op_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                nanos as f64 / 1e9
+            );
+            let _ = writeln!(
+                out,
+                "sia_fuse_op_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                self.op_count(op)
+            );
+        }
+
+        let metrics = [
+            (
+                "sia_fuse_read_bytes_total",
+                "counter",
+                "Bytes returned by reads.",
+                self.bytes_read.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "sia_fuse_written_bytes_total",
+                "counter",
+                "Bytes accepted by writes.",
+                self.bytes_written.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "sia_fuse_write_verify_mismatches_total",
+                "counter",
+                "Writes that read back differently from what was sent.",
+                self.verify_mismatches.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "sia_fuse_cache_hits_total",
+                "counter",
+                "Reads served from the local cache.",
+                stats.cache_hits as f64,
+            ),
+            (
+                "sia_fuse_cache_misses_total",
+                "counter",
+                "Reads that had to download from the backend.",
+                stats.cache_misses as f64,
+            ),
+            (
+                "sia_fuse_cache_hit_ratio",
+                "gauge",
+                "Share of reads served from the local cache.",
+                hit_ratio(stats),
+            ),
+            (
+                "sia_fuse_read_cache_hits_total",
+                "counter",
+                "Chunk reads served from the read cache.",
+                stats.read_cache_hits as f64,
+            ),
+            (
+                "sia_fuse_read_cache_misses_total",
+                "counter",
+                "Chunk reads that had to download the chunk.",
+                stats.read_cache_misses as f64,
+            ),
+            (
+                "sia_fuse_read_cache_bytes",
+                "gauge",
+                "Bytes of downloaded chunks held in the read cache.",
+                stats.read_cache_bytes as f64,
+            ),
+            (
+                "sia_fuse_pending_uploads",
+                "gauge",
+                "Files with changes not yet written to the backend.",
+                stats.pending_uploads as f64,
+            ),
+            (
+                "sia_fuse_read_only",
+                "gauge",
+                "1 while the backend refuses changes and they fail with EROFS.",
+                stats.read_only as u8 as f64,
+            ),
+            (
+                "sia_fuse_dedup_saved_bytes_total",
+                "counter",
+                "Bytes not uploaded because the backend already had them.",
+                stats.dedup_saved_bytes as f64,
+            ),
+            (
+                "sia_fuse_backend_retries_total",
+                "counter",
+                "Backend requests retried after a transient failure.",
+                stats.backend_retries as f64,
+            ),
+            (
+                "sia_fuse_oldest_sync_timestamp_seconds",
+                "gauge",
+                "When the least recently synced file last matched the backend.",
+                stats.oldest_synced.map_or(0.0, |t| t.timestamp() as f64),
+            ),
+            (
+                "sia_fuse_used_bytes",
+                "gauge",
+                "Bytes of file content stored.",
+                stats.used_bytes as f64,
+            ),
+            (
+                "sia_fuse_used_inodes",
+                "gauge",
+                "Files and directories stored.",
+                stats.used_inodes as f64,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+
+    /// A snapshot of the counters and the storage's figures as a JSON
+    /// object, as read from the `.sia-stats` control file
+    pub fn stats_json(&self, stats: &StorageStats, uuid: Option<FsUuid>) -> String {
+        let snapshot = StatsSnapshot {
+            uuid: uuid.map(|uuid| uuid.to_string()),
+            read_only: stats.read_only,
+            inodes: stats.used_inodes,
+            bytes: stats.used_bytes,
+            dirty_bytes: stats.dirty_bytes,
+            pending_uploads: stats.pending_uploads,
+            oldest_synced: stats.oldest_synced.map(|t| t.to_rfc3339()),
+            cache_hit_ratio: hit_ratio(stats),
+            read_bytes: self.bytes_read.load(Ordering::Relaxed),
+            written_bytes: self.bytes_written.load(Ordering::Relaxed),
+            verify_mismatches: self.verify_mismatches.load(Ordering::Relaxed),
+            ops: Op::ALL.iter().map(|&op| self.op_count(op)).sum(),
+        };
+        // Plain numbers always serialize
+        let mut json = serde_json::to_string_pretty(&snapshot).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+}
+
+/// Fields of [`Metrics::stats_json`]
+#[derive(Serialize)]
+struct StatsSnapshot {
+    uuid: Option<String>, // Filesystem identity, if the backend keeps one
+    read_only: bool,      // The backend refuses changes
+    inodes: u64,
+    bytes: u64,
+    dirty_bytes: u64,
+    pending_uploads: u64,
+    oldest_synced: Option<String>, // Earliest last sync of any file, RFC 3339
+    cache_hit_ratio: f64,
+    read_bytes: u64,
+    written_bytes: u64,
+    verify_mismatches: u64,
+    ops: u64, // FUSE operations handled
+}
+
+/// Share of reads served from the local cache, 1 before any reads
+fn hit_ratio(stats: &StorageStats) -> f64 {
+    let lookups = stats.cache_hits + stats.cache_misses;
+    if lookups == 0 {
+        1.0
+    } else {
+        stats.cache_hits as f64 / lookups as f64
+    }
+}
+
+/// Records an operation's duration when dropped, and logs it with its
+/// arguments and outcome to [`TRACE_TARGET`]
+pub struct OpTimer {
+    metrics: Arc<Metrics>,
+    op: Op,
+    started: Instant,
+    ino: Option<u64>,
+    offset: Option<i64>,
+    size: Option<u64>,
+    errno: i32,           // 0 unless the operation failed
+    bytes: Option<usize>, // Bytes read, written or copied
+}
+
+impl OpTimer {
+    /// Note the inode operated on (the parent, for operations on a name)
+    pub fn ino(mut self, ino: u64) -> Self {
+        self.ino = Some(ino);
+        self
+    }
+
+    /// Note the byte range operated on
+    pub fn range(mut self, offset: i64, size: u64) -> Self {
+        self.offset = Some(offset);
+        self.size = Some(size);
+        self
+    }
+
+    /// Record a failure, passing `errno` on to the reply
+    pub fn fail(&mut self, errno: i32) -> i32 {
+        self.errno = errno;
+        errno
+    }
+
+    /// Record the bytes the operation transferred
+    pub fn transferred(&mut self, bytes: usize) {
+        self.bytes = Some(bytes);
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let nanos = elapsed.as_nanos() as u64;
+        let op = self.op as usize;
+        self.metrics.ops[op].fetch_add(1, Ordering::Relaxed);
+        self.metrics.op_nanos[op].fetch_add(nanos, Ordering::Relaxed);
+
+        tracing::info!(
+            target: TRACE_TARGET,
+            op = self.op.name(),
+            ino = self.ino,
+            offset = self.offset,
+            size = self.size,
+            errno = self.errno,
+            bytes = self.bytes,
+            latency_us = elapsed.as_micros() as u64,
+        );
+    }
+}
+
+/// Serves `GET /metrics` over HTTP on a background thread until dropped
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Bind `addr` and start serving the metrics of `metrics` and `storage`
+    pub fn spawn(
+        addr: SocketAddr,
+        metrics: Arc<Metrics>,
+        storage: Arc<dyn Storage>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        tracing::info!("Serving metrics at http://{}/metrics", addr);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = stream.and_then(|stream| serve(stream, &metrics, &*storage));
+                    if let Err(e) = result {
+                        tracing::debug!("metrics connection failed: {}", e);
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        // Wake the accept loop so it sees the stop flag
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer one HTTP request. Only the request line matters; headers are
+/// read and ignored.
+fn serve(stream: TcpStream, metrics: &Metrics, storage: &dyn Storage) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render(&storage.stats())),
+        ["GET", _] => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,237 @@
+use crate::control::{self, ControlServer};
+use crate::error::{Result, SiaFuseError};
+use crate::fuse_impl::SiaFuseFilesystem;
+use fuser::MountOption;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread::JoinHandle;
+
+/// Filesystem name shown in the mount table
+pub const FS_NAME: &str = "sia-fuse";
+
+/// Mount `fs` at `mountpoint` and serve it until it is unmounted. A control
+/// socket for the mount (see `control::socket_path`) is served alongside.
+pub fn mount(fs: SiaFuseFilesystem, mountpoint: &Path, options: &[MountOption]) -> Result<()> {
+    let storage = fs.storage().clone();
+    let handles = fs.file_handles().clone();
+    let creates = fs.share();
+    let mountpoint = mountpoint.canonicalize().map_err(SiaFuseError::Mount)?;
+    let mut session = fuser::Session::new(fs, &mountpoint, options).map_err(SiaFuseError::Mount)?;
+
+    // Serve control commands (e.g. `sia-fuse invalidate`) for this mount
+    let socket = control::socket_path(&mountpoint);
+    ControlServer::new(storage, session.notifier())
+        .with_handles(handles)
+        .with_filesystem(creates)
+        .spawn(&socket)?;
+
+    let shutdown = ShutdownHandler::install(&mountpoint)?;
+    tracing::info!("Press Ctrl+C to unmount");
+
+    // Run the filesystem (this blocks until unmount)
+    let result = session.run();
+    drop(shutdown);
+    let _ = std::fs::remove_file(&socket);
+    result.map_err(SiaFuseError::Mount)
+}
+
+/// Signals that unmount the filesystem cleanly
+const SHUTDOWN_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Write end of the pipe waking the shutdown thread, or -1
+static SHUTDOWN_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Set by the first shutdown signal; a second one exits immediately
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_signal: libc::c_int) {
+    // Only async-signal-safe calls from here
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+    let fd = SHUTDOWN_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Turns SIGINT, SIGTERM and SIGHUP into a regular unmount while installed,
+/// so the session ends normally and the filesystem's `destroy` flushes
+/// pending writes instead of the process dying with them. The signal
+/// handler only writes to a pipe; a thread waiting on it runs the unmount.
+struct ShutdownHandler {
+    read_fd: libc::c_int,
+    write_fd: libc::c_int,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ShutdownHandler {
+    fn install(mountpoint: &Path) -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(SiaFuseError::Unmount(io::Error::last_os_error()));
+        }
+        let [read_fd, write_fd] = fds;
+
+        let mountpoint = mountpoint.to_path_buf();
+        let thread = std::thread::Builder::new()
+            .name("sia-fuse-shutdown".to_string())
+            .spawn(move || {
+                if !wait_readable(read_fd) {
+                    return;
+                }
+                tracing::info!("Received shutdown signal, unmounting");
+                if let Err(e) = unmount(&mountpoint) {
+                    tracing::error!(
+                        "failed to unmount: {}; signal again to exit without flushing",
+                        e
+                    );
+                }
+            });
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                unsafe {
+                    libc::close(read_fd);
+                    libc::close(write_fd);
+                }
+                return Err(SiaFuseError::Unmount(e));
+            }
+        };
+
+        SHUTDOWN_PIPE.store(write_fd, Ordering::SeqCst);
+        SHUTTING_DOWN.store(false, Ordering::SeqCst);
+        let handler: extern "C" fn(libc::c_int) = on_shutdown_signal;
+        for signal in SHUTDOWN_SIGNALS {
+            unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        }
+
+        Ok(Self {
+            read_fd,
+            write_fd,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ShutdownHandler {
+    fn drop(&mut self) {
+        for signal in SHUTDOWN_SIGNALS {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+        SHUTDOWN_PIPE.store(-1, Ordering::SeqCst);
+
+        // Closing the write end wakes the thread if no signal came
+        unsafe { libc::close(self.write_fd) };
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        unsafe { libc::close(self.read_fd) };
+    }
+}
+
+/// Block until a byte arrives on `fd`. Returns false once the pipe closes.
+fn wait_readable(fd: libc::c_int) -> bool {
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return true,
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ => return false,
+        }
+    }
+}
+
+/// Unmount the sia-fuse filesystem at `mountpoint`. Paths that aren't a
+/// sia-fuse mount are refused rather than handed to the system unmounter.
+pub fn unmount(mountpoint: &Path) -> Result<()> {
+    // A mount whose daemon died can't be canonicalized (ENOTCONN), but
+    // still needs unmounting
+    let mountpoint = mountpoint
+        .canonicalize()
+        .or_else(|_| std::path::absolute(mountpoint))
+        .map_err(SiaFuseError::Unmount)?;
+
+    if !is_sia_fuse_mount(&mountpoint)? {
+        return Err(SiaFuseError::InvalidArgument(format!(
+            "{} is not a sia-fuse mount",
+            mountpoint.display()
+        )));
+    }
+
+    let commands: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("umount", &[])]
+    } else {
+        &[("fusermount3", &["-u"]), ("fusermount", &["-u"])]
+    };
+
+    for (program, args) in commands {
+        let status = match Command::new(program).args(*args).arg(&mountpoint).status() {
+            Ok(status) => status,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(SiaFuseError::Unmount(e)),
+        };
+        if !status.success() {
+            return Err(SiaFuseError::Unmount(io::Error::other(format!(
+                "{} failed with {}",
+                program, status
+            ))));
+        }
+        return Ok(());
+    }
+
+    let names: Vec<&str> = commands.iter().map(|(program, _)| *program).collect();
+    Err(SiaFuseError::Unmount(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no unmount helper found (tried {})", names.join(", ")),
+    )))
+}
+
+/// Whether a filesystem named "sia-fuse" is mounted at `mountpoint`
+#[cfg(target_os = "linux")]
+fn is_sia_fuse_mount(mountpoint: &Path) -> Result<bool> {
+    let mounts = std::fs::read_to_string("/proc/mounts")?;
+    Ok(mounts.lines().any(|line| {
+        let mut fields = line.split(' ');
+        let source = fields.next();
+        let target = fields.next().map(unescape_mount_field);
+        source == Some(FS_NAME) && target.as_deref() == Some(mountpoint.as_os_str())
+    }))
+}
+
+/// Whether a filesystem named "sia-fuse" is mounted at `mountpoint`
+#[cfg(not(target_os = "linux"))]
+fn is_sia_fuse_mount(mountpoint: &Path) -> Result<bool> {
+    // `mount` prints lines like "sia-fuse on /mnt/sia (macfuse, ...)"
+    let output = Command::new("mount").output()?;
+    let target = format!(" on {} (", mountpoint.display());
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with(FS_NAME) && line.contains(&target)))
+}
+
+/// Undo the octal escapes (`\040` for a space) used in /proc/mounts
+#[cfg(target_os = "linux")]
+fn unescape_mount_field(field: &str) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escape.and_then(|e| u8::from_str_radix(std::str::from_utf8(e).ok()?, 8).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    std::ffi::OsString::from_vec(out)
+}
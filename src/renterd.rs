@@ -0,0 +1,1001 @@
+use crate::error::{Result, SiaFuseError};
+use crate::throttle::{Bandwidth, ThrottledReader};
+use chrono::{DateTime, Utc};
+use parking_lot::{Condvar, Mutex};
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::{StatusCode, Url};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Cursor, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Bucket used when none is configured
+pub const DEFAULT_BUCKET: &str = "default";
+
+/// Objects requested per listing page
+const LIST_PAGE: usize = 1000;
+
+/// Retries of a failed request when none are configured
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Wait before the first retry when none is configured; each further
+/// retry waits twice as long
+pub const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(100);
+
+/// Longest wait between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Requests in flight at once when no limit is configured
+pub const DEFAULT_CONNECTIONS: usize = 8;
+
+/// Wait before the first attempt to reach renterd again once it is down,
+/// doubling after each failed attempt
+const RECONNECT_BASE: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts to reach renterd again
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// What requests do while renterd is down, between reconnection attempts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutagePolicy {
+    /// Fail at once as unreachable, so reads not served from the cache
+    /// return EIO
+    #[default]
+    Fail,
+    /// Wait for renterd to come back
+    Block,
+}
+
+/// How file names map to object keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyEncoding {
+    /// Percent-encode `%`, `#`, `?` and control characters, which some
+    /// tools and gateways mangle in keys. Other characters are kept.
+    #[default]
+    Percent,
+    /// Use names as keys unchanged, for buckets written before encoding
+    /// existed whose names contain `%`
+    Plain,
+}
+
+/// An object stored in renterd
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    /// Full key, starting with `/`. Keys ending in `/` are directories.
+    pub key: String,
+    pub size: u64,
+    pub mod_time: Option<DateTime<Utc>>,
+}
+
+/// How renterd keeps an object's data on hosts
+#[derive(Debug, Clone)]
+pub struct ObjectHealth {
+    /// 1 with every shard on a good host, falling as shards are lost
+    /// and 0 once only the shards needed to recover the data are left
+    pub health: f64,
+    /// Shards stored per shard needed to recover the data, None for an
+    /// object without data
+    pub redundancy: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListRequest<'a> {
+    bucket: &'a str,
+    prefix: &'a str,
+    marker: &'a str,
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse {
+    has_more: bool,
+    #[serde(default)]
+    next_marker: String,
+    #[serde(default)]
+    objects: Vec<ListedObject>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListedObject {
+    name: String,
+    size: u64,
+    mod_time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectResponse {
+    object: ObjectDetails,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectDetails {
+    #[serde(default)]
+    size: u64,
+    mod_time: Option<String>,
+    #[serde(default = "full_health")]
+    health: f64,
+    #[serde(default)]
+    slabs: Vec<SlabSlice>,
+}
+
+#[derive(Deserialize)]
+struct SlabSlice {
+    slab: Slab,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Slab {
+    min_shards: u32,
+    #[serde(default)]
+    shards: Vec<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct ContractSetSetting {
+    default: String,
+}
+
+#[derive(Deserialize)]
+struct ListedBucket {
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateBucketRequest<'a> {
+    name: &'a str,
+    policy: BucketPolicy,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BucketPolicy {
+    public_read_access: bool,
+}
+
+#[derive(Serialize)]
+struct RenameRequest<'a> {
+    bucket: &'a str,
+    from: &'a str,
+    to: &'a str,
+    mode: &'a str,
+    force: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CopyRequest<'a> {
+    source_bucket: &'a str,
+    source_path: &'a str,
+    destination_bucket: &'a str,
+    destination_path: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MultipartCreateRequest<'a> {
+    bucket: &'a str,
+    path: &'a str,
+    generate_key: bool,
+}
+
+#[derive(Deserialize)]
+struct MultipartCreateResponse {
+    #[serde(rename = "uploadID")]
+    upload_id: String,
+}
+
+#[derive(Serialize)]
+struct MultipartRequest<'a> {
+    bucket: &'a str,
+    path: &'a str,
+    #[serde(rename = "uploadID")]
+    upload_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parts: Option<&'a [UploadedPart]>,
+}
+
+/// A part of a multipart upload that renterd has accepted
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedPart {
+    pub part_number: u32,
+    pub e_tag: String,
+}
+
+/// How often and how patiently failed requests are retried
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_retries: u32,
+    base: Duration,
+}
+
+/// Cap on the requests in flight at once. Waiting requests get a
+/// connection in the order they asked for one, so none starves.
+#[derive(Debug)]
+struct ConnectionPool {
+    size: usize,
+    state: Mutex<PoolState>,
+    freed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    in_use: usize,
+    next_ticket: u64, // Handed to the next request to arrive
+    serving: u64,     // Ticket of the request next in line
+}
+
+/// Whether renterd is reachable, judged by the last requests
+#[derive(Debug, Default)]
+struct Link {
+    state: Mutex<LinkState>,
+    reconnected: AtomicBool, // Back up since `take_reconnected` was last called
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    down_since: Option<Instant>,
+    attempts: u32,                 // Failed attempts to reach it since
+    next_attempt: Option<Instant>, // When requests may try it again
+}
+
+/// A connection of the pool, given back when dropped
+struct PooledConnection<'a>(&'a ConnectionPool);
+
+/// Blocking client for the renterd bus and worker APIs. Requests safe to
+/// repeat (reads, uploads and deletes, but not POSTs that change state)
+/// are retried with exponential backoff when they fail with a server
+/// error, 429, a timeout or a failure to connect. Once renterd can't be reached at all it is taken
+/// as down: requests fail at once or wait, per the outage policy, while
+/// it is tried again with exponential backoff. Clones share the bandwidth
+/// limits, so they hold for all of a mount's transfers together, the
+/// connection pool, the retry count and whether renterd is down.
+#[derive(Debug, Clone)]
+pub struct RenterdClient {
+    http: Client,
+    url: Url,
+    password: String,
+    bucket: String,
+    bandwidth: Arc<Bandwidth>,
+    connections: Arc<ConnectionPool>,
+    reads: Option<Arc<ConnectionPool>>, // Caps object downloads in flight, if set
+    writes: Option<Arc<ConnectionPool>>, // Caps object and part uploads in flight, if set
+    key_encoding: KeyEncoding,
+    retry: RetryPolicy,
+    retries: Arc<AtomicU64>, // Requests retried so far
+    link: Arc<Link>,
+    outage: OutagePolicy,
+}
+
+impl RenterdClient {
+    /// Client for the renterd node at `url` (e.g. `http://localhost:9980`),
+    /// authenticating with the API password
+    pub fn new(url: &str, password: &str) -> Result<Self> {
+        let url = Url::parse(url)
+            .map_err(|e| SiaFuseError::Config(format!("invalid renterd url '{}': {}", url, e)))?;
+        if url.cannot_be_a_base() {
+            return Err(SiaFuseError::Config(format!(
+                "invalid renterd url '{}'",
+                url
+            )));
+        }
+
+        Ok(Self {
+            http: Client::new(),
+            url,
+            password: password.to_string(),
+            bucket: DEFAULT_BUCKET.to_string(),
+            bandwidth: Arc::new(Bandwidth::default()),
+            connections: Arc::new(ConnectionPool::new(DEFAULT_CONNECTIONS)),
+            reads: None,
+            writes: None,
+            key_encoding: KeyEncoding::default(),
+            retry: RetryPolicy {
+                max_retries: DEFAULT_MAX_RETRIES,
+                base: DEFAULT_RETRY_BASE,
+            },
+            retries: Arc::new(AtomicU64::new(0)),
+            link: Arc::new(Link::default()),
+            outage: OutagePolicy::default(),
+        })
+    }
+
+    /// Store objects in `bucket` instead of the default one
+    pub fn with_bucket(mut self, bucket: &str) -> Self {
+        self.bucket = bucket.to_string();
+        self
+    }
+
+    /// Bucket the client stores objects in
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Limit object uploads and downloads to `upload` and `download` bytes
+    /// per second; 0 leaves a direction unlimited
+    pub fn with_bandwidth(mut self, upload: u64, download: u64) -> Self {
+        self.bandwidth = Arc::new(Bandwidth::new(upload, download));
+        self
+    }
+
+    /// Limits on object transfers, which can be changed while in use
+    pub fn bandwidth(&self) -> &Arc<Bandwidth> {
+        &self.bandwidth
+    }
+
+    /// Keep at most `size` requests in flight at once, across all clones
+    pub fn with_connections(mut self, size: usize) -> Self {
+        self.connections = Arc::new(ConnectionPool::new(size.max(1)));
+        self
+    }
+
+    /// Keep at most `reads` downloads and `writes` uploads in flight at
+    /// once, across all clones, so a burst of one kind queues instead of
+    /// taking every connection; 0 leaves a kind limited only by the pool
+    pub fn with_transfer_limits(mut self, reads: usize, writes: usize) -> Self {
+        let limit = |size| (size > 0).then(|| Arc::new(ConnectionPool::new(size)));
+        self.reads = limit(reads);
+        self.writes = limit(writes);
+        self
+    }
+
+    /// Map file names to object keys with `encoding`. Keys passed to and
+    /// returned from the client are always the unencoded paths.
+    pub fn with_key_encoding(mut self, encoding: KeyEncoding) -> Self {
+        self.key_encoding = encoding;
+        self
+    }
+
+    /// Retry a failing request up to `max_retries` times, waiting `base`
+    /// before the first retry and twice as long before each next one
+    pub fn with_retries(mut self, max_retries: u32, base: Duration) -> Self {
+        self.retry = RetryPolicy { max_retries, base };
+        self
+    }
+
+    /// Fail or wait, per `policy`, while renterd is down
+    pub fn with_outage_policy(mut self, policy: OutagePolicy) -> Self {
+        self.outage = policy;
+        self
+    }
+
+    /// Whether renterd is taken as down
+    pub fn is_down(&self) -> bool {
+        self.link.state.lock().down_since.is_some()
+    }
+
+    /// Whether renterd came back after being down since this was last
+    /// called, e.g. so changes held meanwhile can be uploaded
+    pub fn take_reconnected(&self) -> bool {
+        self.link.reconnected.swap(false, Ordering::Relaxed)
+    }
+
+    /// Requests retried after a transient failure so far
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Names of all buckets in the node
+    pub fn list_buckets(&self) -> Result<Vec<String>> {
+        let buckets: Vec<ListedBucket> =
+            self.send_json(|| self.api(reqwest::Method::GET, &["bus", "buckets"]))?;
+        Ok(buckets.into_iter().map(|b| b.name).collect())
+    }
+
+    /// Create a private bucket named `name`
+    pub fn create_bucket(&self, name: &str) -> Result<()> {
+        let request = CreateBucketRequest {
+            name,
+            policy: BucketPolicy {
+                public_read_access: false,
+            },
+        };
+        self.mutate(|| self.post(&["bus", "buckets"]).json(&request))?;
+        Ok(())
+    }
+
+    /// Delete the bucket named `name`, which must be empty
+    pub fn delete_bucket(&self, name: &str) -> Result<()> {
+        self.send(|| self.api(reqwest::Method::DELETE, &["bus", "bucket", name]))?;
+        Ok(())
+    }
+
+    /// List every object whose key starts with `prefix`
+    pub fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut marker = String::new();
+        let prefix = self.key_encoding.encode(prefix);
+
+        loop {
+            let request = ListRequest {
+                bucket: &self.bucket,
+                prefix: &prefix,
+                marker: &marker,
+                limit: LIST_PAGE,
+            };
+            let page: ListResponse =
+                self.send_json(|| self.post(&["bus", "objects", "list"]).json(&request))?;
+
+            objects.extend(page.objects.into_iter().map(|o| ObjectInfo {
+                key: self.key_encoding.decode(&o.name),
+                size: o.size,
+                mod_time: parse_time(o.mod_time),
+            }));
+
+            if !page.has_more || page.next_marker.is_empty() {
+                break;
+            }
+            marker = page.next_marker;
+        }
+
+        Ok(objects)
+    }
+
+    /// Size and modification time of the object at `key`
+    pub fn object_info(&self, key: &str) -> Result<ObjectInfo> {
+        let object = self.object_details(key)?;
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: object.size,
+            mod_time: parse_time(object.mod_time),
+        })
+    }
+
+    /// Health and redundancy of the object at `key`
+    pub fn object_health(&self, key: &str) -> Result<ObjectHealth> {
+        let object = self.object_details(key)?;
+
+        let (stored, needed) = object.slabs.iter().fold((0, 0), |(stored, needed), slice| {
+            (
+                stored + slice.slab.shards.len() as u64,
+                needed + slice.slab.min_shards as u64,
+            )
+        });
+        Ok(ObjectHealth {
+            health: object.health,
+            redundancy: (needed > 0).then(|| stored as f64 / needed as f64),
+        })
+    }
+
+    fn object_details(&self, key: &str) -> Result<ObjectDetails> {
+        let response: ObjectResponse =
+            self.send_json(|| self.keyed(reqwest::Method::GET, &["bus", "objects"], key))?;
+        Ok(response.object)
+    }
+
+    /// Name of the contract set renterd uploads to
+    pub fn contract_set(&self) -> Result<String> {
+        let setting: ContractSetSetting =
+            self.send_json(|| self.api(reqwest::Method::GET, &["bus", "setting", "contractset"]))?;
+        Ok(setting.default)
+    }
+
+    /// Key the object at `path` is stored under in the bucket
+    pub fn object_key(&self, path: &str) -> String {
+        self.key_encoding.encode(path)
+    }
+
+    /// Download the whole object at `key`
+    pub fn download(&self, key: &str) -> Result<Vec<u8>> {
+        let _slot = self.reads.as_deref().map(ConnectionPool::acquire);
+        self.download_body(|| self.object(reqwest::Method::GET, key))
+    }
+
+    /// Download `len` bytes of the object at `key` starting at `offset`.
+    /// Less comes back if the object ends sooner.
+    pub fn download_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let _slot = self.reads.as_deref().map(ConnectionPool::acquire);
+        self.download_body(|| {
+            self.object(reqwest::Method::GET, key)
+                .header(reqwest::header::RANGE, &range)
+        })
+    }
+
+    /// Upload `data` as the object at `key`, replacing any existing one
+    pub fn upload(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let data = data.into();
+        let _slot = self.writes.as_deref().map(ConnectionPool::acquire);
+        self.send(|| {
+            self.object(reqwest::Method::PUT, key)
+                .body(self.body(&data))
+        })?;
+        Ok(())
+    }
+
+    /// Delete the object at `key`. Deleting a missing object succeeds.
+    pub fn delete(&self, key: &str) -> Result<()> {
+        match self.send(|| self.object(reqwest::Method::DELETE, key)) {
+            Err(SiaFuseError::NotFound) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Rename the object at `from`, or with `prefix` set every object
+    /// under it, replacing whatever is at `to`
+    pub fn rename(&self, from: &str, to: &str, prefix: bool) -> Result<()> {
+        let (from, to) = (self.key_encoding.encode(from), self.key_encoding.encode(to));
+        let request = RenameRequest {
+            bucket: &self.bucket,
+            from: &from,
+            to: &to,
+            mode: if prefix { "multi" } else { "single" },
+            force: true,
+        };
+        self.mutate(|| self.post(&["bus", "objects", "rename"]).json(&request))?;
+        Ok(())
+    }
+
+    /// Copy the object at `from` to `to` within the bucket. The copy shares
+    /// the original's slabs, so no data is uploaded.
+    pub fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.copy_from(&self.bucket, from, to)
+    }
+
+    /// Copy the object at `from` in `bucket` to `to` in this client's bucket
+    pub fn copy_from(&self, bucket: &str, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (self.key_encoding.encode(from), self.key_encoding.encode(to));
+        let request = CopyRequest {
+            source_bucket: bucket,
+            source_path: &from,
+            destination_bucket: &self.bucket,
+            destination_path: &to,
+        };
+        self.mutate(|| self.post(&["bus", "objects", "copy"]).json(&request))?;
+        Ok(())
+    }
+
+    /// Start a multipart upload to `key`, returning its upload ID
+    pub fn create_multipart(&self, key: &str) -> Result<String> {
+        let request = MultipartCreateRequest {
+            bucket: &self.bucket,
+            path: &self.key_encoding.encode(key),
+            generate_key: true,
+        };
+        let response: MultipartCreateResponse =
+            self.mutate_json(|| self.post(&["bus", "multipart", "create"]).json(&request))?;
+        Ok(response.upload_id)
+    }
+
+    /// Upload part `part_number` (counting from 1) of a multipart upload
+    pub fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: u32,
+        data: Vec<u8>,
+    ) -> Result<UploadedPart> {
+        let data = data.into();
+        let _slot = self.writes.as_deref().map(ConnectionPool::acquire);
+        let e_tag = self.exchange(
+            true,
+            || {
+                self.keyed(reqwest::Method::PUT, &["worker", "multipart"], key)
+                    .query(&[("uploadid", upload_id)])
+                    .query(&[("partnumber", part_number)])
+                    .body(self.body(&data))
+            },
+            |response| {
+                response
+                    .headers()
+                    .get(reqwest::header::ETAG)
+                    .and_then(|tag| tag.to_str().ok())
+                    .map(|tag| tag.trim_matches('"').to_string())
+                    .ok_or_else(|| {
+                        SiaFuseError::Backend("renterd returned no ETag for part".into())
+                    })
+            },
+        )?;
+
+        Ok(UploadedPart { part_number, e_tag })
+    }
+
+    /// Assemble the uploaded parts into the object at `key`
+    pub fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<()> {
+        let request = MultipartRequest {
+            bucket: &self.bucket,
+            path: &self.key_encoding.encode(key),
+            upload_id,
+            parts: Some(parts),
+        };
+        self.mutate(|| self.post(&["bus", "multipart", "complete"]).json(&request))?;
+        Ok(())
+    }
+
+    /// Discard a multipart upload and the parts uploaded so far
+    pub fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let request = MultipartRequest {
+            bucket: &self.bucket,
+            path: &self.key_encoding.encode(key),
+            upload_id,
+            parts: None,
+        };
+        self.mutate(|| self.post(&["bus", "multipart", "abort"]).json(&request))?;
+        Ok(())
+    }
+
+    fn post(&self, path: &[&str]) -> RequestBuilder {
+        self.api(reqwest::Method::POST, path)
+    }
+
+    fn api(&self, method: reqwest::Method, path: &[&str]) -> RequestBuilder {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push("api").extend(path);
+        }
+        self.http.request(method, url)
+    }
+
+    /// Request for a worker object endpoint
+    fn object(&self, method: reqwest::Method, key: &str) -> RequestBuilder {
+        self.keyed(method, &["worker", "objects"], key)
+    }
+
+    /// Request for an endpoint addressed by object key. Each key component
+    /// is percent-encoded separately so `/` keeps separating them.
+    fn keyed(&self, method: reqwest::Method, path: &[&str], key: &str) -> RequestBuilder {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push("api").extend(path).extend(
+                self.key_encoding
+                    .encode(key)
+                    .trim_start_matches('/')
+                    .split('/'),
+            );
+        }
+        url.query_pairs_mut().append_pair("bucket", &self.bucket);
+        self.http.request(method, url)
+    }
+
+    /// Object content to upload, sent within the upload limit
+    fn body(&self, data: &Arc<[u8]>) -> Body {
+        Body::sized(
+            ThrottledReader::upload(Cursor::new(data.clone()), self.bandwidth.clone()),
+            data.len() as u64,
+        )
+    }
+
+    /// Send a request built by `request` and hand the response to `read`,
+    /// holding a pooled connection until `read` is done. With `retry` both
+    /// are retried together, building the request again for each retry.
+    fn exchange<T>(
+        &self,
+        retry: bool,
+        request: impl Fn() -> RequestBuilder,
+        read: impl Fn(Response) -> Result<T>,
+    ) -> Result<T> {
+        self.wait_for_link()?;
+        let attempt = || {
+            let _connection = self.connections.acquire();
+            read(self.send_once(request())?)
+        };
+        let result = match retry {
+            true => self.retry(attempt),
+            false => attempt(),
+        };
+        self.update_link(&result);
+        result
+    }
+
+    /// While renterd is down and the next attempt to reach it isn't due,
+    /// fail or wait for it per the outage policy
+    fn wait_for_link(&self) -> Result<()> {
+        loop {
+            let wait = match self.link.state.lock().next_attempt {
+                Some(at) => at.saturating_duration_since(Instant::now()),
+                None => return Ok(()),
+            };
+            if wait.is_zero() {
+                return Ok(());
+            }
+            match self.outage {
+                OutagePolicy::Fail => {
+                    return Err(SiaFuseError::Unreachable(format!(
+                        "renterd is down, trying again in {}s",
+                        wait.as_secs_f32().ceil()
+                    )))
+                }
+                OutagePolicy::Block => std::thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Take renterd as down after a request couldn't reach it, putting off
+    /// the next attempt, or as back up after one got an answer
+    fn update_link<T>(&self, result: &Result<T>) {
+        let mut state = self.link.state.lock();
+        match result {
+            Err(e @ SiaFuseError::Unreachable(_)) => {
+                let delay = RECONNECT_BASE
+                    .saturating_mul(1 << state.attempts.min(16))
+                    .min(MAX_RECONNECT_DELAY);
+                if state.down_since.is_none() {
+                    tracing::error!("renterd is down ({}); trying again in {:?}", e, delay);
+                    state.down_since = Some(Instant::now());
+                }
+                state.attempts += 1;
+                state.next_attempt = Some(Instant::now() + delay);
+            }
+            _ => {
+                if let Some(since) = state.down_since.take() {
+                    tracing::warn!("renterd is back after {:?}", since.elapsed());
+                    self.link.reconnected.store(true, Ordering::Relaxed);
+                }
+                state.attempts = 0;
+                state.next_attempt = None;
+            }
+        }
+    }
+
+    /// Send a request that is safe to repeat, ignoring the response body
+    fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<()> {
+        self.exchange(true, request, |_| Ok(()))
+    }
+
+    /// Send a request that is safe to repeat and parse the JSON response
+    fn send_json<T: DeserializeOwned>(&self, request: impl Fn() -> RequestBuilder) -> Result<T> {
+        self.exchange(true, request, |response| {
+            response.json().map_err(backend_error)
+        })
+    }
+
+    /// Send a request that changes state in renterd, such as creating or
+    /// moving objects, ignoring the response body. Never retried: after a
+    /// lost response it may already have been applied.
+    fn mutate(&self, request: impl Fn() -> RequestBuilder) -> Result<()> {
+        self.exchange(false, request, |_| Ok(()))
+    }
+
+    /// Like `mutate`, parsing the JSON response
+    fn mutate_json<T: DeserializeOwned>(&self, request: impl Fn() -> RequestBuilder) -> Result<T> {
+        self.exchange(false, request, |response| {
+            response.json().map_err(backend_error)
+        })
+    }
+
+    /// Send a request for object content and read the content, retrying
+    /// both if the request fails transiently
+    fn download_body(&self, request: impl Fn() -> RequestBuilder) -> Result<Vec<u8>> {
+        self.exchange(true, request, |response| self.receive(response))
+    }
+
+    /// Run `attempt` until it succeeds, fails for good or runs out of
+    /// retries, sleeping with exponential backoff in between
+    fn retry<T>(&self, mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut retries = 0;
+        loop {
+            match attempt() {
+                Err(e) if retries < self.retry.max_retries && is_transient(&e) => {
+                    let delay = self.retry.delay(retries);
+                    tracing::debug!("renterd request failed ({}), retrying in {:?}", e, delay);
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(delay);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Read downloaded object content within the download limit
+    fn receive(&self, response: Response) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+        ThrottledReader::download(response, self.bandwidth.clone())
+            .read_to_end(&mut data)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::TimedOut => {
+                    SiaFuseError::TimedOut(format!("renterd download timed out: {}", e))
+                }
+                _ => SiaFuseError::Backend(format!("renterd download failed: {}", e)),
+            })?;
+        Ok(data)
+    }
+
+    fn send_once(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request
+            .basic_auth("", Some(&self.password))
+            .send()
+            .map_err(backend_error)?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let url = response.url().clone();
+        let body = response.text().unwrap_or_default();
+        tracing::debug!("renterd returned {} for {}: {}", status, url, body.trim());
+
+        match status {
+            // Callers check for missing objects by variant
+            StatusCode::NOT_FOUND => Err(SiaFuseError::NotFound),
+            status => Err(SiaFuseError::Http {
+                status: status.as_u16(),
+                message: body.trim().to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for KeyEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "percent" => Ok(KeyEncoding::Percent),
+            "plain" => Ok(KeyEncoding::Plain),
+            _ => Err(format!(
+                "unknown key encoding '{}' (expected percent or plain)",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for OutagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OutagePolicy::Fail),
+            "block" => Ok(OutagePolicy::Block),
+            _ => Err(format!(
+                "unknown outage policy '{}' (expected fail or block)",
+                s
+            )),
+        }
+    }
+}
+
+impl KeyEncoding {
+    /// Object key of the file at `path`
+    fn encode(self, path: &str) -> String {
+        match self {
+            KeyEncoding::Plain => path.to_string(),
+            KeyEncoding::Percent => {
+                let mut key = String::with_capacity(path.len());
+                for c in path.chars() {
+                    if matches!(c, '%' | '#' | '?') || c.is_control() {
+                        let mut buf = [0; 4];
+                        for byte in c.encode_utf8(&mut buf).bytes() {
+                            key.push_str(&format!("%{:02X}", byte));
+                        }
+                    } else {
+                        key.push(c);
+                    }
+                }
+                key
+            }
+        }
+    }
+
+    /// Path of the file stored at `key`. A key that doesn't decode to
+    /// valid UTF-8 is taken as it is.
+    fn decode(self, key: &str) -> String {
+        if self == KeyEncoding::Plain || !key.contains('%') {
+            return key.to_string();
+        }
+        let bytes = key.as_bytes();
+        let mut path = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                if let Some(byte) = bytes.get(i + 1..i + 3).and_then(hex_byte) {
+                    path.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            path.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8(path).unwrap_or_else(|_| key.to_string())
+    }
+}
+
+impl ConnectionPool {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new(PoolState::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free connection, behind every request already waiting
+    fn acquire(&self) -> PooledConnection<'_> {
+        let mut state = self.state.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || state.in_use >= self.size {
+            self.freed.wait(&mut state);
+        }
+        state.serving += 1;
+        state.in_use += 1;
+        // The next in line may fit too
+        self.freed.notify_all();
+        PooledConnection(self)
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().in_use -= 1;
+        self.0.freed.notify_all();
+    }
+}
+
+impl RetryPolicy {
+    /// Time to wait before retry `retry` (counting from 0): the base
+    /// doubled for each earlier retry, capped, with the upper half of it
+    /// randomized so clients failing together don't retry together
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << retry.min(16))
+            .min(MAX_RETRY_DELAY);
+        let jitter = RandomState::new().build_hasher().finish() % 1024;
+        delay / 2 + delay / 2 * jitter as u32 / 1024
+    }
+}
+
+/// A modification time as renterd reports it, in RFC 3339
+fn parse_time(time: Option<String>) -> Option<DateTime<Utc>> {
+    time.and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Health of objects renterd reports none for, such as empty ones
+fn full_health() -> f64 {
+    1.0
+}
+
+/// Whether a failure may go away by itself: a server error other than a
+/// full node, too many requests, a timeout or a failure to connect. Other
+/// client errors and failures partway through an exchange never do.
+fn is_transient(e: &SiaFuseError) -> bool {
+    match e {
+        SiaFuseError::Http { status, .. } => {
+            ((500..600).contains(status) && *status != 507) || *status == 429
+        }
+        SiaFuseError::TimedOut(_) | SiaFuseError::Unreachable(_) => true,
+        _ => false,
+    }
+}
+
+/// Byte written as two hex digits
+fn hex_byte(hex: &[u8]) -> Option<u8> {
+    let digit = |b: u8| (b as char).to_digit(16);
+    match hex {
+        [high, low] => Some((digit(*high)? * 16 + digit(*low)?) as u8),
+        _ => None,
+    }
+}
+
+fn backend_error(e: reqwest::Error) -> SiaFuseError {
+    if e.is_timeout() {
+        return SiaFuseError::TimedOut(format!("renterd request timed out: {}", e));
+    }
+    if e.is_connect() {
+        return SiaFuseError::Unreachable(format!("renterd request failed: {}", e));
+    }
+    SiaFuseError::Backend(format!("renterd request failed: {}", e))
+}
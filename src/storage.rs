@@ -1,11 +1,78 @@
-use chrono::{DateTime, Utc};
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use crate::error::{Result, SiaFuseError};
+use crate::throttle::Bandwidth;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use indexmap::IndexMap;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use content::Content;
+
+mod cache;
+#[cfg(feature = "renterd")]
+mod chunk_cache;
+mod content;
+#[cfg(feature = "renterd")]
+mod crypt;
+#[cfg(feature = "renterd")]
+mod dedup;
+mod mirror;
+mod passthrough;
+#[cfg(feature = "renterd")]
+mod readahead;
+#[cfg(feature = "renterd")]
+mod sia;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod union;
+
+pub use cache::{CacheLayer, DEFAULT_FLUSH_INTERVAL, DEFAULT_MAX_DIRTY};
+pub use mirror::LocalMirrorStorage;
+pub use passthrough::PassthroughStorage;
+#[cfg(feature = "renterd")]
+pub use sia::SiaStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{DanglingEntry, FsckReport, SqliteStorage, WrongNlink, DEFAULT_ORPHAN_GRACE};
+pub use union::UnionStorage;
+
 /// Unique identifier for inodes
 pub type Inode = u64;
 
+/// Inode of the root directory
+const ROOT_INODE: Inode = 1;
+
+/// Capacity reported when no quota is set (1 PiB)
+const DEFAULT_CAPACITY: u64 = 1 << 50;
+
+/// Inode count reported when no inode limit is set
+const DEFAULT_MAX_INODES: u64 = 1 << 32;
+
+/// Bytes moved at a time by the default `copy_range`
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// Space and inode usage, as reported by statfs, and cache activity
+#[derive(Debug, Clone, Copy)]
+pub struct StorageStats {
+    pub capacity_bytes: u64,
+    pub used_bytes: u64,
+    pub max_inodes: u64,
+    pub used_inodes: u64,
+    pub cache_hits: u64,        // Reads served from local content
+    pub cache_misses: u64,      // Reads that had to download content first
+    pub pending_uploads: u64,   // Files with changes not yet in the backend
+    pub dirty_bytes: u64,       // Size of the files with changes not yet in the backend
+    pub dedup_saved_bytes: u64, // Bytes not uploaded because the backend had them
+    pub backend_retries: u64,   // Backend requests retried after transient failures
+    pub read_cache_hits: u64,   // Chunk reads served from the read cache
+    pub read_cache_misses: u64, // Chunk reads that had to download the chunk
+    pub read_cache_bytes: u64,  // Bytes of chunks in the read cache
+    pub read_only: bool,        // The backend refuses changes, which fail with EROFS
+    // Earliest backend sync of any file, if there are files
+    pub oldest_synced: Option<DateTime<Utc>>,
+}
+
 /// File attributes
 #[derive(Debug, Clone)]
 pub struct FileAttr {
@@ -27,6 +94,11 @@ pub struct FileAttr {
 pub enum FileKind {
     File,
     Directory,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
 }
 
 impl FileKind {
@@ -34,27 +106,40 @@ impl FileKind {
         match self {
             FileKind::File => fuser::FileType::RegularFile,
             FileKind::Directory => fuser::FileType::Directory,
+            FileKind::Symlink => fuser::FileType::Symlink,
+            FileKind::Fifo => fuser::FileType::NamedPipe,
+            FileKind::CharDevice => fuser::FileType::CharDevice,
+            FileKind::BlockDevice => fuser::FileType::BlockDevice,
+            FileKind::Socket => fuser::FileType::Socket,
         }
     }
+
+    /// FIFOs, sockets and device nodes: the kernel handles their I/O, so
+    /// only the node itself is stored
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            FileKind::Fifo | FileKind::CharDevice | FileKind::BlockDevice | FileKind::Socket
+        )
+    }
 }
 
 impl FileAttr {
     pub fn to_fuser_attr(&self) -> fuser::FileAttr {
         let blksize = 4096;
-        let blocks = (self.size + blksize - 1) / blksize;
+        // st_blocks counts 512-byte units whatever the block size
+        let blocks = self.size.div_ceil(blksize) * (blksize / 512);
 
         fuser::FileAttr {
             ino: self.ino,
             size: self.size,
             blocks,
-            atime: std::time::UNIX_EPOCH
-                + std::time::Duration::from_secs(self.atime.timestamp() as u64),
-            mtime: std::time::UNIX_EPOCH
-                + std::time::Duration::from_secs(self.mtime.timestamp() as u64),
-            ctime: std::time::UNIX_EPOCH
-                + std::time::Duration::from_secs(self.ctime.timestamp() as u64),
-            crtime: std::time::UNIX_EPOCH
-                + std::time::Duration::from_secs(self.ctime.timestamp() as u64),
+            // Keep sub-second precision and times before the epoch, both
+            // of which can be set through utimensat
+            atime: self.atime.into(),
+            mtime: self.mtime.into(),
+            ctime: self.ctime.into(),
+            crtime: self.ctime.into(),
             kind: self.kind.to_fuser_type(),
             perm: self.perm,
             nlink: self.nlink,
@@ -67,42 +152,148 @@ impl FileAttr {
     }
 }
 
-/// Directory entry
+/// User and group owning a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Owner {
+    /// The user and group this process runs as, for nodes created on its
+    /// own behalf, such as objects found in a backend
+    pub fn current() -> Self {
+        unsafe {
+            Self {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+            }
+        }
+    }
+}
+
+/// How a backend that spreads content over hosts stores a file
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub key: String,                  // Object key in the backend
+    pub health: f64,                  // 1 with all redundancy in place
+    pub redundancy: Option<f64>,      // Shards stored per shard needed, if there is data
+    pub contract_set: Option<String>, // Contracts uploads go to, if known
+}
+
+/// Directory entry. It carries no attributes: those belong to the inode,
+/// so all hard links to a file report the same size and times.
 #[derive(Debug, Clone)]
 pub struct DirEntry {
     pub ino: Inode,
     pub name: String,
     pub kind: FileKind,
+    /// readdir offset of this entry. Assigned once when the entry is added
+    /// and never reused, so listings resume correctly across changes.
+    pub cookie: u64,
+}
+
+/// First cookie handed out in a directory; 1 and 2 belong to `.` and `..`
+const FIRST_COOKIE: u64 = 3;
+
+/// Directory entries keyed by name. Insertion order is cookie order, so
+/// iterating yields entries in the order readdir must return them; removals
+/// have to use `shift_remove` to keep it that way.
+type Children = IndexMap<String, DirEntry>;
+
+/// Type, mode and owner of a node about to be created
+struct NewNode {
+    kind: FileKind,
+    perm: u16,
+    rdev: u32, // Device number of a device node
+    owner: Owner,
+}
+
+impl NewNode {
+    fn new(kind: FileKind, perm: u16, owner: Owner) -> Self {
+        Self {
+            kind,
+            perm,
+            rdev: 0,
+            owner,
+        }
+    }
 }
 
 /// In-memory file data
 #[derive(Debug, Clone)]
 struct FileData {
     pub attr: FileAttr,
-    pub content: Vec<u8>,
-    pub children: Vec<DirEntry>, // Only for directories
+    pub content: Arc<RwLock<Content>>, // Locked on its own so copies don't hold up the tree
+    pub parent: Inode,                 // Directory holding one of this inode's names
+    pub children: Children,            // Only for directories
+    pub next_cookie: u64,              // Only for directories
+    pub last_synced: DateTime<Utc>,
 }
 
-/// In-memory storage backend
+impl FileData {
+    /// Append a directory entry with a fresh cookie
+    fn add_child(&mut self, ino: Inode, name: String, kind: FileKind) {
+        let entry = DirEntry {
+            ino,
+            name: name.clone(),
+            kind,
+            cookie: self.next_cookie,
+        };
+        self.children.insert(name, entry);
+        self.next_cookie += 1;
+        self.update_dir_attr();
+    }
+
+    /// Remove a directory entry, keeping the rest in cookie order
+    fn remove_child(&mut self, name: &str) -> Option<DirEntry> {
+        let entry = self.children.shift_remove(name);
+        self.update_dir_attr();
+        entry
+    }
+
+    /// Derive a directory's size and link count from its entries
+    fn update_dir_attr(&mut self) {
+        self.attr.size = dir_size(&self.children);
+        self.attr.nlink = dir_nlink(&self.children);
+    }
+}
+
+/// In-memory storage backend. Clones share the same underlying state.
+#[derive(Clone)]
 pub struct InMemoryStorage {
     files: Arc<RwLock<HashMap<Inode, FileData>>>,
     next_inode: Arc<RwLock<Inode>>,
+    held: Arc<Mutex<HashMap<Inode, u32>>>, // Holds on open files, by inode
+    quota: Option<u64>,                    // Maximum total content bytes, if limited
+    used: Arc<AtomicU64>,                  // Size of everything but directories
+    max_dir_entries: usize,                // Maximum entries per directory, 0 = unlimited
+    max_inodes: u64,                       // Maximum inodes including the root, 0 = unlimited
+    time_resolution: Option<TimeDelta>,    // Granularity of stored timestamps
+    noatime: bool,                         // Never update atime on reads
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InMemoryStorage {
     pub fn new() -> Self {
         let mut files = HashMap::new();
         let now = Utc::now();
+        let owner = Owner::current();
 
         // Create root directory (inode 1)
         let root_attr = FileAttr {
-            ino: 1,
-            size: 0,
+            ino: ROOT_INODE,
+            size: dir_size(&Children::new()),
             kind: FileKind::Directory,
             perm: 0o755,
             nlink: 2,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
+            uid: owner.uid,
+            gid: owner.gid,
             rdev: 0,
             flags: 0,
             atime: now,
@@ -111,17 +302,86 @@ impl InMemoryStorage {
         };
 
         files.insert(
-            1,
+            ROOT_INODE,
             FileData {
                 attr: root_attr,
-                content: Vec::new(),
-                children: Vec::new(),
+                content: Arc::new(RwLock::new(Content::new())),
+                parent: ROOT_INODE,
+                children: Children::new(),
+                next_cookie: FIRST_COOKIE,
+                last_synced: now,
             },
         );
 
         Self {
             files: Arc::new(RwLock::new(files)),
             next_inode: Arc::new(RwLock::new(2)),
+            held: Arc::new(Mutex::new(HashMap::new())),
+            quota: None,
+            used: Arc::new(AtomicU64::new(0)),
+            max_dir_entries: 0,
+            max_inodes: 0,
+            time_resolution: None,
+            noatime: false,
+        }
+    }
+
+    /// Create a storage that holds at most `quota` bytes of file content
+    pub fn with_quota(quota: u64) -> Self {
+        Self {
+            quota: Some(quota),
+            ..Self::new()
+        }
+    }
+
+    /// Refuse to add entries to a directory that already holds `limit`
+    /// of them. Zero means unlimited.
+    pub fn with_max_dir_entries(mut self, limit: usize) -> Self {
+        self.max_dir_entries = limit;
+        self
+    }
+
+    /// Refuse to create inodes once `limit` exist, regardless of how many
+    /// bytes they hold. Zero means unlimited.
+    pub fn with_max_inodes(mut self, limit: u64) -> Self {
+        self.max_inodes = limit;
+        self
+    }
+
+    /// Total number of inodes, including the root
+    pub fn inode_count(&self) -> u64 {
+        self.files.read().len() as u64
+    }
+
+    /// Round stored mtime/ctime down to multiples of `resolution`, e.g. 2s
+    /// to match FAT. Zero keeps full precision.
+    pub fn with_time_resolution(mut self, resolution: std::time::Duration) -> Self {
+        self.time_resolution = TimeDelta::from_std(resolution)
+            .ok()
+            .filter(|r| !r.is_zero());
+        self
+    }
+
+    /// Never update atime when files are read
+    pub fn with_noatime(mut self, noatime: bool) -> Self {
+        self.noatime = noatime;
+        self
+    }
+
+    /// Check that `parent` is a directory with room for one more entry
+    fn check_parent(&self, files: &HashMap<Inode, FileData>, parent: Inode) -> Result<()> {
+        match files.get(&parent) {
+            Some(dir) if dir.attr.kind != FileKind::Directory => Err(SiaFuseError::NotADirectory),
+            Some(dir) if self.max_dir_entries > 0 && dir.children.len() >= self.max_dir_entries => {
+                tracing::warn!(
+                    "directory {} is full ({} entries)",
+                    parent,
+                    self.max_dir_entries
+                );
+                Err(SiaFuseError::NoSpace)
+            }
+            Some(_) => Ok(()),
+            None => Err(SiaFuseError::NotFound),
         }
     }
 
@@ -133,216 +393,1249 @@ impl InMemoryStorage {
         ino
     }
 
-    /// Get file attributes
-    pub fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
-        self.files.read().get(&ino).map(|f| f.attr.clone())
+    /// A file's content, to be locked once the tree lock is released
+    fn content_of(&self, ino: Inode) -> Option<Arc<RwLock<Content>>> {
+        self.files.read().get(&ino).map(|f| f.content.clone())
     }
 
-    /// Set file attributes
-    pub fn set_attr(&self, ino: Inode, attr: FileAttr) -> bool {
-        if let Some(file) = self.files.write().get_mut(&ino) {
-            file.attr = attr;
-            true
-        } else {
-            false
-        }
+    /// Get the attributes of many inodes under a single lock acquisition
+    pub fn get_attrs(&self, inos: &[Inode]) -> Vec<Option<FileAttr>> {
+        let files = self.files.read();
+        inos.iter()
+            .map(|ino| files.get(ino).map(|f| f.attr.clone()))
+            .collect()
     }
 
-    /// Read file content
-    pub fn read(&self, ino: Inode, offset: usize, size: usize) -> Option<Vec<u8>> {
-        self.files.read().get(&ino).map(|f| {
-            let end = std::cmp::min(offset + size, f.content.len());
-            if offset >= f.content.len() {
-                Vec::new()
-            } else {
-                f.content[offset..end].to_vec()
-            }
-        })
-    }
+    /// Record a read in atime, relatime-style: only when atime is older
+    /// than the last modification or more than a day old, so repeated
+    /// reads don't take the write lock every time
+    fn update_atime(&self, ino: Inode) {
+        if self.noatime {
+            return;
+        }
 
-    /// Write file content
-    pub fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Option<usize> {
-        let mut files = self.files.write();
-        if let Some(file) = files.get_mut(&ino) {
-            let end = offset + data.len();
+        let stale = |attr: &FileAttr, now: DateTime<Utc>| {
+            attr.atime <= attr.mtime
+                || attr.atime <= attr.ctime
+                || now - attr.atime > TimeDelta::days(1)
+        };
 
-            // Extend if necessary
-            if end > file.content.len() {
-                file.content.resize(end, 0);
+        let now = self.now();
+        let needed = self
+            .files
+            .read()
+            .get(&ino)
+            .is_some_and(|f| stale(&f.attr, now));
+        if needed {
+            if let Some(file) = self.files.write().get_mut(&ino) {
+                if stale(&file.attr, now) {
+                    file.attr.atime = now;
+                }
             }
-
-            // Write data
-            file.content[offset..end].copy_from_slice(data);
-
-            // Update size and mtime
-            file.attr.size = file.content.len() as u64;
-            file.attr.mtime = Utc::now();
-
-            Some(data.len())
-        } else {
-            None
         }
     }
 
-    /// Create a new file
-    pub fn create_file(&self, parent: Inode, name: String, perm: u16) -> Option<FileAttr> {
+    /// Insert a new inode and link it into `parent`. Everything that can
+    /// fail is checked under the write lock before an inode is allocated,
+    /// so a failed create never leaves an unlinked inode behind.
+    fn create_node(
+        &self,
+        parent: Inode,
+        name: String,
+        node: NewNode,
+        content: Content,
+    ) -> Result<FileAttr> {
+        let NewNode {
+            kind,
+            perm,
+            rdev,
+            owner,
+        } = node;
+        let mut files = self.files.write();
+        self.check_parent(&files, parent)?;
+        if self.max_inodes > 0 && files.len() as u64 >= self.max_inodes {
+            tracing::warn!("inode limit of {} reached", self.max_inodes);
+            return Err(SiaFuseError::NoSpace);
+        }
+        let parent_file = files.get_mut(&parent).ok_or(SiaFuseError::NotFound)?;
+        if parent_file.children.contains_key(&name) {
+            return Err(SiaFuseError::AlreadyExists);
+        }
+
         let ino = self.allocate_inode();
-        let now = Utc::now();
+        let now = self.now();
+
+        parent_file.add_child(ino, name, kind);
+        parent_file.attr.mtime = now;
 
         let attr = FileAttr {
             ino,
-            size: 0,
-            kind: FileKind::File,
+            size: match kind {
+                FileKind::Directory => dir_size(&Children::new()),
+                _ => content.len(),
+            },
+            kind,
             perm,
-            nlink: 1,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            rdev: 0,
+            nlink: if kind == FileKind::Directory { 2 } else { 1 },
+            uid: owner.uid,
+            gid: owner.gid,
+            rdev,
             flags: 0,
             atime: now,
             mtime: now,
             ctime: now,
         };
 
-        let mut files = self.files.write();
-
-        // Add file
+        if kind != FileKind::Directory {
+            self.used.fetch_add(attr.size, Ordering::Relaxed);
+        }
         files.insert(
             ino,
             FileData {
                 attr: attr.clone(),
-                content: Vec::new(),
-                children: Vec::new(),
+                content: Arc::new(RwLock::new(content)),
+                parent,
+                children: Children::new(),
+                next_cookie: FIRST_COOKIE,
+                last_synced: now,
             },
         );
 
-        // Add to parent directory
-        if let Some(parent_file) = files.get_mut(&parent) {
-            parent_file.children.push(DirEntry {
-                ino,
-                name,
-                kind: FileKind::File,
-            });
-            parent_file.attr.mtime = now;
+        Ok(attr)
+    }
+
+    /// Set a file's size, keeping the total of content bytes up to date
+    fn resize(&self, file: &mut FileData, size: u64) {
+        if file.attr.kind != FileKind::Directory {
+            self.used.fetch_sub(file.attr.size, Ordering::Relaxed);
+            self.used.fetch_add(size, Ordering::Relaxed);
         }
+        file.attr.size = size;
+    }
 
-        Some(attr)
+    /// Remove an inode, taking its content out of the total
+    fn remove_inode(&self, files: &mut HashMap<Inode, FileData>, ino: Inode) {
+        if let Some(file) = files.remove(&ino) {
+            if file.attr.kind != FileKind::Directory {
+                self.used.fetch_sub(file.attr.size, Ordering::Relaxed);
+            }
+        }
     }
 
-    /// Create a new directory
-    pub fn create_dir(&self, parent: Inode, name: String, perm: u16) -> Option<FileAttr> {
-        let ino = self.allocate_inode();
-        let now = Utc::now();
+    /// Count the content bytes afresh after the tree was replaced whole
+    #[cfg(feature = "sqlite")]
+    fn recount_used(&self, files: &HashMap<Inode, FileData>) {
+        let used = files
+            .values()
+            .filter(|f| f.attr.kind != FileKind::Directory)
+            .map(|f| f.attr.size)
+            .sum();
+        self.used.store(used, Ordering::Relaxed);
+    }
 
-        let attr = FileAttr {
-            ino,
-            size: 0,
-            kind: FileKind::Directory,
-            perm,
-            nlink: 2,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            rdev: 0,
-            flags: 0,
-            atime: now,
-            mtime: now,
-            ctime: now,
+    /// Drop one link to a file, freeing it once no names refer to it
+    fn drop_link(&self, files: &mut HashMap<Inode, FileData>, ino: Inode) {
+        let Some(file) = files.get_mut(&ino) else {
+            return;
         };
+        file.attr.nlink = file.attr.nlink.saturating_sub(1);
+        if file.attr.nlink == 0 {
+            // An open file lives on without a name until it is released
+            if self.held.lock().contains_key(&ino) {
+                file.attr.ctime = self.now();
+            } else {
+                self.remove_inode(files, ino);
+            }
+            return;
+        }
+        file.attr.ctime = self.now();
+
+        // If the name that went away was the one `parent` pointed at, point
+        // it at a directory holding one of the remaining names
+        let parent = file.parent;
+        let linked = |dir: &FileData| dir.children.values().any(|e| e.ino == ino);
+        if !files.get(&parent).is_some_and(linked) {
+            if let Some(dir) = files.iter().find(|(_, d)| linked(d)).map(|(i, _)| *i) {
+                if let Some(file) = files.get_mut(&ino) {
+                    file.parent = dir;
+                }
+            }
+        }
+    }
 
+    fn move_entry(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+        replace: bool,
+    ) -> Result<()> {
+        check_entry_name(name)?;
+        check_entry_name(newname)?;
         let mut files = self.files.write();
 
-        // Add directory
-        files.insert(
-            ino,
-            FileData {
-                attr: attr.clone(),
-                content: Vec::new(),
-                children: Vec::new(),
+        let entry = files
+            .get(&parent)
+            .and_then(|p| p.children.get(name))
+            .cloned()
+            .ok_or(SiaFuseError::NotFound)?;
+        if entry.ino == ROOT_INODE {
+            return Err(SiaFuseError::Busy);
+        }
+
+        let target = match files.get(&newparent) {
+            Some(dir) if dir.attr.kind == FileKind::Directory => dir.children.get(newname).cloned(),
+            Some(_) => return Err(SiaFuseError::NotADirectory),
+            None => return Err(SiaFuseError::NotFound),
+        };
+
+        // Renaming an entry onto itself is a no-op
+        if target.as_ref().is_some_and(|t| t.ino == entry.ino) {
+            return Ok(());
+        }
+
+        // A directory can't be moved into its own subtree
+        if entry.kind == FileKind::Directory && is_ancestor(&files, entry.ino, newparent) {
+            return Err(SiaFuseError::InvalidArgument(
+                "cannot move a directory into itself".to_string(),
+            ));
+        }
+
+        match &target {
+            Some(_) if !replace => return Err(SiaFuseError::AlreadyExists),
+            Some(t) => match (entry.kind, t.kind) {
+                (kind, FileKind::Directory) if kind != FileKind::Directory => {
+                    return Err(SiaFuseError::IsADirectory)
+                }
+                (FileKind::Directory, kind) if kind != FileKind::Directory => {
+                    return Err(SiaFuseError::NotADirectory)
+                }
+                (FileKind::Directory, FileKind::Directory)
+                    if files.get(&t.ino).is_some_and(|d| !d.children.is_empty()) =>
+                {
+                    return Err(SiaFuseError::NotEmpty)
+                }
+                _ => {}
             },
-        );
+            // Moving into another directory adds an entry there
+            None if newparent != parent => self.check_parent(&files, newparent)?,
+            None => {}
+        }
 
-        // Add to parent directory
-        if let Some(parent_file) = files.get_mut(&parent) {
-            parent_file.children.push(DirEntry {
-                ino,
-                name,
-                kind: FileKind::Directory,
-            });
-            parent_file.attr.mtime = now;
-            parent_file.attr.nlink += 1;
+        let now = self.now();
+
+        if let Some(dir) = files.get_mut(&parent) {
+            dir.remove_child(name);
+        }
+        if let Some(dir) = files.get_mut(&newparent) {
+            if target.is_some() {
+                dir.remove_child(newname);
+            }
+            dir.add_child(entry.ino, newname.to_string(), entry.kind);
+        }
+        if let Some(t) = target {
+            if t.kind == FileKind::Directory {
+                files.remove(&t.ino);
+            } else {
+                self.drop_link(&mut files, t.ino);
+            }
+        }
+
+        for dir in [parent, newparent] {
+            if let Some(dir) = files.get_mut(&dir) {
+                dir.attr.mtime = now;
+                dir.attr.ctime = now;
+            }
+        }
+        if let Some(file) = files.get_mut(&entry.ino) {
+            file.parent = newparent;
+            file.attr.ctime = now;
         }
 
-        Some(attr)
+        Ok(())
     }
 
+    /// Make `ino_out` share the content of `ino_in` if `len` covers all of
+    /// it and `ino_out` is no longer, returning the bytes copied. None if
+    /// the copy has to move bytes instead.
+    fn reflink(&self, ino_in: Inode, ino_out: Inode, len: u64) -> Result<Option<usize>> {
+        let mut files = self.files.write();
+        let used = self.used.load(Ordering::Relaxed);
+
+        let source = files.get(&ino_in).ok_or(SiaFuseError::NotFound)?;
+        if source.attr.kind != FileKind::File {
+            return Ok(None);
+        }
+        let content = source.content.read().clone();
+        let file = files.get_mut(&ino_out).ok_or(SiaFuseError::NotFound)?;
+        if file.attr.kind != FileKind::File
+            || len < content.len()
+            || file.attr.size > content.len()
+            || content.len() == 0
+        {
+            return Ok(None);
+        }
+        if let Some(quota) = self.quota {
+            if content.len() - file.attr.size > quota.saturating_sub(used) {
+                return Ok(None);
+            }
+        }
+
+        let size = content.len();
+        *file.content.write() = content;
+        self.resize(file, size);
+        file.attr.mtime = self.now();
+        file.attr.ctime = file.attr.mtime;
+        file.last_synced = file.attr.mtime;
+        Ok(Some(size as usize))
+    }
+
+    /// Bytes of content held in memory, counting blocks shared between
+    /// files (see `copy_range`) once
+    pub fn allocated_bytes(&self) -> u64 {
+        let files = self.files.read();
+        let mut blocks = HashSet::new();
+        for file in files.values() {
+            blocks.extend(file.content.read().block_addrs());
+        }
+        (blocks.len() * content::BLOCK) as u64
+    }
+
+    /// Whether a file is open, so it outlives its last name
+    pub(crate) fn is_held(&self, ino: Inode) -> bool {
+        self.held.lock().contains_key(&ino)
+    }
+
+    /// Combined size of the files among `inos`, e.g. those not saved yet
+    pub(crate) fn total_size<'a>(&self, inos: impl IntoIterator<Item = &'a Inode>) -> u64 {
+        let files = self.files.read();
+        inos.into_iter()
+            .filter_map(|ino| files.get(ino))
+            .map(|file| file.attr.size)
+            .sum()
+    }
+
+    /// Write `data` at `offset`, or with `None` at the end of the file as
+    /// found with its content locked, returning the offset written at and
+    /// the bytes stored
+    fn write_at(&self, ino: Inode, offset: Option<usize>, data: &[u8]) -> Result<(usize, usize)> {
+        let mut files = self.files.write();
+        let used = self.used.load(Ordering::Relaxed);
+
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        let mut content = file.content.write_arc();
+        let offset = offset.unwrap_or(content.len() as usize);
+
+        // Only growth counts against the quota, so overwriting existing
+        // bytes always succeeds
+        let len = match self.quota {
+            Some(quota) => {
+                let max_end = content.len() + quota.saturating_sub(used);
+                let fits = max_end.saturating_sub(offset as u64);
+                std::cmp::min(data.len() as u64, fits) as usize
+            }
+            None => data.len(),
+        };
+        if len == 0 {
+            return if data.is_empty() {
+                Ok((offset, 0))
+            } else {
+                Err(SiaFuseError::NoSpace)
+            };
+        }
+
+        let data = &data[..len];
+
+        // Update size and mtime. There is no backend behind the
+        // in-memory store, so the data is in sync as soon as it lands.
+        self.resize(file, content.len().max(offset as u64 + len as u64));
+        file.attr.mtime = self.now();
+        file.last_synced = file.attr.mtime;
+
+        // Copy with only this file's content locked, so a large write
+        // doesn't hold up operations on other files
+        drop(files);
+        content.write(offset as u64, data);
+
+        Ok((offset, data.len()))
+    }
+}
+
+/// Backend holding the filesystem tree and file contents
+pub trait Storage: Send + Sync {
+    /// Current time at the configured timestamp resolution
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Current usage against the quota and inode limit. Without limits a
+    /// large nominal capacity is reported so free space still shrinks as
+    /// files grow.
+    fn stats(&self) -> StorageStats;
+
+    /// Get file attributes
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr>;
+
+    /// Set file attributes
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()>;
+
+    /// Update an inode's mtime (and ctime) to now without changing content
+    fn touch(&self, ino: Inode) -> Result<()>;
+
+    /// Get the time an inode was last synced with the backend
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>>;
+
+    /// Record that an inode has just been synced with the backend
+    fn mark_synced(&self, ino: Inode) -> Result<()>;
+
+    /// Commit an inode's pending changes to the backend, blocking until
+    /// they are durable, and record the sync
+    fn sync(&self, ino: Inode) -> Result<()> {
+        self.mark_synced(ino)
+    }
+
+    /// Read file content
+    fn read(&self, ino: Inode, offset: usize, size: usize) -> Option<Vec<u8>> {
+        let mut data = None;
+        self.read_with(ino, offset, size, &mut |bytes| data = Some(bytes.to_vec()));
+        data
+    }
+
+    /// Read file content by handing the stored bytes to `f` without copying
+    /// them, returning false if the inode doesn't exist. Any storage lock
+    /// is held while `f` runs, so keep it short.
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool;
+
+    /// Read the entire content of a file in one go
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>>;
+
+    /// Write file content, returning the number of bytes stored. This is
+    /// short of `data.len()` when the write runs into the quota, and fails
+    /// with `NoSpace` if not a single byte fits.
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize>;
+
+    /// Write `data` at the end of a file, returning the offset it landed
+    /// at and the bytes stored. No other write can change the file's size
+    /// between finding the end and writing, so concurrent appends never
+    /// overwrite each other.
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)>;
+
+    /// Truncate or zero-extend a file to `size` bytes
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()>;
+
+    /// Zero `len` bytes at `offset`, releasing the space they held where
+    /// possible. The file's size doesn't change.
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()>;
+
+    /// Copy up to `len` bytes from `ino_in` at `off_in` to `ino_out` at
+    /// `off_out`, returning the number copied. The copy stops short at the
+    /// end of the source or when the quota is reached. Backends that can
+    /// share content between files override this to avoid moving bytes.
+    fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: usize,
+        ino_out: Inode,
+        off_out: usize,
+        len: usize,
+    ) -> Result<usize> {
+        copy_bytes(self, ino_in, off_in, ino_out, off_out, len)
+    }
+
+    /// Create a new file owned by `owner`
+    fn create_file(&self, parent: Inode, name: String, perm: u16, owner: Owner)
+        -> Result<FileAttr>;
+
+    /// Create a new directory owned by `owner`
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr>;
+
+    /// Create a symbolic link pointing at `target`. The target is kept as
+    /// the link's content, so its size is the target's length.
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr>;
+
+    /// Create a FIFO, socket or device node. `rdev` is the device number
+    /// of a device node.
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr>;
+
+    /// Target of a symbolic link
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>>;
+
     /// List directory contents
-    pub fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
-        self.files.read().get(&ino).map(|f| f.children.clone())
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>>;
+
+    /// List every regular file with its `/`-separated path from the root,
+    /// sorted by path
+    fn file_paths(&self) -> Vec<(String, Inode)>;
+
+    /// Compute the BLAKE3 checksum of a file's content
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash>;
+
+    /// Bytes actually held for a file, as opposed to its logical size
+    fn allocated_size(&self, ino: Inode) -> Option<u64>;
+
+    /// Start of the first data at or after `offset` (SEEK_DATA), or None
+    /// past the last data. Storages that don't track holes report the
+    /// whole file as data.
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let attr = self.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        Ok((offset < attr.size).then_some(offset))
     }
 
-    /// Look up a file by name in a directory
-    pub fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
-        self.files
-            .read()
+    /// Start of the first hole at or after `offset` (SEEK_HOLE), where the
+    /// end of the file counts as a hole, or None past the end
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let attr = self.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        Ok((offset < attr.size).then_some(attr.size))
+    }
+
+    /// Look up a file by name in a directory. The attributes are the
+    /// inode's own, shared by every name linked to it.
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr>;
+
+    /// Remove a file or symlink. Fails with `IsADirectory` for directories.
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()>;
+
+    /// Add another name for an existing file (a hard link)
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr>;
+
+    /// Absolute path of an inode, built by walking parent pointers up to
+    /// the root. A hard-linked file yields one of its paths.
+    fn path_of(&self, ino: Inode) -> Option<PathBuf>;
+
+    /// Remove an empty directory. Fails with `NotADirectory` for anything
+    /// else.
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()>;
+
+    /// Move an entry, replacing whatever `newname` refers to. A file may
+    /// replace a file; a directory may only replace an empty directory.
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()>;
+
+    /// Move an entry, failing with `AlreadyExists` if `newname` exists
+    /// (`RENAME_NOREPLACE`)
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()>;
+
+    /// Atomically swap two directory entries (`RENAME_EXCHANGE`). Each name
+    /// stays in its directory but now refers to the other inode.
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()>;
+
+    /// Keep a file alive while it is open: once its last name is unlinked,
+    /// its content and attributes stay until every hold is released. Each
+    /// call needs a matching `release`.
+    fn hold(&self, _ino: Inode) {}
+
+    /// Drop a hold taken with `hold`, freeing the file if that was the
+    /// last one and no names refer to it any more
+    fn release(&self, _ino: Inode) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start loading the content following `offset` in the background, for
+    /// a reader going through the file sequentially. Backends holding
+    /// content locally have nothing to load.
+    fn readahead(&self, _ino: Inode, _offset: u64) {}
+
+    /// Size of the parts a file's content is uploaded in, if the backend
+    /// uploads in chunks
+    fn chunk_size(&self, _ino: Inode) -> Option<u64> {
+        None
+    }
+
+    /// Upload a file in parts of `chunk_size` bytes. Only possible while
+    /// the file is still empty.
+    fn set_chunk_size(&self, _ino: Inode, _chunk_size: u64) -> Result<()> {
+        Err(SiaFuseError::NotSupported(
+            "per-file chunk size".to_string(),
+        ))
+    }
+
+    /// Number of stored objects sharing a file's content, if the backend
+    /// deduplicates content and knows the file's
+    fn dedup_refs(&self, _ino: Inode) -> Option<u64> {
+        None
+    }
+
+    /// How the backend stores a file's content, if it reports that and
+    /// the file has been uploaded. May ask the backend, so it can be slow.
+    fn stored_object(&self, _ino: Inode) -> Result<Option<StoredObject>> {
+        Ok(None)
+    }
+
+    /// Limits on the backend's transfers, if it moves content over a
+    /// network. Changing them affects transfers already under way.
+    fn bandwidth(&self) -> Option<Arc<Bandwidth>> {
+        None
+    }
+
+    /// Fail with `ReadOnly` while the backend can't take changes, e.g.
+    /// renterd refusing uploads
+    fn check_writable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write out anything held only in memory, e.g. before unmounting
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn now(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        match self.time_resolution {
+            Some(resolution) => now.duration_trunc(resolution).unwrap_or(now),
+            None => now,
+        }
+    }
+
+    fn stats(&self) -> StorageStats {
+        let files = self.files.read();
+        StorageStats {
+            capacity_bytes: self.quota.unwrap_or(DEFAULT_CAPACITY),
+            used_bytes: self.used.load(Ordering::Relaxed),
+            max_inodes: match self.max_inodes {
+                0 => DEFAULT_MAX_INODES,
+                limit => limit,
+            },
+            used_inodes: files.len() as u64,
+            cache_hits: 0,
+            cache_misses: 0,
+            pending_uploads: 0,
+            dirty_bytes: 0,
+            dedup_saved_bytes: 0,
+            backend_retries: 0,
+            read_cache_hits: 0,
+            read_cache_misses: 0,
+            read_cache_bytes: 0,
+            read_only: false,
+            oldest_synced: files
+                .values()
+                .filter(|f| f.attr.kind == FileKind::File)
+                .map(|f| f.last_synced)
+                .min(),
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        self.files.read().get(&ino).map(|f| f.attr.clone())
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        let mut files = self.files.write();
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        self.resize(file, attr.size);
+        file.attr = attr;
+        Ok(())
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        let mut files = self.files.write();
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        let now = self.now();
+        file.attr.mtime = now;
+        file.attr.ctime = now;
+        Ok(())
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.files.read().get(&ino).map(|f| f.last_synced)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        let mut files = self.files.write();
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        file.last_synced = self.now();
+        Ok(())
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        let Some(content) = self.content_of(ino) else {
+            return false;
+        };
+        content.read().read_with(offset as u64, size, f);
+        self.update_atime(ino);
+        true
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        let content = self.content_of(ino)?.read().to_vec();
+        self.update_atime(ino);
+        Some(content)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        self.write_at(ino, Some(offset), data)
+            .map(|(_, written)| written)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        self.write_at(ino, None, data)
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        let mut files = self.files.write();
+        let used = self.used.load(Ordering::Relaxed);
+
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        if file.attr.kind == FileKind::Directory {
+            return Err(SiaFuseError::IsADirectory);
+        }
+        let mut content = file.content.write();
+        if content.len() == size {
+            return Ok(());
+        }
+
+        let growth = size.saturating_sub(content.len());
+        if let Some(quota) = self.quota {
+            if growth > quota.saturating_sub(used) {
+                return Err(SiaFuseError::NoSpace);
+            }
+        }
+
+        content.truncate(size);
+        drop(content);
+        self.resize(file, size);
+        file.attr.mtime = self.now();
+        file.attr.ctime = file.attr.mtime;
+        file.last_synced = file.attr.mtime;
+        Ok(())
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        let mut files = self.files.write();
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        if file.attr.kind == FileKind::Directory {
+            return Err(SiaFuseError::IsADirectory);
+        }
+
+        file.content.write().punch_hole(offset, len);
+        file.attr.mtime = self.now();
+        file.attr.ctime = file.attr.mtime;
+        file.last_synced = file.attr.mtime;
+        Ok(())
+    }
+
+    // A whole file copied over the start of one no longer than it shares
+    // the source's blocks (a reflink) instead of duplicating them
+    fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: usize,
+        ino_out: Inode,
+        off_out: usize,
+        len: usize,
+    ) -> Result<usize> {
+        if off_in == 0 && off_out == 0 && ino_in != ino_out {
+            if let Some(copied) = self.reflink(ino_in, ino_out, len as u64)? {
+                return Ok(copied);
+            }
+        }
+        copy_bytes(self, ino_in, off_in, ino_out, off_out, len)
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let node = NewNode::new(FileKind::File, perm, owner);
+        self.create_node(parent, name, node, Content::new())
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        let node = NewNode::new(FileKind::Directory, perm, owner);
+        self.create_node(parent, name, node, Content::new())
+    }
+
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let node = NewNode::new(FileKind::Symlink, 0o777, owner);
+        let content = Content::from_vec(target.as_bytes().to_vec());
+        self.create_node(parent, name, node, content)
+    }
+
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        if !kind.is_special() {
+            return Err(SiaFuseError::InvalidArgument(format!(
+                "{:?} is not a special file type",
+                kind
+            )));
+        }
+        let node = NewNode {
+            rdev,
+            ..NewNode::new(kind, perm, owner)
+        };
+        self.create_node(parent, name, node, Content::new())
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        match self.files.read().get(&ino) {
+            Some(f) if f.attr.kind == FileKind::Symlink => Ok(f.content.read().to_vec()),
+            Some(_) => Err(SiaFuseError::InvalidArgument(format!(
+                "inode {} is not a symlink",
+                ino
+            ))),
+            None => Err(SiaFuseError::NotFound),
+        }
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        let files = self.files.read();
+        let dir = files.get(&ino)?;
+
+        // Report each child's kind from its own attributes, the same source
+        // lookup and getattr use, so the three can't disagree
+        let entries = dir
+            .children
+            .values()
+            .map(|entry| {
+                let kind = files.get(&entry.ino).map_or(entry.kind, |f| f.attr.kind);
+                debug_assert_eq!(
+                    kind, entry.kind,
+                    "directory entry '{}' disagrees with inode {}",
+                    entry.name, entry.ino
+                );
+                DirEntry {
+                    kind,
+                    ..entry.clone()
+                }
+            })
+            .collect();
+        Some(entries)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        let files = self.files.read();
+        let mut paths = Vec::new();
+        let mut pending = vec![(String::new(), 1)];
+
+        while let Some((prefix, dir)) = pending.pop() {
+            let Some(dir) = files.get(&dir) else {
+                continue;
+            };
+            for entry in dir.children.values() {
+                let path = if prefix.is_empty() {
+                    entry.name.clone()
+                } else {
+                    format!("{}/{}", prefix, entry.name)
+                };
+                match entry.kind {
+                    FileKind::File => paths.push((path, entry.ino)),
+                    FileKind::Directory => pending.push((path, entry.ino)),
+                    _ => {}
+                }
+            }
+        }
+
+        paths.sort();
+        paths
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        Some(self.content_of(ino)?.read().hash())
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        Some(self.content_of(ino)?.read().allocated())
+    }
+
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let content = self.content_of(ino).ok_or(SiaFuseError::NotFound)?;
+        let data = content.read().seek_data(offset);
+        Ok(data)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let content = self.content_of(ino).ok_or(SiaFuseError::NotFound)?;
+        let hole = content.read().seek_hole(offset);
+        Ok(hole)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        let files = self.files.read();
+        files
             .get(&parent)
-            .and_then(|f| f.children.iter().find(|e| e.name == name))
-            .and_then(|entry| self.files.read().get(&entry.ino).map(|f| f.attr.clone()))
+            .and_then(|f| f.children.get(name))
+            .and_then(|entry| files.get(&entry.ino).map(|f| f.attr.clone()))
     }
 
-    /// Remove a file
-    pub fn unlink(&self, parent: Inode, name: &str) -> bool {
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        check_entry_name(name)?;
         let mut files = self.files.write();
 
         // Find the file in parent's children
-        if let Some(parent_file) = files.get_mut(&parent) {
-            if let Some(pos) = parent_file
+        let parent_file = files.get_mut(&parent).ok_or(SiaFuseError::NotFound)?;
+        let ino = match parent_file.children.get(name) {
+            Some(entry) if entry.kind == FileKind::Directory => {
+                return Err(SiaFuseError::IsADirectory)
+            }
+            Some(entry) => entry.ino,
+            None => return Err(SiaFuseError::NotFound),
+        };
+        parent_file.remove_child(name);
+        parent_file.attr.mtime = self.now();
+
+        self.drop_link(&mut files, ino);
+        Ok(())
+    }
+
+    fn hold(&self, ino: Inode) {
+        *self.held.lock().entry(ino).or_insert(0) += 1;
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        let mut files = self.files.write();
+        let mut held = self.held.lock();
+        match held.get_mut(&ino) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return Ok(());
+            }
+            Some(_) => {
+                held.remove(&ino);
+            }
+            None => return Err(SiaFuseError::NotFound),
+        }
+
+        if files.get(&ino).is_some_and(|file| file.attr.nlink == 0) {
+            self.remove_inode(&mut files, ino);
+        }
+        Ok(())
+    }
+
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr> {
+        check_entry_name(newname)?;
+        let mut files = self.files.write();
+
+        let kind = match files.get(&ino) {
+            Some(f) if f.attr.kind == FileKind::Directory => {
+                return Err(SiaFuseError::NotPermitted)
+            }
+            Some(f) => f.attr.kind,
+            None => return Err(SiaFuseError::NotFound),
+        };
+        self.check_parent(&files, newparent)?;
+
+        let now = self.now();
+        let dir = files.get_mut(&newparent).ok_or(SiaFuseError::NotFound)?;
+        if dir.children.contains_key(newname) {
+            return Err(SiaFuseError::AlreadyExists);
+        }
+        dir.add_child(ino, newname.to_string(), kind);
+        dir.attr.mtime = now;
+        dir.attr.ctime = now;
+
+        let file = files.get_mut(&ino).ok_or(SiaFuseError::NotFound)?;
+        file.attr.nlink += 1;
+        file.attr.ctime = now;
+        Ok(file.attr.clone())
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        let files = self.files.read();
+        let mut names = Vec::new();
+        let mut current = ino;
+
+        while current != ROOT_INODE {
+            let parent = files.get(&current)?.parent;
+            let entry = files
+                .get(&parent)?
                 .children
-                .iter()
-                .position(|e| e.name == name && e.kind == FileKind::File)
-            {
-                let ino = parent_file.children[pos].ino;
-                parent_file.children.remove(pos);
-                parent_file.attr.mtime = Utc::now();
-
-                // Remove the file
-                files.remove(&ino);
-                return true;
+                .values()
+                .find(|e| e.ino == current)?;
+            names.push(entry.name.as_str());
+            current = parent;
+
+            // A cycle would mean the tree is corrupt
+            if names.len() > files.len() {
+                tracing::warn!("parent pointers of inode {} form a cycle", ino);
+                return None;
             }
         }
 
-        false
+        let mut path = PathBuf::from("/");
+        path.extend(names.iter().rev());
+        Some(path)
     }
 
-    /// Remove a directory
-    pub fn rmdir(&self, parent: Inode, name: &str) -> bool {
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        check_entry_name(name)?;
         let mut files = self.files.write();
 
         // Find the directory in parent's children
+        let ino = match files.get(&parent).and_then(|p| p.children.get(name)) {
+            Some(entry) if entry.kind == FileKind::Directory => entry.ino,
+            Some(_) => return Err(SiaFuseError::NotADirectory),
+            None => return Err(SiaFuseError::NotFound),
+        };
+
+        if ino == ROOT_INODE {
+            return Err(SiaFuseError::Busy);
+        }
+
+        // Check if directory is empty
+        if let Some(dir) = files.get(&ino) {
+            if !dir.children.is_empty() {
+                return Err(SiaFuseError::NotEmpty);
+            }
+        }
+
         if let Some(parent_file) = files.get_mut(&parent) {
-            if let Some(pos) = parent_file
-                .children
-                .iter()
-                .position(|e| e.name == name && e.kind == FileKind::Directory)
-            {
-                let ino = parent_file.children[pos].ino;
-
-                // Check if directory is empty
-                if let Some(dir) = files.get(&ino) {
-                    if !dir.children.is_empty() {
-                        return false; // Directory not empty
-                    }
-                }
+            // A directory's nlink is 2 plus one per subdirectory. It is
+            // recomputed rather than decremented; report any drift from
+            // the running count.
+            let decremented = parent_file.attr.nlink.saturating_sub(1);
+            parent_file.remove_child(name);
+            parent_file.attr.mtime = self.now();
+            if decremented != parent_file.attr.nlink {
+                tracing::warn!(
+                    "nlink drift on directory {}: had {}, expected {}",
+                    parent,
+                    decremented,
+                    parent_file.attr.nlink
+                );
+            }
+        }
 
-                parent_file.children.remove(pos);
-                parent_file.attr.mtime = Utc::now();
-                parent_file.attr.nlink -= 1;
+        // Remove the directory
+        files.remove(&ino);
+        Ok(())
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        self.move_entry(parent, name, newparent, newname, true)
+    }
 
-                // Remove the directory
-                files.remove(&ino);
-                return true;
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        self.move_entry(parent, name, newparent, newname, false)
+    }
+
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        check_entry_name(name)?;
+        check_entry_name(newname)?;
+        let mut files = self.files.write();
+
+        let find = |files: &HashMap<Inode, FileData>, dir: Inode, name: &str| {
+            let dir = files.get(&dir).ok_or(SiaFuseError::NotFound)?;
+            if dir.attr.kind != FileKind::Directory {
+                return Err(SiaFuseError::NotADirectory);
+            }
+            dir.children
+                .get(name)
+                .cloned()
+                .ok_or(SiaFuseError::NotFound)
+        };
+        let entry = find(&files, parent, name)?;
+        let newentry = find(&files, newparent, newname)?;
+
+        if entry.ino == ROOT_INODE || newentry.ino == ROOT_INODE {
+            return Err(SiaFuseError::Busy);
+        }
+        if entry.ino == newentry.ino {
+            return Ok(());
+        }
+
+        // A directory can't be swapped into its own subtree
+        if is_ancestor(&files, entry.ino, newparent) || is_ancestor(&files, newentry.ino, parent) {
+            return Err(SiaFuseError::InvalidArgument(
+                "cannot move a directory into itself".to_string(),
+            ));
+        }
+
+        let now = self.now();
+        if let Some(e) = files
+            .get_mut(&parent)
+            .and_then(|d| d.children.get_mut(name))
+        {
+            e.ino = newentry.ino;
+            e.kind = newentry.kind;
+        }
+        if let Some(e) = files
+            .get_mut(&newparent)
+            .and_then(|d| d.children.get_mut(newname))
+        {
+            e.ino = entry.ino;
+            e.kind = entry.kind;
+        }
+        if let Some(file) = files.get_mut(&entry.ino) {
+            file.parent = newparent;
+        }
+        if let Some(file) = files.get_mut(&newentry.ino) {
+            file.parent = parent;
+        }
+
+        // Swapping a file with a directory across parents moves a
+        // subdirectory from one parent to the other, so recompute both
+        // link counts from their entries
+        for dir in [parent, newparent] {
+            if let Some(dir) = files.get_mut(&dir) {
+                dir.update_dir_attr();
+                dir.attr.mtime = now;
+                dir.attr.ctime = now;
+            }
+        }
+        for ino in [entry.ino, newentry.ino] {
+            if let Some(file) = files.get_mut(&ino) {
+                file.attr.ctime = now;
             }
         }
 
-        false
+        Ok(())
+    }
+}
+
+/// Reject `.` and `..` as the target of an unlink, rmdir or rename
+fn check_entry_name(name: &str) -> Result<()> {
+    if name == "." || name == ".." {
+        return Err(SiaFuseError::InvalidArgument(format!(
+            "'{}' cannot be removed or renamed",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `dir` is `ino` itself or lies somewhere beneath it
+fn is_ancestor(files: &HashMap<Inode, FileData>, ino: Inode, dir: Inode) -> bool {
+    if ino == dir {
+        return true;
     }
+    files.get(&ino).is_some_and(|f| {
+        f.children
+            .values()
+            .filter(|e| e.kind == FileKind::Directory)
+            .any(|e| is_ancestor(files, e.ino, dir))
+    })
+}
+
+/// Size of a directory with the given entries, ext4-style: whole blocks
+/// holding 8 bytes plus the name, padded to 4, per entry including `.`
+/// and `..`
+fn dir_size(children: &Children) -> u64 {
+    let entry_len = |name_len: usize| 8 + (name_len as u64).next_multiple_of(4);
+    let bytes = entry_len(".".len())
+        + entry_len("..".len())
+        + children
+            .keys()
+            .map(|name| entry_len(name.len()))
+            .sum::<u64>();
+    bytes.next_multiple_of(content::BLOCK as u64)
+}
+
+/// Split a `/`-separated path into the path of its directory and its
+/// last name
+pub(crate) fn split_path(path: &str) -> Result<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = trimmed.rsplit_once('/').unwrap_or(("", trimmed));
+    if name.is_empty() {
+        return Err(SiaFuseError::InvalidArgument(format!(
+            "empty path '{}'",
+            path
+        )));
+    }
+    Ok((dir, name))
+}
+
+/// Makes the directory `name` in a parent directory
+pub(crate) type MakeDir<'a> = dyn Fn(Inode, &str) -> Result<FileAttr> + 'a;
+
+/// Find the directory at the `/`-separated `path` below `parent`. Missing
+/// directories on the way are made with `mkdir` if one is given, like S3
+/// key prefixes, and are NotFound otherwise.
+pub(crate) fn walk_dirs<S: Storage + ?Sized>(
+    storage: &S,
+    parent: Inode,
+    path: &str,
+    mkdir: Option<&MakeDir>,
+) -> Result<Inode> {
+    let mut dir = parent;
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        dir = match (storage.lookup(dir, name), mkdir) {
+            (Some(attr), _) if attr.kind == FileKind::Directory => attr.ino,
+            (Some(_), _) => return Err(SiaFuseError::NotADirectory),
+            (None, Some(mkdir)) => mkdir(dir, name)?.ino,
+            (None, None) => return Err(SiaFuseError::NotFound),
+        };
+    }
+    Ok(dir)
+}
+
+/// Copy a range by reading it and writing it back, as `copy_range` does
+/// unless a backend can share content
+fn copy_bytes<S: Storage + ?Sized>(
+    storage: &S,
+    ino_in: Inode,
+    off_in: usize,
+    ino_out: Inode,
+    off_out: usize,
+    len: usize,
+) -> Result<usize> {
+    let mut copied = 0;
+    while copied < len {
+        let size = std::cmp::min(len - copied, COPY_CHUNK);
+        let data = storage
+            .read(ino_in, off_in + copied, size)
+            .ok_or(SiaFuseError::NotFound)?;
+        if data.is_empty() {
+            break;
+        }
+        let written = match storage.write(ino_out, off_out + copied, &data) {
+            Ok(written) => written,
+            Err(_) if copied > 0 => break,
+            Err(e) => return Err(e),
+        };
+        copied += written;
+        if written < data.len() {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+/// Link count of a directory with the given entries
+fn dir_nlink(children: &Children) -> u32 {
+    let subdirs = children
+        .values()
+        .filter(|e| e.kind == FileKind::Directory)
+        .count();
+    2 + subdirs as u32
 }
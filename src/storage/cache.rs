@@ -0,0 +1,809 @@
+use super::{DirEntry, FileAttr, FileKind, Inode, Owner, Storage, StorageStats, StoredObject};
+use crate::error::{Result, SiaFuseError};
+use crate::throttle::{self, Bandwidth};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a file must go unwritten before its buffered writes are passed
+/// on and it is uploaded, so a file being written isn't uploaded over and
+/// over
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Dirty files allowed before writes to further files block
+pub const DEFAULT_MAX_DIRTY: usize = 64;
+
+/// Bytes buffered for one file before they are passed to the inner storage
+/// without waiting for the file to go idle
+const MAX_BUFFERED: usize = 4 * 1024 * 1024;
+
+/// Write-back layer over another storage. Writes are buffered per file,
+/// with adjacent and overlapping writes merged, and return at once; a
+/// background thread passes each dirty file's writes to the inner storage
+/// and syncs it once it has been idle for the flush interval. `sync`
+/// (flush/fsync/release) does the same straight away and waits for the
+/// upload. Once `max_dirty` files are waiting, writes to other files block
+/// until uploads catch up. With `max_dirty_bytes` set, a write that would
+/// take the dirty bytes (buffered here or awaiting upload below) past it
+/// blocks until they drop to three quarters of it.
+///
+/// Buffered writes are held in memory, not in a cache directory on disk,
+/// so they are lost if the process dies before passing them on. Growth
+/// they will cause is reserved against the inner storage's capacity when
+/// they are buffered, so running out of room fails the write itself.
+pub struct CacheLayer {
+    inner: Arc<dyn Storage>,
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<WriteBack>,
+    changed: Condvar,
+    buffers: Mutex<HashMap<Inode, Arc<Mutex<WriteBuffer>>>>,
+    buffered: AtomicUsize, // Bytes held in all buffers
+    reserved: AtomicU64,   // Capacity set aside for buffered file growth
+}
+
+struct WriteBack {
+    queue: IndexMap<Inode, Instant>, // Dirty files by time of last write, oldest first
+    in_flight: Option<Inode>,
+    max_dirty: usize,
+    max_dirty_bytes: Option<u64>,
+    blocked: usize, // Writers waiting for dirty bytes to drain
+    flush_interval: Duration,
+    shutdown: bool,
+}
+
+/// Writes to one file not yet passed to the inner storage, as disjoint,
+/// non-adjacent ranges keyed by offset
+#[derive(Default)]
+struct WriteBuffer {
+    ranges: BTreeMap<usize, Vec<u8>>,
+    bytes: usize,
+    modified: Option<DateTime<Utc>>,
+    reserved: u64, // This file's share of `Shared::reserved`
+    retired: bool, // Dropped from `Shared::buffers`; writers must look again
+}
+
+impl WriteBuffer {
+    /// End of the last buffered range
+    fn end(&self) -> usize {
+        self.ranges
+            .last_key_value()
+            .map_or(0, |(offset, data)| offset + data.len())
+    }
+
+    /// Add a write, merging it with every range it overlaps or touches.
+    /// Returns how many bytes the buffer grew by.
+    fn insert(&mut self, offset: usize, data: &[u8]) -> usize {
+        let mut start = offset;
+        let mut end = offset + data.len();
+        let merged: Vec<usize> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(&at, range)| at + range.len() >= offset)
+            .map(|(&at, _)| at)
+            .collect();
+
+        let before = self.bytes;
+        let mut old = Vec::with_capacity(merged.len());
+        for at in merged {
+            let range = self.ranges.remove(&at).unwrap_or_default();
+            self.bytes -= range.len();
+            start = start.min(at);
+            end = end.max(at + range.len());
+            old.push((at, range));
+        }
+
+        let mut combined = vec![0; end - start];
+        for (at, range) in old {
+            combined[at - start..at - start + range.len()].copy_from_slice(&range);
+        }
+        combined[offset - start..offset - start + data.len()].copy_from_slice(data);
+
+        self.bytes += combined.len();
+        self.ranges.insert(start, combined);
+        self.bytes - before
+    }
+}
+
+impl WriteBack {
+    fn dirty(&self) -> usize {
+        self.queue.len() + self.in_flight.map_or(0, |_| 1)
+    }
+}
+
+impl CacheLayer {
+    /// Wrap `inner`, starting the upload thread
+    pub fn new(inner: Box<dyn Storage>) -> Result<Self> {
+        let inner: Arc<dyn Storage> = inner.into();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(WriteBack {
+                queue: IndexMap::new(),
+                in_flight: None,
+                max_dirty: DEFAULT_MAX_DIRTY,
+                max_dirty_bytes: None,
+                blocked: 0,
+                flush_interval: DEFAULT_FLUSH_INTERVAL,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+            buffers: Mutex::new(HashMap::new()),
+            buffered: AtomicUsize::new(0),
+            reserved: AtomicU64::new(0),
+        });
+
+        let worker = {
+            let inner = inner.clone();
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("sia-fuse-upload".to_string())
+                .spawn(move || {
+                    throttle::set_background();
+                    shared.run(&*inner)
+                })?
+        };
+
+        Ok(Self {
+            inner,
+            shared,
+            worker: Some(worker),
+        })
+    }
+
+    /// Block writes to clean files while `max_dirty` files await upload.
+    /// Zero is treated as one.
+    pub fn with_max_dirty(self, max_dirty: usize) -> Self {
+        self.shared.state.lock().max_dirty = max_dirty.max(1);
+        self
+    }
+
+    /// Block writes while `bytes` or more are dirty, until the dirty bytes
+    /// drop to three quarters of it. Zero is treated as one.
+    pub fn with_max_dirty_bytes(self, bytes: u64) -> Self {
+        self.shared.state.lock().max_dirty_bytes = Some(bytes.max(1));
+        self
+    }
+
+    /// Pass a file's writes on and upload it once it has gone unwritten
+    /// for `interval`
+    pub fn with_flush_interval(self, interval: Duration) -> Self {
+        self.shared.state.lock().flush_interval = interval;
+        self
+    }
+
+    /// Buffer a write to a regular file. Growth of the file is reserved
+    /// first; if only part of it fits, only the part that fits is
+    /// buffered, and with no room at all the write fails with ENOSPC.
+    fn buffer_write(&self, attr: &FileAttr, offset: usize, data: &[u8]) -> Result<usize> {
+        let now = self.inner.now();
+        loop {
+            let buffer = self
+                .shared
+                .buffers
+                .lock()
+                .entry(attr.ino)
+                .or_default()
+                .clone();
+            let mut buffer = buffer.lock();
+            if buffer.retired {
+                continue;
+            }
+
+            let size = (attr.size as usize).max(buffer.end());
+            let growth = (offset + data.len()).saturating_sub(size) as u64;
+            let granted = self.shared.reserve(&*self.inner, growth);
+            let len = if granted < growth {
+                (size + granted as usize).saturating_sub(offset)
+            } else {
+                data.len()
+            };
+            if len == 0 {
+                self.shared.release(granted);
+                return Err(SiaFuseError::NoSpace);
+            }
+            buffer.reserved += granted;
+
+            let grown = buffer.insert(offset, &data[..len]);
+            buffer.modified = Some(now);
+            self.shared.buffered.fetch_add(grown, Ordering::Relaxed);
+            if buffer.bytes >= MAX_BUFFERED {
+                // What doesn't land stays buffered and fails the next sync
+                if let Err(e) = self.shared.drain(&*self.inner, attr.ino, &mut buffer) {
+                    tracing::warn!("failed to write buffered data of inode {}: {}", attr.ino, e);
+                }
+            }
+            return Ok(len);
+        }
+    }
+
+    /// Bytes written but not uploaded yet, here or in the inner storage
+    fn dirty_bytes(&self) -> u64 {
+        let buffered = self.shared.buffered.load(Ordering::Relaxed) as u64;
+        self.inner.stats().dirty_bytes.max(buffered)
+    }
+
+    /// Before writing `len` bytes, wait while they would take the dirty
+    /// bytes past `max_dirty_bytes`, hurrying uploads along meanwhile
+    fn wait_for_dirty_room(&self, len: usize) {
+        let Some(max) = self.shared.state.lock().max_dirty_bytes else {
+            return;
+        };
+        let dirty = self.dirty_bytes();
+        if dirty + len as u64 <= max {
+            return;
+        }
+        tracing::debug!("{} bytes awaiting upload, blocking write", dirty);
+
+        let low = max / 4 * 3;
+        let mut state = self.shared.state.lock();
+        state.blocked += 1;
+        self.shared.changed.notify_all();
+        // Every finished upload signals `changed`. Nothing queued means
+        // nothing will drain, e.g. after failed uploads.
+        while !state.queue.is_empty() || state.in_flight.is_some() {
+            self.shared.changed.wait(&mut state);
+            if MutexGuard::unlocked(&mut state, || self.dirty_bytes()) <= low {
+                break;
+            }
+        }
+        state.blocked -= 1;
+    }
+
+    /// `attr` with the size and times its file will have once its buffered
+    /// writes are passed on
+    fn pending_attr(&self, mut attr: FileAttr) -> FileAttr {
+        let Some(buffer) = self.shared.buffers.lock().get(&attr.ino).cloned() else {
+            return attr;
+        };
+        let buffer = buffer.lock();
+        if let Some(modified) = buffer.modified {
+            attr.size = attr.size.max(buffer.end() as u64);
+            attr.mtime = attr.mtime.max(modified);
+            attr.ctime = attr.ctime.max(modified);
+        }
+        attr
+    }
+
+    /// Forget a deleted file's buffered writes
+    fn discard(&self, ino: Inode) {
+        let Some(buffer) = self.shared.buffers.lock().remove(&ino) else {
+            return;
+        };
+        let mut buffer = buffer.lock();
+        self.shared
+            .buffered
+            .fetch_sub(buffer.bytes, Ordering::Relaxed);
+        self.shared.release(buffer.reserved);
+        *buffer = WriteBuffer {
+            retired: true,
+            ..WriteBuffer::default()
+        };
+        self.shared.changed.notify_all();
+    }
+
+    /// Queue a file for upload after a write, first waiting for room if
+    /// it isn't queued already
+    fn mark_dirty(&self, ino: Inode) {
+        let mut state = self.shared.state.lock();
+        while !state.queue.contains_key(&ino)
+            && state.in_flight != Some(ino)
+            && state.dirty() >= state.max_dirty
+        {
+            tracing::debug!("{} files awaiting upload, blocking write", state.dirty());
+            self.shared.changed.wait(&mut state);
+        }
+
+        // Move to the back: the idle time restarts with every write
+        state.queue.shift_remove(&ino);
+        state.queue.insert(ino, Instant::now());
+        self.shared.changed.notify_all();
+    }
+
+    /// Take a file out of the queue, waiting out an upload in progress.
+    /// Returns when it was last written if it was queued.
+    fn dequeue(&self, ino: Inode) -> Option<Instant> {
+        let mut state = self.shared.state.lock();
+        let written = state.queue.shift_remove(&ino);
+        while state.in_flight == Some(ino) {
+            self.shared.changed.wait(&mut state);
+        }
+        self.shared.changed.notify_all();
+        written
+    }
+
+    /// Run `op` with the files `inos` out of the upload queue, so it can't
+    /// interleave with their uploads, then queue those still there again
+    /// and forget the buffered writes of those gone
+    fn paused<T>(&self, inos: &[Inode], op: impl FnOnce() -> Result<T>) -> Result<T> {
+        let queued: Vec<(Inode, Option<Instant>)> =
+            inos.iter().map(|&ino| (ino, self.dequeue(ino))).collect();
+        let result = op();
+
+        for (ino, written) in queued {
+            if self.inner.get_attr(ino).is_none() {
+                self.discard(ino);
+                continue;
+            }
+            if let Some(written) = written {
+                let mut state = self.shared.state.lock();
+                state.queue.entry(ino).or_insert(written);
+                self.shared.changed.notify_all();
+            }
+        }
+        result
+    }
+
+    /// Inodes of the entries named, for `paused`
+    fn entries(&self, names: &[(Inode, &str)]) -> Vec<Inode> {
+        names
+            .iter()
+            .filter_map(|&(parent, name)| self.inner.lookup(parent, name))
+            .map(|attr| attr.ino)
+            .collect()
+    }
+}
+
+impl Shared {
+    /// Set aside up to `bytes` of the inner storage's free capacity for
+    /// buffered growth, returning how much was granted
+    fn reserve(&self, inner: &dyn Storage, bytes: u64) -> u64 {
+        if bytes == 0 {
+            return 0;
+        }
+        let stats = inner.stats();
+        let mut reserved = self.reserved.load(Ordering::Relaxed);
+        loop {
+            let free = stats
+                .capacity_bytes
+                .saturating_sub(stats.used_bytes.saturating_add(reserved));
+            let granted = bytes.min(free);
+            match self.reserved.compare_exchange(
+                reserved,
+                reserved + granted,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return granted,
+                Err(current) => reserved = current,
+            }
+        }
+    }
+
+    /// Give back capacity from `reserve` once the growth it covered has
+    /// landed in the inner storage, or won't
+    fn release(&self, bytes: u64) {
+        self.reserved.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Pass a file's buffered writes to the inner storage. Readers call
+    /// this first so they see every write.
+    fn apply(&self, inner: &dyn Storage, ino: Inode) -> Result<()> {
+        let Some(buffer) = self.buffers.lock().get(&ino).cloned() else {
+            return Ok(());
+        };
+        let mut buffer = buffer.lock();
+        if buffer.retired {
+            return Ok(());
+        }
+        let result = self.drain(inner, ino, &mut buffer);
+
+        // Drop the emptied buffer, unless a writer raced in to reuse it
+        let mut buffers = self.buffers.lock();
+        if buffer.ranges.is_empty() {
+            buffers.remove(&ino);
+            buffer.retired = true;
+        }
+        result
+    }
+
+    /// Write out and empty a locked buffer. Bytes the inner storage fails
+    /// to take, e.g. for lack of room, stay buffered, so the next flush,
+    /// fsync or release tries them again and reports the error.
+    fn drain(&self, inner: &dyn Storage, ino: Inode, buffer: &mut WriteBuffer) -> Result<()> {
+        let ranges = std::mem::take(&mut buffer.ranges);
+        self.buffered.fetch_sub(buffer.bytes, Ordering::Relaxed);
+        buffer.bytes = 0;
+        if ranges.is_empty() {
+            buffer.modified = None;
+            return Ok(());
+        }
+
+        tracing::debug!("writing {} coalesced ranges of inode {}", ranges.len(), ino);
+        let mut ranges = ranges.into_iter();
+        while let Some((offset, data)) = ranges.next() {
+            let (written, result) = match inner.write(ino, offset, &data) {
+                Ok(n) if n < data.len() => (n, Err(SiaFuseError::NoSpace)),
+                Ok(n) => (n, Ok(())),
+                Err(e) => (0, Err(e)),
+            };
+            if let Err(e) = result {
+                let kept = buffer.insert(offset + written, &data[written..])
+                    + ranges
+                        .map(|(at, rest)| buffer.insert(at, &rest))
+                        .sum::<usize>();
+                self.buffered.fetch_add(kept, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        // The growth is now counted in the inner storage's used bytes
+        self.release(std::mem::take(&mut buffer.reserved));
+        buffer.modified = None;
+        Ok(())
+    }
+
+    /// Upload thread: pass on and sync the longest-idle file once it has
+    /// been idle for the flush interval, or straight away when writers are
+    /// blocked or the layer is shutting down
+    fn run(&self, inner: &dyn Storage) {
+        let mut state = self.state.lock();
+        loop {
+            let Some((&ino, &written)) = state.queue.first() else {
+                if state.shutdown {
+                    break;
+                }
+                self.changed.wait(&mut state);
+                continue;
+            };
+
+            let idle = written.elapsed();
+            let hurry = state.shutdown || state.dirty() >= state.max_dirty || state.blocked > 0;
+            if idle < state.flush_interval && !hurry {
+                let wait = state.flush_interval - idle;
+                self.changed.wait_for(&mut state, wait);
+                continue;
+            }
+
+            state.queue.shift_remove(&ino);
+            state.in_flight = Some(ino);
+            MutexGuard::unlocked(&mut state, || {
+                match self.apply(inner, ino).and_then(|()| inner.sync(ino)) {
+                    Ok(()) => {}
+                    // Deleted while it waited
+                    Err(SiaFuseError::NotFound) => {}
+                    // The inner storage keeps the file dirty, so the next
+                    // flush or fsync retries the upload and reports the error
+                    Err(e) => tracing::warn!("background upload of inode {} failed: {}", ino, e),
+                }
+            });
+            state.in_flight = None;
+            self.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for CacheLayer {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                tracing::error!("upload thread panicked");
+            }
+        }
+    }
+}
+
+impl Storage for CacheLayer {
+    fn now(&self) -> DateTime<Utc> {
+        self.inner.now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        // Files waiting here are usually dirty in the inner storage too
+        let stats = self.inner.stats();
+        let queued = self.shared.state.lock().dirty() as u64;
+        let buffered = self.shared.buffered.load(Ordering::Relaxed) as u64;
+        StorageStats {
+            pending_uploads: stats.pending_uploads.max(queued),
+            dirty_bytes: stats.dirty_bytes.max(buffered),
+            ..stats
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        self.inner.get_attr(ino).map(|attr| self.pending_attr(attr))
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        self.shared.apply(&*self.inner, ino)?;
+        self.inner.set_attr(ino, attr)
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        self.shared.apply(&*self.inner, ino)?;
+        self.inner.touch(ino)
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.inner.last_synced(ino)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        self.inner.mark_synced(ino)
+    }
+
+    fn sync(&self, ino: Inode) -> Result<()> {
+        self.dequeue(ino);
+        self.shared.apply(&*self.inner, ino)?;
+        let result = self.inner.sync(ino);
+        // Writers blocked on dirty bytes look again
+        self.shared.changed.notify_all();
+        result
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        if let Err(e) = self.shared.apply(&*self.inner, ino) {
+            tracing::warn!("failed to write buffered data of inode {}: {}", ino, e);
+        }
+        self.inner.read_with(ino, offset, size, f)
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        if let Err(e) = self.shared.apply(&*self.inner, ino) {
+            tracing::warn!("failed to write buffered data of inode {}: {}", ino, e);
+        }
+        self.inner.read_all(ino)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        let attr = self.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        let written = if attr.kind == FileKind::File && !data.is_empty() {
+            // Buffered writes would only fail later, in the background
+            self.inner.check_writable()?;
+            self.wait_for_dirty_room(data.len());
+            self.buffer_write(&attr, offset, data)?
+        } else {
+            self.inner.write(ino, offset, data)?
+        };
+        self.mark_dirty(ino);
+        Ok(written)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        // The end is only known once earlier writes have landed
+        self.shared.apply(&*self.inner, ino)?;
+        let appended = self.inner.append(ino, data)?;
+        self.mark_dirty(ino);
+        Ok(appended)
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        self.paused(&[ino], || {
+            self.shared.apply(&*self.inner, ino)?;
+            self.inner.truncate(ino, size)
+        })?;
+        self.mark_dirty(ino);
+        Ok(())
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        self.shared.apply(&*self.inner, ino)?;
+        self.inner.punch_hole(ino, offset, len)?;
+        self.mark_dirty(ino);
+        Ok(())
+    }
+
+    fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: usize,
+        ino_out: Inode,
+        off_out: usize,
+        len: usize,
+    ) -> Result<usize> {
+        self.shared.apply(&*self.inner, ino_in)?;
+        self.shared.apply(&*self.inner, ino_out)?;
+        let copied = self
+            .inner
+            .copy_range(ino_in, off_in, ino_out, off_out, len)?;
+        self.mark_dirty(ino_out);
+        Ok(copied)
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let attr = self.inner.create_file(parent, name, perm, owner)?;
+        self.mark_dirty(attr.ino);
+        Ok(attr)
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        self.inner.create_dir(parent, name, perm, owner)
+    }
+
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.inner.create_symlink(parent, name, target, owner)
+    }
+
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.inner
+            .create_special(parent, name, kind, perm, rdev, owner)
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        self.inner.readlink(ino)
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        self.inner.read_dir(ino)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        self.inner.file_paths()
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        self.shared.apply(&*self.inner, ino).ok()?;
+        self.inner.checksum(ino)
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        self.shared.apply(&*self.inner, ino).ok()?;
+        self.inner.allocated_size(ino)
+    }
+
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.shared.apply(&*self.inner, ino)?;
+        self.inner.seek_data(ino, offset)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.shared.apply(&*self.inner, ino)?;
+        self.inner.seek_hole(ino, offset)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        self.inner
+            .lookup(parent, name)
+            .map(|attr| self.pending_attr(attr))
+    }
+
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        // Nothing left to upload once the last link is gone
+        let inos = self.entries(&[(parent, name)]);
+        self.paused(&inos, || self.inner.unlink(parent, name))
+    }
+
+    fn hold(&self, ino: Inode) {
+        self.inner.hold(ino)
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        self.inner.release(ino)?;
+        if self.inner.get_attr(ino).is_none() {
+            self.discard(ino);
+            self.dequeue(ino);
+        }
+        Ok(())
+    }
+
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr> {
+        self.inner
+            .link(ino, newparent, newname)
+            .map(|attr| self.pending_attr(attr))
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        self.inner.path_of(ino)
+    }
+
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        self.inner.rmdir(parent, name)
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        let inos = self.entries(&[(parent, name), (newparent, newname)]);
+        self.paused(&inos, || {
+            self.inner.rename(parent, name, newparent, newname)
+        })
+    }
+
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        let inos = self.entries(&[(parent, name)]);
+        self.paused(&inos, || {
+            self.inner
+                .rename_noreplace(parent, name, newparent, newname)
+        })
+    }
+
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        let inos = self.entries(&[(parent, name), (newparent, newname)]);
+        self.paused(&inos, || {
+            self.inner.exchange(parent, name, newparent, newname)
+        })
+    }
+
+    fn readahead(&self, ino: Inode, offset: u64) {
+        self.inner.readahead(ino, offset)
+    }
+
+    fn chunk_size(&self, ino: Inode) -> Option<u64> {
+        self.inner.chunk_size(ino)
+    }
+
+    fn set_chunk_size(&self, ino: Inode, chunk_size: u64) -> Result<()> {
+        self.inner.set_chunk_size(ino, chunk_size)
+    }
+
+    fn dedup_refs(&self, ino: Inode) -> Option<u64> {
+        self.inner.dedup_refs(ino)
+    }
+
+    fn stored_object(&self, ino: Inode) -> Result<Option<StoredObject>> {
+        self.inner.stored_object(ino)
+    }
+
+    fn bandwidth(&self) -> Option<Arc<Bandwidth>> {
+        self.inner.bandwidth()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.inner.check_writable()
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        // Keep going past failures so one bad file doesn't strand the rest
+        let mut result = Ok(());
+        let buffered: Vec<Inode> = self.shared.buffers.lock().keys().copied().collect();
+        for ino in buffered {
+            match self.shared.apply(&*self.inner, ino) {
+                Ok(()) | Err(SiaFuseError::NotFound) => {}
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(e) => tracing::warn!("failed to write buffered data of inode {}: {}", ino, e),
+            }
+        }
+
+        let queued: Vec<Inode> = {
+            let mut state = self.shared.state.lock();
+            let queued = state.queue.drain(..).map(|(ino, _)| ino).collect();
+            while state.in_flight.is_some() {
+                self.shared.changed.wait(&mut state);
+            }
+            self.shared.changed.notify_all();
+            queued
+        };
+
+        for ino in queued {
+            match self.inner.sync(ino) {
+                Ok(()) | Err(SiaFuseError::NotFound) => {}
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(e) => tracing::warn!("failed to upload inode {}: {}", ino, e),
+            }
+        }
+        result.and(self.inner.sync_all())
+    }
+}
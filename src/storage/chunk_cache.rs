@@ -0,0 +1,102 @@
+use super::Inode;
+use lru::LruCache;
+use std::sync::Arc;
+
+/// Recently downloaded chunks of files, keyed by inode and chunk index.
+/// The least recently used chunks are evicted once their total size
+/// passes the byte budget.
+#[derive(Debug)]
+pub struct ChunkCache {
+    chunks: LruCache<(Inode, u64), Arc<Vec<u8>>>,
+    bytes: u64,
+    budget: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ChunkCache {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            chunks: LruCache::unbounded(),
+            bytes: 0,
+            budget,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Whether chunks are cached at all; a zero budget disables the cache
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    /// Whether chunk `index` of `ino` is cached, without counting a lookup
+    pub fn contains(&self, ino: Inode, index: u64) -> bool {
+        self.chunks.contains(&(ino, index))
+    }
+
+    /// Chunk `index` of `ino`, marking it most recently used
+    pub fn get(&mut self, ino: Inode, index: u64) -> Option<Arc<Vec<u8>>> {
+        let chunk = self.chunks.get(&(ino, index)).cloned();
+        match chunk {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        chunk
+    }
+
+    /// Cache a downloaded chunk, evicting others to stay within the
+    /// budget. Chunks larger than the whole budget aren't kept.
+    pub fn insert(&mut self, ino: Inode, index: u64, data: Arc<Vec<u8>>) {
+        let len = data.len() as u64;
+        if len > self.budget {
+            return;
+        }
+        if let Some(old) = self.chunks.pop(&(ino, index)) {
+            self.bytes -= old.len() as u64;
+        }
+        while self.bytes + len > self.budget {
+            match self.chunks.pop_lru() {
+                Some((_, evicted)) => self.bytes -= evicted.len() as u64,
+                None => break,
+            }
+        }
+        self.chunks.put((ino, index), data);
+        self.bytes += len;
+    }
+
+    /// Drop every chunk of `ino`, e.g. once the whole file is local
+    pub fn remove(&mut self, ino: Inode) {
+        let keys: Vec<(Inode, u64)> = self
+            .chunks
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(chunk_ino, _)| *chunk_ino == ino)
+            .collect();
+        for key in keys {
+            if let Some(chunk) = self.chunks.pop(&key) {
+                self.bytes -= chunk.len() as u64;
+            }
+        }
+    }
+
+    /// Most bytes of chunks held at once
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// Bytes of chunks held
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Lookups that found their chunk
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Lookups that had to download their chunk
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
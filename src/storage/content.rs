@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Size of the blocks file content is stored in
+pub const BLOCK: usize = 4096;
+
+/// Stands in for blocks that were never written
+static ZEROS: [u8; BLOCK] = [0; BLOCK];
+
+/// Zeros fed to the hasher per step while hashing a hole
+static ZERO_RUN: [u8; 1 << 16] = [0; 1 << 16];
+
+/// Sparse file content. Only blocks that were written and aren't all zeros
+/// are stored; everything else below `len` reads as zeros, so extending a
+/// file is a metadata change no matter how far it grows.
+///
+/// Clones share their blocks until one side changes a block, which then
+/// gets its own copy.
+#[derive(Debug, Clone, Default)]
+pub struct Content {
+    blocks: BTreeMap<u64, Arc<[u8; BLOCK]>>,
+    len: u64,
+}
+
+impl Content {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Content holding `data`, leaving all-zero blocks unallocated
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        let mut content = Self::new();
+        content.write(0, &data);
+        content
+    }
+
+    /// Logical size in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Bytes of storage actually held
+    pub fn allocated(&self) -> u64 {
+        (self.blocks.len() * BLOCK) as u64
+    }
+
+    /// Addresses of the stored blocks, which clones share
+    pub fn block_addrs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .values()
+            .map(|block| Arc::as_ptr(block) as usize)
+    }
+
+    /// Hand up to `size` bytes at `offset` to `f`. A range inside a single
+    /// block is passed without copying; longer ranges are assembled first.
+    pub fn read_with<R>(&self, offset: u64, size: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+        let start = offset.min(self.len);
+        let end = offset.saturating_add(size as u64).min(self.len);
+        if start == end {
+            return f(&[]);
+        }
+
+        let block = start / BLOCK as u64;
+        let within = (start % BLOCK as u64) as usize;
+        let len = (end - start) as usize;
+        if within + len <= BLOCK {
+            let data = self.blocks.get(&block).map_or(&ZEROS, |b| &**b);
+            return f(&data[within..within + len]);
+        }
+
+        let mut buf = vec![0; len];
+        self.copy_to(start, &mut buf);
+        f(&buf)
+    }
+
+    /// The whole content as one buffer
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.len as usize];
+        self.copy_to(0, &mut buf);
+        buf
+    }
+
+    /// Fill `buf` from `offset`, leaving holes as the zeros already there
+    fn copy_to(&self, offset: u64, buf: &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let end = offset + buf.len() as u64;
+        let first = offset / BLOCK as u64;
+        let last = (end - 1) / BLOCK as u64;
+
+        for (&index, block) in self.blocks.range(first..=last) {
+            let block_start = index * BLOCK as u64;
+            let from = offset.max(block_start);
+            let to = end.min(block_start + BLOCK as u64);
+            buf[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &block[(from - block_start) as usize..(to - block_start) as usize],
+            );
+        }
+    }
+
+    /// Store `data` at `offset`, growing the content if it ends past `len`
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut pos = offset;
+        for chunk in split_at_blocks(offset, data) {
+            let index = pos / BLOCK as u64;
+            let within = (pos % BLOCK as u64) as usize;
+            pos += chunk.len() as u64;
+
+            // Writing zeros into a hole leaves it a hole
+            if !self.blocks.contains_key(&index) && chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let block = self
+                .blocks
+                .entry(index)
+                .or_insert_with(|| Arc::new([0; BLOCK]));
+            Arc::make_mut(block)[within..within + chunk.len()].copy_from_slice(chunk);
+        }
+        self.len = self.len.max(offset + data.len() as u64);
+    }
+
+    /// Shrink or extend to `size` bytes. Extending only records the size.
+    pub fn truncate(&mut self, size: u64) {
+        if size < self.len {
+            // Drop whole blocks past the end and clear the tail of the last
+            // one, so growing again later reads zeros there
+            let keep = size.div_ceil(BLOCK as u64);
+            self.blocks.split_off(&keep);
+            let within = (size % BLOCK as u64) as usize;
+            if within > 0 {
+                if let Some(block) = self.blocks.get_mut(&(size / BLOCK as u64)) {
+                    Arc::make_mut(block)[within..].fill(0);
+                }
+            }
+        }
+        self.len = size;
+    }
+
+    /// Turn `offset..offset + len` into a hole without changing the size.
+    /// Blocks wholly inside the range are freed; partly covered ones are
+    /// zeroed over the range.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len).min(self.len);
+        if offset >= end {
+            return;
+        }
+
+        let first = offset / BLOCK as u64;
+        let last = (end - 1) / BLOCK as u64;
+        let indices: Vec<u64> = self.blocks.range(first..=last).map(|(&i, _)| i).collect();
+        for index in indices {
+            let block_start = index * BLOCK as u64;
+            let from = offset.max(block_start) - block_start;
+            let to = end.min(block_start + BLOCK as u64) - block_start;
+            if from == 0 && to == BLOCK as u64 {
+                self.blocks.remove(&index);
+            } else if let Some(block) = self.blocks.get_mut(&index) {
+                Arc::make_mut(block)[from as usize..to as usize].fill(0);
+            }
+        }
+    }
+
+    /// First offset at or after `offset` inside a stored block, or None
+    /// if only holes follow (SEEK_DATA)
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let (&index, _) = self.blocks.range(offset / BLOCK as u64..).next()?;
+        let start = offset.max(index * BLOCK as u64);
+        (start < self.len).then_some(start)
+    }
+
+    /// First offset at or after `offset` that isn't inside a stored block.
+    /// The end of the content counts as a hole (SEEK_HOLE).
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let mut index = offset / BLOCK as u64;
+        for (&stored, _) in self.blocks.range(index..) {
+            if stored != index {
+                break;
+            }
+            index += 1;
+        }
+        Some(offset.max(index * BLOCK as u64).min(self.len))
+    }
+
+    /// BLAKE3 hash of the content. Only stored blocks are visited; the
+    /// holes between them are fed to the hasher as long runs of zeros.
+    pub fn hash(&self) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new();
+        let mut hashed = 0;
+        for (&index, block) in self.blocks.range(..self.len.div_ceil(BLOCK as u64)) {
+            let start = index * BLOCK as u64;
+            hash_zeros(&mut hasher, start - hashed);
+            let len = (self.len - start).min(BLOCK as u64) as usize;
+            hasher.update(&block[..len]);
+            hashed = start + len as u64;
+        }
+        hash_zeros(&mut hasher, self.len - hashed);
+        hasher.finalize()
+    }
+}
+
+/// Feed `len` zero bytes to `hasher`
+fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    while len > 0 {
+        let run = len.min(ZERO_RUN.len() as u64) as usize;
+        hasher.update(&ZERO_RUN[..run]);
+        len -= run as u64;
+    }
+}
+
+/// Split `data`, which starts at `offset`, at block boundaries
+fn split_at_blocks(offset: u64, data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let head = (BLOCK - (offset % BLOCK as u64) as usize).min(data.len());
+    let (first, rest) = data.split_at(head);
+    std::iter::once(first)
+        .filter(|c| !c.is_empty())
+        .chain(rest.chunks(BLOCK))
+}
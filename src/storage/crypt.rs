@@ -0,0 +1,141 @@
+use crate::error::{Result, SiaFuseError};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+/// Plaintext bytes sealed together. Ranged reads and upload parts must
+/// start on a block boundary.
+pub const BLOCK: u64 = 64 * 1024;
+
+/// Bytes added to each block: the nonce in front, the tag behind
+const OVERHEAD: u64 = 24 + 16;
+
+/// Bytes of the salt the key is derived with
+pub const SALT_LEN: usize = 16;
+
+/// Encrypts file content block by block with XChaCha20-Poly1305 under a key
+/// derived from a passphrase. Each block carries its own random nonce and
+/// is authenticated together with its index and whether it ends the file,
+/// so reordered, dropped or truncated blocks fail to open.
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derive the key from `passphrase` and `salt` with Argon2id
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| SiaFuseError::Config(format!("cannot derive key: {}", e)))?;
+        Ok(Self {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    /// A fresh random salt
+    pub fn generate_salt() -> [u8; SALT_LEN] {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&nonce[..SALT_LEN]);
+        salt
+    }
+
+    /// Encrypt `plain`, which starts at block `first_block` of its file.
+    /// With `last` set its final block ends the file; an empty file is
+    /// one empty block.
+    pub fn seal(&self, plain: &[u8], first_block: u64, last: bool) -> Vec<u8> {
+        let mut blocks: Vec<&[u8]> = plain.chunks(BLOCK as usize).collect();
+        if blocks.is_empty() && last {
+            blocks.push(&[]);
+        }
+
+        let mut sealed = Vec::with_capacity(plain.len() + blocks.len() * OVERHEAD as usize);
+        let count = blocks.len();
+        for (i, block) in blocks.into_iter().enumerate() {
+            let index = first_block + i as u64;
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let aad = block_aad(index, last && i + 1 == count);
+            let payload = Payload {
+                msg: block,
+                aad: &aad,
+            };
+            // Only fails for messages far larger than a block
+            let ciphertext = self
+                .aead
+                .encrypt(&nonce, payload)
+                .expect("block too large to encrypt");
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+        }
+        sealed
+    }
+
+    /// Decrypt sealed blocks starting at block `first_block` of a file of
+    /// `total_blocks` blocks
+    pub fn open(&self, sealed: &[u8], first_block: u64, total_blocks: u64) -> Result<Vec<u8>> {
+        let mut plain = Vec::with_capacity(sealed.len());
+        for (i, block) in sealed.chunks((BLOCK + OVERHEAD) as usize).enumerate() {
+            let index = first_block + i as u64;
+            if (block.len() as u64) < OVERHEAD {
+                return Err(SiaFuseError::Corrupted(format!(
+                    "block {} is truncated",
+                    index
+                )));
+            }
+            let (nonce, ciphertext) = block.split_at(24);
+            let aad = block_aad(index, index + 1 == total_blocks);
+            let payload = Payload {
+                msg: ciphertext,
+                aad: &aad,
+            };
+            let data = self
+                .aead
+                .decrypt(XNonce::from_slice(nonce), payload)
+                .map_err(|_| {
+                    SiaFuseError::Corrupted(format!("block {} failed authentication", index))
+                })?;
+            plain.extend_from_slice(&data);
+        }
+        Ok(plain)
+    }
+
+    /// Decrypt a whole sealed file
+    pub fn open_all(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let total = (sealed.len() as u64).div_ceil(BLOCK + OVERHEAD);
+        if total == 0 {
+            return Err(SiaFuseError::Corrupted("object is empty".to_string()));
+        }
+        self.open(sealed, 0, total)
+    }
+}
+
+/// Blocks a file of `plain_len` bytes is sealed in
+pub fn block_count(plain_len: u64) -> u64 {
+    plain_len.div_ceil(BLOCK).max(1)
+}
+
+/// Size of the plaintext in a sealed file of `sealed_len` bytes, or None
+/// if no plaintext seals to that size
+pub fn plain_len(sealed_len: u64) -> Option<u64> {
+    let blocks = sealed_len.div_ceil(BLOCK + OVERHEAD);
+    let last = sealed_len.checked_sub(blocks.checked_sub(1)? * (BLOCK + OVERHEAD))?;
+    (last >= OVERHEAD).then(|| sealed_len - blocks * OVERHEAD)
+}
+
+/// Where the blocks holding `len` plaintext bytes at `offset` sit in the
+/// sealed file, as an offset and length. `offset` must start a block.
+pub fn sealed_range(offset: u64, len: u64) -> (u64, u64) {
+    let first = offset / BLOCK;
+    (
+        first * (BLOCK + OVERHEAD),
+        len + len.div_ceil(BLOCK) * OVERHEAD,
+    )
+}
+
+fn block_aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&index.to_le_bytes());
+    aad[8] = last as u8;
+    aad
+}
@@ -0,0 +1,94 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Which renterd objects hold which content, by BLAKE3 hash, so identical
+/// content can be copied server-side instead of uploaded again. Only
+/// objects this mount uploaded or downloaded are known.
+#[derive(Debug, Default)]
+pub struct DedupIndex {
+    by_hash: HashMap<blake3::Hash, BTreeSet<String>>,
+    by_key: HashMap<String, blake3::Hash>,
+    saved_bytes: u64,
+}
+
+impl DedupIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash of the content last stored at `key`
+    pub fn hash_of(&self, key: &str) -> Option<blake3::Hash> {
+        self.by_key.get(key).copied()
+    }
+
+    /// Another object already holding content with `hash`
+    pub fn source_for(&self, hash: &blake3::Hash, key: &str) -> Option<String> {
+        self.by_hash
+            .get(hash)?
+            .iter()
+            .find(|source| *source != key)
+            .cloned()
+    }
+
+    /// Number of objects holding content with `hash`
+    pub fn refs(&self, hash: &blake3::Hash) -> usize {
+        self.by_hash.get(hash).map_or(0, BTreeSet::len)
+    }
+
+    /// Record that `key` now holds content with `hash`
+    pub fn insert(&mut self, key: &str, hash: blake3::Hash) {
+        self.remove(key);
+        self.by_hash
+            .entry(hash)
+            .or_default()
+            .insert(key.to_string());
+        self.by_key.insert(key.to_string(), hash);
+    }
+
+    /// Forget the object at `key`, e.g. after it was deleted
+    pub fn remove(&mut self, key: &str) {
+        let Some(hash) = self.by_key.remove(key) else {
+            return;
+        };
+        if let Some(keys) = self.by_hash.get_mut(&hash) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_hash.remove(&hash);
+            }
+        }
+    }
+
+    /// Follow a rename of the object at `from`, or with `prefix` set of
+    /// every object under it
+    pub fn rename(&mut self, from: &str, to: &str, prefix: bool) {
+        let moved: Vec<String> = if prefix {
+            self.by_key
+                .keys()
+                .filter(|key| key.starts_with(from))
+                .cloned()
+                .collect()
+        } else {
+            self.by_key
+                .keys()
+                .filter(|key| *key == from)
+                .cloned()
+                .collect()
+        };
+
+        for key in moved {
+            if let Some(hash) = self.by_key.get(&key).copied() {
+                self.remove(&key);
+                self.insert(&format!("{}{}", to, &key[from.len()..]), hash);
+            }
+        }
+    }
+
+    /// Count bytes that didn't have to be uploaded
+    pub fn add_saved(&mut self, bytes: u64) {
+        self.saved_bytes += bytes;
+    }
+
+    /// Bytes not uploaded thanks to deduplication
+    pub fn saved_bytes(&self) -> u64 {
+        self.saved_bytes
+    }
+}
@@ -0,0 +1,663 @@
+use super::{
+    Content, DirEntry, FileAttr, FileKind, InMemoryStorage, Inode, Owner, Storage, StorageStats,
+    ROOT_INODE,
+};
+use crate::error::{Result, SiaFuseError};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Storage mirrored onto a local directory, for developing and testing
+/// without a renterd node.
+///
+/// The tree under the directory is loaded at startup, with file content
+/// read on first access. Creating, removing and renaming entries and
+/// changing attributes happen on disk straight away; file content is
+/// written back whole when the file is flushed. The directory is the
+/// source of truth: namespace changes are made there first, and new
+/// entries that the disk refuses are taken out of the cache again.
+pub struct LocalMirrorStorage {
+    cache: InMemoryStorage,
+    root: PathBuf,
+    on_disk: Mutex<HashSet<Inode>>, // Files whose content hasn't been read yet
+    dirty: Mutex<HashSet<Inode>>,   // Files written since they were saved
+}
+
+impl LocalMirrorStorage {
+    /// Load the tree under `root`, creating the directory if needed.
+    /// `cache` holds file content and supplies limits such as the quota.
+    pub fn open(root: &Path, cache: InMemoryStorage) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        let storage = Self {
+            cache,
+            root: root.to_path_buf(),
+            on_disk: Mutex::new(HashSet::new()),
+            dirty: Mutex::new(HashSet::new()),
+        };
+
+        storage.load_dir(root, ROOT_INODE, &mut HashMap::new())?;
+        tracing::info!(
+            "Loaded {} inodes from {}",
+            storage.cache.inode_count(),
+            root.display()
+        );
+        Ok(storage)
+    }
+
+    /// Add the entries of the directory at `path` under `dir`. `links`
+    /// maps already loaded files to their inodes, so hard links share one.
+    fn load_dir(
+        &self,
+        path: &Path,
+        dir: Inode,
+        links: &mut HashMap<(u64, u64), Inode>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let path = entry.path();
+            let result = match entry.file_name().into_string() {
+                Ok(name) => self.load_entry(&path, dir, name, links),
+                Err(_) => Err(SiaFuseError::InvalidArgument(
+                    "name is not valid UTF-8".to_string(),
+                )),
+            };
+            if let Err(e) = result {
+                tracing::warn!("skipping '{}': {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn load_entry(
+        &self,
+        path: &Path,
+        dir: Inode,
+        name: String,
+        links: &mut HashMap<(u64, u64), Inode>,
+    ) -> Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let file_type = meta.file_type();
+        let perm = (meta.mode() & 0o7777) as u16;
+        let owner = Owner {
+            uid: meta.uid(),
+            gid: meta.gid(),
+        };
+
+        if file_type.is_dir() {
+            let attr = self.cache.create_dir(dir, name, perm, owner)?;
+            self.apply_metadata(attr.ino, &meta);
+            return self.load_dir(path, attr.ino, links);
+        }
+
+        let id = (meta.dev(), meta.ino());
+        if let Some(&ino) = links.get(&id) {
+            self.cache.link(ino, dir, &name)?;
+            return Ok(());
+        }
+
+        let attr = if file_type.is_file() {
+            let attr = self.cache.create_file(dir, name, perm, owner)?;
+            self.on_disk.lock().insert(attr.ino);
+            attr
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target.to_str().ok_or_else(|| {
+                SiaFuseError::InvalidArgument("link target is not valid UTF-8".to_string())
+            })?;
+            self.cache.create_symlink(dir, name, target, owner)?
+        } else {
+            let kind = if file_type.is_fifo() {
+                FileKind::Fifo
+            } else if file_type.is_char_device() {
+                FileKind::CharDevice
+            } else if file_type.is_block_device() {
+                FileKind::BlockDevice
+            } else {
+                FileKind::Socket
+            };
+            self.cache
+                .create_special(dir, name, kind, perm, meta.rdev() as u32, owner)?
+        };
+        self.apply_metadata(attr.ino, &meta);
+        if meta.nlink() > 1 {
+            links.insert(id, attr.ino);
+        }
+        Ok(())
+    }
+
+    /// Copy ownership, times and, for files, the size from disk
+    fn apply_metadata(&self, ino: Inode, meta: &Metadata) {
+        let mut files = self.cache.files.write();
+        let Some(file) = files.get_mut(&ino) else {
+            return;
+        };
+        let time = |secs, nanos| DateTime::from_timestamp(secs, nanos as u32).unwrap_or_default();
+
+        file.attr.uid = meta.uid();
+        file.attr.gid = meta.gid();
+        file.attr.atime = time(meta.atime(), meta.atime_nsec());
+        file.attr.mtime = time(meta.mtime(), meta.mtime_nsec());
+        file.attr.ctime = time(meta.ctime(), meta.ctime_nsec());
+        if file.attr.kind == FileKind::File {
+            self.cache.resize(file, meta.len());
+        }
+        file.last_synced = file.attr.mtime;
+    }
+
+    /// Location of an inode under the mirror directory
+    fn disk_path(&self, ino: Inode) -> Result<PathBuf> {
+        let path = self.cache.path_of(ino).ok_or(SiaFuseError::NotFound)?;
+        Ok(self.root.join(path.strip_prefix("/").unwrap_or(&path)))
+    }
+
+    /// Location of `name` in the directory `parent`
+    fn child_path(&self, parent: Inode, name: &str) -> Result<PathBuf> {
+        Ok(self.disk_path(parent)?.join(name))
+    }
+
+    /// Whether a file has lost its last name but is still open
+    fn is_unlinked(&self, ino: Inode) -> bool {
+        self.cache.get_attr(ino).is_some_and(|attr| attr.nlink == 0)
+    }
+
+    /// Read a file's content from disk if that hasn't happened yet
+    fn fetch(&self, ino: Inode) -> Result<()> {
+        let mut on_disk = self.on_disk.lock();
+        if !on_disk.contains(&ino) {
+            return Ok(());
+        }
+
+        let path = self.disk_path(ino)?;
+        tracing::debug!("reading {}", path.display());
+        let content = Content::from_vec(fs::read(&path)?);
+        if let Some(file) = self.cache.files.write().get_mut(&ino) {
+            self.cache.resize(file, content.len());
+            *file.content.write() = content;
+        }
+        on_disk.remove(&ino);
+        Ok(())
+    }
+
+    /// Read before reading. The read interface can't carry an error, so a
+    /// failure is logged and the read sees no file.
+    fn fetch_for_read(&self, ino: Inode) -> bool {
+        match self.fetch(ino) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("failed to read inode {} from disk: {}", ino, e);
+                false
+            }
+        }
+    }
+
+    /// Write a file's content to disk if it changed since it was last saved
+    fn save(&self, ino: Inode) -> Result<()> {
+        if !self.dirty.lock().contains(&ino) {
+            return Ok(());
+        }
+
+        // Unlinked but still open: there is no file on disk to write to
+        if self.is_unlinked(ino) {
+            self.dirty.lock().remove(&ino);
+            return Ok(());
+        }
+
+        let path = self.disk_path(ino)?;
+        let attr = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        let content = self.cache.read_all(ino).ok_or(SiaFuseError::NotFound)?;
+        tracing::debug!("writing {} ({} bytes)", path.display(), content.len());
+        write_content(&path, &content, attr.perm)?;
+        set_times(&path, attr.atime, attr.mtime)?;
+
+        self.dirty.lock().remove(&ino);
+        Ok(())
+    }
+
+    /// Create an entry in the cache, then on disk with `create`, taking it
+    /// out of the cache again if the disk refuses. The disk copy is given
+    /// the entry's owner where this process may do so.
+    fn create_with(
+        &self,
+        parent: Inode,
+        name: String,
+        cached: impl FnOnce(String) -> Result<FileAttr>,
+        create: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> Result<FileAttr> {
+        let path = self.child_path(parent, &name)?;
+        let attr = cached(name.clone())?;
+
+        if let Err(e) = create(&path) {
+            let undo = match attr.kind {
+                FileKind::Directory => self.cache.rmdir(parent, &name),
+                _ => self.cache.unlink(parent, &name),
+            };
+            if let Err(undo) = undo {
+                tracing::warn!("failed to forget '{}': {}", name, undo);
+            }
+            return Err(e.into());
+        }
+        let owner = Owner {
+            uid: attr.uid,
+            gid: attr.gid,
+        };
+        if owner != Owner::current() {
+            if let Err(e) = std::os::unix::fs::lchown(&path, Some(owner.uid), Some(owner.gid)) {
+                tracing::warn!("failed to chown '{}': {}", path.display(), e);
+            }
+        }
+        Ok(attr)
+    }
+}
+
+/// Write a file's content, lifting a read-only mode for the moment it
+/// takes, as a file opened before the mode changed could still be written
+fn write_content(path: &Path, content: &[u8], perm: u16) -> io::Result<()> {
+    match fs::write(path, content) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            fs::set_permissions(path, fs::Permissions::from_mode(u32::from(perm) | 0o200))?;
+            let written = fs::write(path, content);
+            fs::set_permissions(path, fs::Permissions::from_mode(u32::from(perm)))?;
+            written
+        }
+        result => result,
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Set access and modification times without following symlinks
+fn set_times(path: &Path, atime: DateTime<Utc>, mtime: DateTime<Utc>) -> io::Result<()> {
+    let timespec = |t: DateTime<Utc>| libc::timespec {
+        tv_sec: t.timestamp() as libc::time_t,
+        tv_nsec: t.timestamp_subsec_nanos() as libc::c_long,
+    };
+    let times = [timespec(atime), timespec(mtime)];
+    let path = c_path(path)?;
+    let rc = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// renameat2(2), for `RENAME_NOREPLACE` and `RENAME_EXCHANGE`
+fn rename_with_flags(from: &Path, to: &Path, flags: libc::c_uint) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    let rc = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+impl Storage for LocalMirrorStorage {
+    fn now(&self) -> DateTime<Utc> {
+        self.cache.now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        let dirty = self.dirty.lock();
+        StorageStats {
+            pending_uploads: dirty.len() as u64,
+            dirty_bytes: self.cache.total_size(dirty.iter()),
+            ..self.cache.stats()
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        self.cache.get_attr(ino)
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        let old = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        if !self.is_unlinked(ino) {
+            let path = self.disk_path(ino)?;
+            // chmod would follow a symlink; a link's own mode is ignored
+            if attr.perm != old.perm && attr.kind != FileKind::Symlink {
+                fs::set_permissions(&path, fs::Permissions::from_mode(u32::from(attr.perm)))?;
+            }
+            if attr.uid != old.uid || attr.gid != old.gid {
+                std::os::unix::fs::lchown(&path, Some(attr.uid), Some(attr.gid))?;
+            }
+            if attr.atime != old.atime || attr.mtime != old.mtime {
+                set_times(&path, attr.atime, attr.mtime)?;
+            }
+        }
+        self.cache.set_attr(ino, attr)
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        self.cache.touch(ino)?;
+        if let Some(attr) = self.cache.get_attr(ino).filter(|attr| attr.nlink > 0) {
+            set_times(&self.disk_path(ino)?, attr.atime, attr.mtime)?;
+        }
+        Ok(())
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.cache.last_synced(ino)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        self.cache.mark_synced(ino)
+    }
+
+    fn sync(&self, ino: Inode) -> Result<()> {
+        self.save(ino)?;
+        self.cache.mark_synced(ino)
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        self.fetch_for_read(ino) && self.cache.read_with(ino, offset, size, f)
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        if !self.fetch_for_read(ino) {
+            return None;
+        }
+        self.cache.read_all(ino)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        self.fetch(ino)?;
+        let written = self.cache.write(ino, offset, data)?;
+        self.dirty.lock().insert(ino);
+        Ok(written)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        self.fetch(ino)?;
+        let appended = self.cache.append(ino, data)?;
+        self.dirty.lock().insert(ino);
+        Ok(appended)
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        // Nothing of the old content survives truncation to zero
+        if size == 0 {
+            self.on_disk.lock().remove(&ino);
+        } else {
+            self.fetch(ino)?;
+        }
+        self.cache.truncate(ino, size)?;
+        self.dirty.lock().insert(ino);
+        Ok(())
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        self.fetch(ino)?;
+        self.cache.punch_hole(ino, offset, len)?;
+        self.dirty.lock().insert(ino);
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.create_with(
+            parent,
+            name,
+            |name| self.cache.create_file(parent, name, perm, owner),
+            |path| {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(u32::from(perm)))
+            },
+        )
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        self.create_with(
+            parent,
+            name,
+            |name| self.cache.create_dir(parent, name, perm, owner),
+            |path| {
+                fs::create_dir(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(u32::from(perm)))
+            },
+        )
+    }
+
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.create_with(
+            parent,
+            name,
+            |name| self.cache.create_symlink(parent, name, target, owner),
+            |path| std::os::unix::fs::symlink(target, path),
+        )
+    }
+
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let format = match kind {
+            FileKind::Fifo => libc::S_IFIFO,
+            FileKind::CharDevice => libc::S_IFCHR,
+            FileKind::BlockDevice => libc::S_IFBLK,
+            FileKind::Socket => libc::S_IFSOCK,
+            _ => {
+                return Err(SiaFuseError::InvalidArgument(format!(
+                    "{:?} is not a special file",
+                    kind
+                )))
+            }
+        };
+        self.create_with(
+            parent,
+            name,
+            |name| {
+                self.cache
+                    .create_special(parent, name, kind, perm, rdev, owner)
+            },
+            |path| {
+                let c_path = c_path(path)?;
+                let mode = format | libc::mode_t::from(perm);
+                if unsafe { libc::mknod(c_path.as_ptr(), mode, libc::dev_t::from(rdev)) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                fs::set_permissions(path, fs::Permissions::from_mode(u32::from(perm)))
+            },
+        )
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        self.cache.readlink(ino)
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        self.cache.read_dir(ino)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        self.cache.file_paths()
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        if !self.fetch_for_read(ino) {
+            return None;
+        }
+        self.cache.checksum(ino)
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        self.cache.allocated_size(ino)
+    }
+
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.fetch(ino)?;
+        self.cache.seek_data(ino, offset)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.fetch(ino)?;
+        self.cache.seek_hole(ino, offset)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        self.cache.lookup(parent, name)
+    }
+
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        let attr = self
+            .cache
+            .lookup(parent, name)
+            .ok_or(SiaFuseError::NotFound)?;
+        if attr.kind == FileKind::Directory {
+            return Err(SiaFuseError::IsADirectory);
+        }
+
+        // An open file stays readable, so it needs its content first
+        if self.cache.is_held(attr.ino) {
+            self.fetch(attr.ino)?;
+        }
+        fs::remove_file(self.child_path(parent, name)?)?;
+        self.cache.unlink(parent, name)?;
+
+        if self.cache.get_attr(attr.ino).is_none() {
+            self.on_disk.lock().remove(&attr.ino);
+            self.dirty.lock().remove(&attr.ino);
+        }
+        Ok(())
+    }
+
+    fn hold(&self, ino: Inode) {
+        self.cache.hold(ino)
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        self.cache.release(ino)?;
+        if self.cache.get_attr(ino).is_none() {
+            self.dirty.lock().remove(&ino);
+        }
+        Ok(())
+    }
+
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr> {
+        let from = self.disk_path(ino)?;
+        let to = self.child_path(newparent, newname)?;
+        let attr = self.cache.link(ino, newparent, newname)?;
+
+        if let Err(e) = fs::hard_link(&from, &to) {
+            if let Err(undo) = self.cache.unlink(newparent, newname) {
+                tracing::warn!("failed to forget '{}': {}", newname, undo);
+            }
+            return Err(e.into());
+        }
+        Ok(attr)
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        self.cache.path_of(ino)
+    }
+
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        match self.cache.lookup(parent, name) {
+            Some(attr) if attr.kind != FileKind::Directory => {
+                return Err(SiaFuseError::NotADirectory)
+            }
+            Some(_) => {}
+            None => return Err(SiaFuseError::NotFound),
+        }
+        fs::remove_dir(self.child_path(parent, name)?)?;
+        self.cache.rmdir(parent, name)
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        // A replaced file that is still open stays readable
+        if let Some(target) = self.cache.lookup(newparent, newname) {
+            if self.cache.is_held(target.ino) {
+                self.fetch(target.ino)?;
+            }
+        }
+        fs::rename(
+            self.child_path(parent, name)?,
+            self.child_path(newparent, newname)?,
+        )?;
+        self.cache.rename(parent, name, newparent, newname)
+    }
+
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        rename_with_flags(
+            &self.child_path(parent, name)?,
+            &self.child_path(newparent, newname)?,
+            libc::RENAME_NOREPLACE,
+        )?;
+        self.cache
+            .rename_noreplace(parent, name, newparent, newname)
+    }
+
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        rename_with_flags(
+            &self.child_path(parent, name)?,
+            &self.child_path(newparent, newname)?,
+            libc::RENAME_EXCHANGE,
+        )?;
+        self.cache.exchange(parent, name, newparent, newname)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        let dirty: Vec<Inode> = self.dirty.lock().iter().copied().collect();
+        for ino in dirty {
+            self.save(ino)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LocalMirrorStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_all() {
+            tracing::error!("failed to save pending writes: {}", e);
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use super::{
+    DirEntry, FileAttr, FileKind, Inode, Owner, Storage, StorageStats, DEFAULT_CAPACITY,
+    FIRST_COOKIE, ROOT_INODE,
+};
+use crate::error::{Result, SiaFuseError};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Read-only view of a host directory, served straight from the host
+/// filesystem on every call. Meant to be mounted into another storage with
+/// `UnionStorage`, e.g. to show local files next to those kept in Sia.
+///
+/// Inode numbers are handed out to paths as they are first seen and kept
+/// for as long as the storage lives.
+pub struct PassthroughStorage {
+    root: PathBuf,
+    inodes: RwLock<Inodes>,
+}
+
+/// Paths below the root by inode number, and back
+struct Inodes {
+    paths: HashMap<Inode, PathBuf>,
+    numbers: HashMap<PathBuf, Inode>,
+    next: Inode,
+}
+
+impl PassthroughStorage {
+    /// Serve the directory at `root`
+    pub fn open(root: &Path) -> Result<Self> {
+        if !fs::metadata(root)?.is_dir() {
+            return Err(SiaFuseError::NotADirectory);
+        }
+        let inodes = Inodes {
+            paths: HashMap::from([(ROOT_INODE, PathBuf::new())]),
+            numbers: HashMap::from([(PathBuf::new(), ROOT_INODE)]),
+            next: ROOT_INODE + 1,
+        };
+        Ok(Self {
+            root: root.to_path_buf(),
+            inodes: RwLock::new(inodes),
+        })
+    }
+
+    /// Path of an inode relative to the root
+    fn relative(&self, ino: Inode) -> Result<PathBuf> {
+        self.inodes
+            .read()
+            .paths
+            .get(&ino)
+            .cloned()
+            .ok_or(SiaFuseError::NotFound)
+    }
+
+    /// Location of an inode on the host
+    fn host_path(&self, ino: Inode) -> Result<PathBuf> {
+        Ok(self.root.join(self.relative(ino)?))
+    }
+
+    /// Inode number of a path relative to the root, handing out a new one
+    /// the first time the path is seen
+    fn inode_of(&self, relative: PathBuf) -> Inode {
+        let mut inodes = self.inodes.write();
+        if let Some(&ino) = inodes.numbers.get(&relative) {
+            return ino;
+        }
+        let ino = inodes.next;
+        inodes.next += 1;
+        inodes.paths.insert(ino, relative.clone());
+        inodes.numbers.insert(relative, ino);
+        ino
+    }
+
+    /// Metadata of an inode, without following a symlink
+    fn metadata(&self, ino: Inode) -> Result<Metadata> {
+        Ok(fs::symlink_metadata(self.host_path(ino)?)?)
+    }
+
+    /// Relative path of `name` in the directory `parent`. Names that could
+    /// lead outside the root, and directories reached through a symlink,
+    /// are refused.
+    fn child(&self, parent: Inode, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(SiaFuseError::NotFound);
+        }
+        if !self.metadata(parent)?.is_dir() {
+            return Err(SiaFuseError::NotADirectory);
+        }
+        Ok(self.relative(parent)?.join(name))
+    }
+
+    /// Regular files below the directory at `relative`, by path
+    fn collect_files(&self, relative: &Path, ino: Inode, paths: &mut Vec<(String, Inode)>) {
+        for entry in self.read_dir(ino).unwrap_or_default() {
+            let path = relative.join(&entry.name);
+            match entry.kind {
+                FileKind::File => paths.push((path.to_string_lossy().into_owned(), entry.ino)),
+                FileKind::Directory => self.collect_files(&path, entry.ino, paths),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Storage for PassthroughStorage {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            capacity_bytes: DEFAULT_CAPACITY,
+            used_bytes: 0,
+            max_inodes: u64::MAX,
+            used_inodes: self.inodes.read().paths.len() as u64,
+            cache_hits: 0,
+            cache_misses: 0,
+            pending_uploads: 0,
+            dirty_bytes: 0,
+            dedup_saved_bytes: 0,
+            backend_retries: 0,
+            read_cache_hits: 0,
+            read_cache_misses: 0,
+            read_cache_bytes: 0,
+            read_only: false,
+            oldest_synced: None,
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        let meta = self.metadata(ino).ok()?;
+        Some(host_attr(ino, &meta))
+    }
+
+    fn set_attr(&self, _ino: Inode, _attr: FileAttr) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn touch(&self, _ino: Inode) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.get_attr(ino).map(|attr| attr.mtime)
+    }
+
+    fn mark_synced(&self, _ino: Inode) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        let read = || -> Result<Vec<u8>> {
+            let mut file = File::open(self.host_path(ino)?)?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            let mut data = Vec::new();
+            file.take(size as u64).read_to_end(&mut data)?;
+            Ok(data)
+        };
+        match read() {
+            Ok(data) => {
+                f(&data);
+                true
+            }
+            Err(e) => {
+                tracing::debug!("read of host inode {} failed: {}", ino, e);
+                false
+            }
+        }
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        fs::read(self.host_path(ino).ok()?).ok()
+    }
+
+    fn write(&self, _ino: Inode, _offset: usize, _data: &[u8]) -> Result<usize> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn append(&self, _ino: Inode, _data: &[u8]) -> Result<(usize, usize)> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn truncate(&self, _ino: Inode, _size: u64) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn punch_hole(&self, _ino: Inode, _offset: u64, _len: u64) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn create_file(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _perm: u16,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn create_dir(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _perm: u16,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn create_symlink(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _target: &str,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn create_special(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _kind: FileKind,
+        _perm: u16,
+        _rdev: u32,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        let target = fs::read_link(self.host_path(ino)?)?;
+        Ok(target.as_os_str().as_bytes().to_vec())
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        let relative = self.relative(ino).ok()?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.root.join(&relative)).ok()? {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let (Ok(name), Ok(file_type)) = (entry.file_name().into_string(), entry.file_type())
+            else {
+                continue;
+            };
+            let ino = self.inode_of(relative.join(&name));
+            entries.push(DirEntry {
+                ino,
+                name,
+                kind: kind_of(&file_type),
+                // Inode numbers are never reused, so neither are cookies
+                cookie: FIRST_COOKIE + ino,
+            });
+        }
+        entries.sort_unstable_by_key(|entry| entry.cookie);
+        Some(entries)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        let mut paths = Vec::new();
+        self.collect_files(Path::new(""), ROOT_INODE, &mut paths);
+        paths.sort();
+        paths
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        let file = File::open(self.host_path(ino).ok()?).ok()?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(file).ok()?;
+        Some(hasher.finalize())
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        self.metadata(ino).ok().map(|meta| meta.blocks() * 512)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        let relative = self.child(parent, name).ok()?;
+        let meta = fs::symlink_metadata(self.root.join(&relative)).ok()?;
+        Some(host_attr(self.inode_of(relative), &meta))
+    }
+
+    fn unlink(&self, _parent: Inode, _name: &str) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn link(&self, _ino: Inode, _newparent: Inode, _newname: &str) -> Result<FileAttr> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        self.relative(ino)
+            .ok()
+            .map(|path| Path::new("/").join(path))
+    }
+
+    fn rmdir(&self, _parent: Inode, _name: &str) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn rename(&self, _parent: Inode, _name: &str, _newparent: Inode, _newname: &str) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn rename_noreplace(
+        &self,
+        _parent: Inode,
+        _name: &str,
+        _newparent: Inode,
+        _newname: &str,
+    ) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+
+    fn exchange(
+        &self,
+        _parent: Inode,
+        _name: &str,
+        _newparent: Inode,
+        _newname: &str,
+    ) -> Result<()> {
+        Err(SiaFuseError::ReadOnly)
+    }
+}
+
+/// Attributes of a host file
+fn host_attr(ino: Inode, meta: &Metadata) -> FileAttr {
+    let time = |secs, nanos| DateTime::from_timestamp(secs, nanos as u32).unwrap_or_default();
+    FileAttr {
+        ino,
+        size: meta.len(),
+        kind: kind_of(&meta.file_type()),
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: meta.rdev() as u32,
+        flags: 0,
+        atime: time(meta.atime(), meta.atime_nsec()),
+        mtime: time(meta.mtime(), meta.mtime_nsec()),
+        ctime: time(meta.ctime(), meta.ctime_nsec()),
+    }
+}
+
+fn kind_of(file_type: &fs::FileType) -> FileKind {
+    if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_fifo() {
+        FileKind::Fifo
+    } else if file_type.is_char_device() {
+        FileKind::CharDevice
+    } else if file_type.is_block_device() {
+        FileKind::BlockDevice
+    } else if file_type.is_socket() {
+        FileKind::Socket
+    } else {
+        FileKind::File
+    }
+}
@@ -0,0 +1,170 @@
+use super::chunk_cache::ChunkCache;
+use super::crypt::Cipher;
+use super::Inode;
+use crate::renterd::RenterdClient;
+use crate::throttle;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Chunks downloaded at once
+const THREADS: usize = 4;
+
+/// A chunk to download ahead of a sequential reader
+pub struct Job {
+    pub ino: Inode,
+    pub index: u64,
+    pub client: RenterdClient,       // For the bucket holding the object
+    pub cipher: Option<Arc<Cipher>>, // Decrypts the chunk, if encrypted
+    pub key: String,                 // Object key within the bucket
+    pub offset: u64,
+    pub len: u64,
+    pub file_size: u64,
+}
+
+/// Downloads chunks into the read cache on background threads, in the
+/// order they were scheduled
+pub struct Readahead {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    cache: Arc<Mutex<ChunkCache>>,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Job>,
+    in_flight: HashMap<(Inode, u64), bool>, // Chunks downloading, and whether still wanted
+    shutdown: bool,
+}
+
+impl State {
+    fn is_pending(&self, ino: Inode, index: u64) -> bool {
+        self.in_flight.contains_key(&(ino, index))
+            || self
+                .queue
+                .iter()
+                .any(|job| job.ino == ino && job.index == index)
+    }
+}
+
+impl Readahead {
+    /// Start the download threads, which fill `cache`
+    pub fn new(cache: Arc<Mutex<ChunkCache>>) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+            cache,
+        });
+
+        let mut readahead = Self {
+            shared,
+            workers: Vec::with_capacity(THREADS),
+        };
+        for _ in 0..THREADS {
+            let shared = readahead.shared.clone();
+            let worker = std::thread::Builder::new()
+                .name("sia-fuse-readahead".to_string())
+                .spawn(move || {
+                    throttle::set_background();
+                    shared.run()
+                })?;
+            readahead.workers.push(worker);
+        }
+        Ok(readahead)
+    }
+
+    /// Queue a chunk unless it is already queued or downloading
+    pub fn schedule(&self, job: Job) {
+        let mut state = self.shared.state.lock();
+        if !state.is_pending(job.ino, job.index) {
+            state.queue.push_back(job);
+            self.shared.changed.notify_all();
+        }
+    }
+
+    /// Make way for a reader about to download chunk `index` of `ino`
+    /// itself: take it off the queue, or wait for its download to finish
+    /// so the reader finds it in the cache
+    pub fn claim(&self, ino: Inode, index: u64) {
+        let mut state = self.shared.state.lock();
+        state
+            .queue
+            .retain(|job| job.ino != ino || job.index != index);
+        while state.in_flight.contains_key(&(ino, index)) {
+            self.shared.changed.wait(&mut state);
+        }
+    }
+
+    /// Drop the queued chunks of `ino`, and discard its chunk being
+    /// downloaded once the download returns
+    pub fn cancel(&self, ino: Inode) {
+        let mut state = self.shared.state.lock();
+        state.queue.retain(|job| job.ino != ino);
+        for (_, wanted) in state
+            .in_flight
+            .iter_mut()
+            .filter(|((chunk_ino, _), _)| *chunk_ino == ino)
+        {
+            *wanted = false;
+        }
+    }
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut state = self.state.lock();
+        loop {
+            if state.shutdown {
+                break;
+            }
+            let Some(job) = state.queue.pop_front() else {
+                self.changed.wait(&mut state);
+                continue;
+            };
+
+            let chunk = (job.ino, job.index);
+            state.in_flight.insert(chunk, true);
+            let result = MutexGuard::unlocked(&mut state, || {
+                tracing::debug!("reading ahead chunk {} of {}", job.index, job.key);
+                super::sia::download_range(
+                    &job.client,
+                    job.cipher.as_deref(),
+                    &job.key,
+                    job.offset,
+                    job.len,
+                    job.file_size,
+                )
+            });
+
+            let wanted = state.in_flight.remove(&chunk).unwrap_or(false);
+            match result {
+                Ok(data) if wanted => {
+                    self.cache.lock().insert(job.ino, job.index, Arc::new(data));
+                }
+                Ok(_) => {}
+                // The reader downloads the chunk itself when it gets there
+                Err(e) => tracing::debug!("readahead of {} failed: {}", job.key, e),
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for Readahead {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                tracing::error!("readahead thread panicked");
+            }
+        }
+    }
+}
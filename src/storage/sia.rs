@@ -0,0 +1,1684 @@
+use super::chunk_cache::ChunkCache;
+use super::crypt::{self, Cipher};
+use super::dedup::DedupIndex;
+use super::readahead::{Job, Readahead};
+use super::{
+    split_path, walk_dirs, Content, DirEntry, FileAttr, FileKind, InMemoryStorage, Inode, Owner,
+    Storage, StorageStats, StoredObject, ROOT_INODE,
+};
+use crate::error::{Result, SiaFuseError};
+use crate::renterd::{RenterdClient, UploadedPart};
+use crate::throttle::Bandwidth;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Bytes of a file gathered before they are uploaded as one part
+pub const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Largest chunk size of files named like databases, which are read and
+/// written at random
+const DATABASE_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Smallest chunk size of files named like media or archives, which are
+/// read and written front to back
+const MEDIA_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Extensions of files given database-sized chunks
+const DATABASE_EXTENSIONS: &[&str] = &["db", "sqlite", "sqlite3", "mdb", "ldb", "wal"];
+
+/// Extensions of files given media-sized chunks
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "mov", "avi", "webm", "mp3", "flac", "iso", "img", "tar", "gz", "zst", "xz",
+    "zip",
+];
+
+/// Bounds of a chunk size set per file
+const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+const MAX_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Bytes of downloaded chunks kept for reads
+pub const DEFAULT_READ_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Chunks downloaded ahead of a sequential reader
+pub const DEFAULT_READAHEAD_CHUNKS: u64 = 4;
+
+/// Object holding the salt the encryption key is derived with. It is
+/// hidden from the tree and its name can't be used at the top of a bucket.
+const KEY_OBJECT: &str = "/.sia-fuse-key";
+
+/// Plaintext sealed in the key object to tell a wrong passphrase apart
+const KEY_CHECK: &[u8] = b"sia-fuse";
+
+/// Uploads renterd must refuse in a row before the mount turns read-only
+const MAX_REFUSED_UPLOADS: u32 = 3;
+
+/// How often a read-only mount checks whether renterd takes uploads again
+const WRITE_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long after a file's changes are uploaded its local attributes are
+/// kept over what renterd reports, which may still predate the upload
+pub const DEFAULT_TRUST_LOCAL: Duration = Duration::from_secs(30);
+
+/// How long what renterd reported about an object is reused for
+const STORED_OBJECT_TTL: Duration = Duration::from_secs(10);
+
+/// Object uploaded and deleted to check whether renterd takes uploads
+const PROBE_OBJECT: &str = "/.sia-fuse-probe";
+
+/// Contents of [`KEY_OBJECT`]
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    salt: Vec<u8>,
+    check: Vec<u8>, // KEY_CHECK sealed with the derived key
+}
+
+/// How a dirty file's content is getting to renterd
+enum PendingUpload {
+    /// Written front to back so far; complete chunks are already uploaded
+    /// as parts of a multipart upload
+    Parts(MultipartUpload),
+    /// Rewritten out of order; uploaded whole on the next sync
+    Whole,
+    /// Parts on their way to renterd, with the upload out of the map; set
+    /// to something else meanwhile, the parts are dropped once sent
+    Sending,
+}
+
+/// Whether renterd takes uploads, judged by how the last ones went
+#[derive(Default)]
+struct WriteHealth {
+    refused: u32, // Uploads refused in a row
+    // While read-only: the path to probe renterd with, and when it last was
+    probe: Option<(String, Instant)>,
+}
+
+/// How current a file's attributes are compared to renterd's
+#[derive(Default)]
+struct Freshness {
+    reported: Option<(u64, Option<DateTime<Utc>>)>, // Object size and mtime renterd last reported
+    checked: Option<Instant>,                       // When renterd was last asked
+    uploaded: Option<Instant>,                      // When local changes were last uploaded
+}
+
+struct MultipartUpload {
+    key: String,
+    id: String,
+    parts: Vec<UploadedPart>,
+    uploaded: u64, // Bytes covered by `parts`
+}
+
+/// Storage backed by objects in a renterd bucket, one object per file.
+///
+/// Directories are derived from key prefixes; empty ones are kept as
+/// `dir/` marker objects. Reads of files not downloaded yet fetch them a
+/// chunk at a time, keeping recent chunks in an LRU read cache; a file's
+/// whole content is downloaded once it is written or read as a whole.
+/// Sequential readers have the next few chunks downloaded in the
+/// background.
+/// Files written front to back are uploaded a chunk at a time as the
+/// chunks fill up, with the rest sent when the file is flushed; rewriting
+/// bytes already sent replaces just the parts holding them. Other files
+/// are uploaded whole on flush. Content identical to an object
+/// already in the bucket is copied server-side instead of uploaded.
+/// Attributes such as permissions live only in memory.
+///
+/// With a passphrase set through [`SiaStorage::with_passphrase`], content
+/// is encrypted before it is uploaded and decrypted after download.
+///
+/// Opened with [`SiaStorage::open_buckets`], each top-level directory is a
+/// bucket and nothing else may be created at the root.
+///
+/// Once renterd has refused several uploads in a row, e.g. for lack of
+/// contracts or funds, the mount turns read-only: changes fail with
+/// `ReadOnly` while unsynced data stays in the cache, and renterd is probed
+/// now and then until it takes uploads again.
+///
+/// While renterd is down, content already downloaded is still served and
+/// changes are held in the cache. Once it is back the root is checked
+/// against renterd's buckets and the held changes are uploaded.
+pub struct SiaStorage {
+    cache: InMemoryStorage,
+    client: RenterdClient,
+    buckets: bool, // Top-level directories are buckets
+    chunk_size: u64,
+    chunk_sizes: Mutex<HashMap<Inode, u64>>, // Chunk size of each file, once fixed
+    remote: Mutex<HashSet<Inode>>,           // Files whose content hasn't been downloaded
+    dirty: Mutex<HashSet<Inode>>,            // Files written since their last upload
+    pending: Mutex<HashMap<Inode, PendingUpload>>,
+    write_health: Mutex<WriteHealth>,
+    stored_objects: Mutex<HashMap<Inode, (Instant, StoredObject)>>, // Recently fetched from renterd
+    freshness: Mutex<HashMap<Inode, Freshness>>,
+    attr_refresh: Option<Duration>, // How long renterd's attributes are trusted, if they are refreshed
+    trust_local: Duration,
+    dedup: Mutex<DedupIndex>,
+    read_cache: Arc<Mutex<ChunkCache>>,
+    readahead: Readahead,
+    readahead_chunks: u64,
+    cipher: Option<Arc<Cipher>>, // Encrypts content, if a passphrase is set
+    hits: AtomicU64,             // Reads of content already downloaded
+    misses: AtomicU64,           // Reads that downloaded content first
+}
+
+impl SiaStorage {
+    /// Build the tree from the objects in the client's bucket. `cache`
+    /// holds downloaded content and supplies limits such as the quota.
+    pub fn open(client: RenterdClient, cache: InMemoryStorage) -> Result<Self> {
+        let storage = Self::new(client, cache, false)?;
+        storage.load_bucket(None)?;
+        Ok(storage)
+    }
+
+    /// Show `buckets`, or every bucket in the node if it is empty, as
+    /// top-level directories, each holding the tree of its objects
+    pub fn open_buckets(
+        client: RenterdClient,
+        buckets: &[String],
+        cache: InMemoryStorage,
+    ) -> Result<Self> {
+        let buckets = match buckets {
+            [] => client.list_buckets()?,
+            buckets => buckets.to_vec(),
+        };
+        let storage = Self::new(client, cache, true)?;
+        for bucket in &buckets {
+            storage.load_bucket(Some(bucket))?;
+        }
+        Ok(storage)
+    }
+
+    fn new(client: RenterdClient, cache: InMemoryStorage, buckets: bool) -> Result<Self> {
+        let read_cache = Arc::new(Mutex::new(ChunkCache::new(DEFAULT_READ_CACHE_SIZE)));
+        Ok(Self {
+            cache,
+            client,
+            buckets,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_sizes: Mutex::new(HashMap::new()),
+            remote: Mutex::new(HashSet::new()),
+            dirty: Mutex::new(HashSet::new()),
+            pending: Mutex::new(HashMap::new()),
+            write_health: Mutex::new(WriteHealth::default()),
+            stored_objects: Mutex::new(HashMap::new()),
+            freshness: Mutex::new(HashMap::new()),
+            attr_refresh: None,
+            trust_local: DEFAULT_TRUST_LOCAL,
+            dedup: Mutex::new(DedupIndex::new()),
+            readahead: Readahead::new(read_cache.clone())?,
+            readahead_chunks: DEFAULT_READAHEAD_CHUNKS,
+            read_cache,
+            cipher: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Add the objects of `bucket` under a directory named after it, or
+    /// with None those of the client's bucket at the root
+    fn load_bucket(&self, bucket: Option<&str>) -> Result<()> {
+        let (client, prefix) = match bucket {
+            Some(bucket) => {
+                self.ensure_dir(bucket)?;
+                (
+                    Cow::Owned(self.client.clone().with_bucket(bucket)),
+                    format!("/{}", bucket),
+                )
+            }
+            None => (Cow::Borrowed(&self.client), String::new()),
+        };
+
+        let objects = client.list("/")?;
+        tracing::info!(
+            "Found {} objects in renterd bucket '{}'",
+            objects.len(),
+            client.bucket()
+        );
+
+        for object in objects {
+            if object.key == KEY_OBJECT || object.key == PROBE_OBJECT {
+                continue;
+            }
+            let path = format!("{}{}", prefix, object.key);
+            let result = match path.strip_suffix('/') {
+                Some(dir) => self.ensure_dir(dir).map(|_| ()),
+                None => self.add_remote_file(&path, object.size, object.mod_time),
+            };
+            if let Err(e) = result {
+                tracing::warn!("skipping object '{}': {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload sequentially written files in parts of `chunk_size` bytes,
+    /// and download chunks of that size for reads. Zero is treated as one
+    /// byte. With encryption on, it is rounded up to whole blocks.
+    ///
+    /// Files named like databases get chunks of at most 256 KiB and those
+    /// named like media or archives chunks of at least 16 MiB instead. A
+    /// file's chunk size is fixed the first time it is used.
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = self.whole_blocks(chunk_size);
+        self
+    }
+
+    /// `chunk_size` rounded up to whole encryption blocks if content is
+    /// encrypted, and to at least a byte
+    fn whole_blocks(&self, chunk_size: u64) -> u64 {
+        match self.cipher {
+            Some(_) => chunk_size.max(1).next_multiple_of(crypt::BLOCK),
+            None => chunk_size.max(1),
+        }
+    }
+
+    /// Chunk size of a file, picking one from its name if it has none yet
+    fn chunk_size_of(&self, ino: Inode) -> u64 {
+        if let Some(&chunk_size) = self.chunk_sizes.lock().get(&ino) {
+            return chunk_size;
+        }
+        let extension = self.cache.path_of(ino).and_then(|path| {
+            let extension = path.extension()?.to_str()?;
+            Some(extension.to_ascii_lowercase())
+        });
+        let chunk_size = match extension.as_deref() {
+            Some(ext) if DATABASE_EXTENSIONS.contains(&ext) => {
+                self.chunk_size.min(DATABASE_CHUNK_SIZE)
+            }
+            Some(ext) if MEDIA_EXTENSIONS.contains(&ext) => self.chunk_size.max(MEDIA_CHUNK_SIZE),
+            _ => self.chunk_size,
+        };
+        let chunk_size = self.whole_blocks(chunk_size);
+        *self.chunk_sizes.lock().entry(ino).or_insert(chunk_size)
+    }
+
+    /// Keep up to `bytes` of downloaded chunks for reads. Zero disables the
+    /// read cache, so reads download the whole file.
+    pub fn with_read_cache_size(self, bytes: u64) -> Self {
+        *self.read_cache.lock() = ChunkCache::new(bytes);
+        self
+    }
+
+    /// Download up to `chunks` chunks ahead of sequential readers, as many
+    /// as fit in half the read cache. Zero disables readahead.
+    pub fn with_readahead_chunks(mut self, chunks: u64) -> Self {
+        self.readahead_chunks = chunks;
+        self
+    }
+
+    /// Ask renterd for a file's size and modification time when its
+    /// attributes are read and were last checked over `interval` ago,
+    /// picking up changes made through other clients
+    pub fn with_attr_refresh(mut self, interval: Duration) -> Self {
+        self.attr_refresh = Some(interval);
+        self
+    }
+
+    /// Keep a file's local attributes over renterd's for `window` after
+    /// its changes are uploaded, as renterd may still answer from a
+    /// replica that hasn't seen them
+    pub fn with_trust_local(mut self, window: Duration) -> Self {
+        self.trust_local = window;
+        self
+    }
+
+    /// Encrypt content with a key derived from `passphrase`. The salt is
+    /// read from the key object in the client's bucket, or created there
+    /// on first use; a passphrase that doesn't match it is an error.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Result<Self> {
+        let cipher = match self.client.download(KEY_OBJECT) {
+            Ok(data) => {
+                let key: KeyFile = serde_json::from_slice(&data).map_err(|e| {
+                    SiaFuseError::Config(format!("invalid key object {}: {}", KEY_OBJECT, e))
+                })?;
+                if key.version != 1 {
+                    return Err(SiaFuseError::Config(format!(
+                        "unsupported key object version {}",
+                        key.version
+                    )));
+                }
+                let cipher = Cipher::derive(passphrase, &key.salt)?;
+                if cipher.open_all(&key.check).ok().as_deref() != Some(KEY_CHECK) {
+                    return Err(SiaFuseError::Config(format!(
+                        "wrong passphrase for bucket '{}'",
+                        self.client.bucket()
+                    )));
+                }
+                cipher
+            }
+            Err(SiaFuseError::NotFound) => {
+                if !self.remote.lock().is_empty() {
+                    tracing::warn!("objects stored before encryption was set up can't be read");
+                }
+                let salt = Cipher::generate_salt();
+                let cipher = Cipher::derive(passphrase, &salt)?;
+                let key = KeyFile {
+                    version: 1,
+                    salt: salt.to_vec(),
+                    check: cipher.seal(KEY_CHECK, 0, true),
+                };
+                let data = serde_json::to_vec(&key)
+                    .map_err(|e| SiaFuseError::Config(format!("cannot encode key: {}", e)))?;
+                self.client.upload(KEY_OBJECT, data)?;
+                tracing::info!(
+                    "created encryption key in bucket '{}'",
+                    self.client.bucket()
+                );
+                cipher
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Objects were listed with their encrypted sizes
+        let remote: Vec<Inode> = self.remote.lock().iter().copied().collect();
+        let mut files = self.cache.files.write();
+        for ino in remote {
+            if let Some(file) = files.get_mut(&ino) {
+                match crypt::plain_len(file.attr.size) {
+                    Some(size) => self.cache.resize(file, size),
+                    None => tracing::warn!("inode {} is too short to be encrypted", ino),
+                }
+            }
+        }
+        drop(files);
+
+        self.cipher = Some(Arc::new(cipher));
+        let chunk_size = self.chunk_size;
+        Ok(self.with_chunk_size(chunk_size))
+    }
+
+    /// Keep the key and probe objects' names free at the top of each bucket
+    fn check_reserved(&self, parent: Inode, name: &str) -> Result<()> {
+        let path = format!("{}/{}", self.key_of(parent)?.trim_end_matches('/'), name);
+        match self.remote(&path) {
+            Ok((_, object)) if object == KEY_OBJECT || object == PROBE_OBJECT => {
+                Err(SiaFuseError::NotPermitted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create the directory at `path` and any missing parents
+    fn ensure_dir(&self, path: &str) -> Result<Inode> {
+        let mkdir = |dir, name: &str| {
+            self.cache
+                .create_dir(dir, name.to_string(), 0o755, Owner::current())
+        };
+        walk_dirs(&self.cache, ROOT_INODE, path, Some(&mkdir))
+    }
+
+    /// Add a file whose content is still only in renterd
+    fn add_remote_file(&self, key: &str, size: u64, mod_time: Option<DateTime<Utc>>) -> Result<()> {
+        let (dir, name) = split_path(key)?;
+        let dir = self.ensure_dir(dir)?;
+        let attr = self
+            .cache
+            .create_file(dir, name.to_string(), 0o644, Owner::current())?;
+
+        self.freshness.lock().insert(
+            attr.ino,
+            Freshness {
+                reported: Some((size, mod_time)),
+                checked: Some(Instant::now()),
+                uploaded: None,
+            },
+        );
+        if let Some(file) = self.cache.files.write().get_mut(&attr.ino) {
+            self.cache.resize(file, size);
+            if let Some(mod_time) = mod_time {
+                file.attr.mtime = mod_time;
+                file.attr.ctime = mod_time;
+                file.last_synced = mod_time;
+            }
+        }
+        self.remote.lock().insert(attr.ino);
+        Ok(())
+    }
+
+    /// Path of an inode, which `remote` turns into a bucket and object key
+    fn key_of(&self, ino: Inode) -> Result<String> {
+        let path = self.cache.path_of(ino).ok_or(SiaFuseError::NotFound)?;
+        path.to_str().map(str::to_string).ok_or_else(|| {
+            SiaFuseError::InvalidArgument(format!("{} is not valid UTF-8", path.display()))
+        })
+    }
+
+    /// Client for the bucket holding `path`, and the object key within it.
+    /// With buckets as top-level directories, the first component names
+    /// the bucket and the root itself holds no objects.
+    fn remote<'a>(&'a self, path: &'a str) -> Result<(Cow<'a, RenterdClient>, &'a str)> {
+        if !self.buckets {
+            return Ok((Cow::Borrowed(&self.client), path));
+        }
+        let path = path.strip_prefix('/').unwrap_or(path);
+        match path.find('/') {
+            Some(end) => {
+                let client = self.client.clone().with_bucket(&path[..end]);
+                Ok((Cow::Owned(client), &path[end..]))
+            }
+            None => Err(SiaFuseError::NotPermitted),
+        }
+    }
+
+    /// Whether an entry is directly under the root, where only buckets live
+    fn at_bucket_level(&self, parent: Inode) -> bool {
+        self.buckets && parent == ROOT_INODE
+    }
+
+    /// Whether a file has lost its last name but is still open
+    fn is_unlinked(&self, ino: Inode) -> bool {
+        self.cache.get_attr(ino).is_some_and(|attr| attr.nlink == 0)
+    }
+
+    /// Download a file's content if that hasn't happened yet. The download
+    /// runs unlocked, so a slow or throttled one doesn't hold up others.
+    fn fetch(&self, ino: Inode) -> Result<()> {
+        if !self.remote.lock().contains(&ino) {
+            return Ok(());
+        }
+
+        let key = self.key_of(ino)?;
+        tracing::debug!("downloading {}", key);
+        let (client, object) = self.remote(&key)?;
+        let content = client.download(object)?;
+        let content = match &self.cipher {
+            Some(cipher) => cipher.open_all(&content)?,
+            None => content,
+        };
+
+        // Downloaded meanwhile, or truncated without needing the content
+        let mut remote = self.remote.lock();
+        if !remote.contains(&ino) {
+            return Ok(());
+        }
+        self.dedup.lock().insert(&key, blake3::hash(&content));
+
+        if let Some(file) = self.cache.files.write().get_mut(&ino) {
+            let content = Content::from_vec(content);
+            self.cache.resize(file, content.len());
+            *file.content.write() = content;
+        }
+        remote.remove(&ino);
+        drop(remote);
+        self.forget_chunks(ino);
+        Ok(())
+    }
+
+    /// Drop a file's cached chunks and stop reading ahead in it, e.g. once
+    /// its whole content is local
+    fn forget_chunks(&self, ino: Inode) {
+        self.readahead.cancel(ino);
+        self.read_cache.lock().remove(ino);
+    }
+
+    /// Read `size` bytes at `offset` of a file not downloaded yet, going
+    /// through the read cache a chunk at a time
+    fn read_chunks(&self, ino: Inode, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let file_size = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?.size;
+        let end = offset.saturating_add(size).min(file_size);
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut downloaded = false;
+
+        let chunk_size = self.chunk_size_of(ino);
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / chunk_size;
+            let chunk_start = index * chunk_size;
+            self.readahead.claim(ino, index);
+            let cached = self.read_cache.lock().get(ino, index);
+            let chunk = match cached {
+                Some(chunk) => chunk,
+                None => {
+                    downloaded = true;
+                    self.download_chunk(ino, index, file_size)?
+                }
+            };
+
+            // The object may be shorter than listed
+            let from = (pos - chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(chunk.len());
+            if from >= to {
+                break;
+            }
+            data.extend_from_slice(&chunk[from..to]);
+            pos += (to - from) as u64;
+        }
+
+        let counter = if downloaded { &self.misses } else { &self.hits };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    /// Queue the chunks following `offset` of a file not downloaded yet
+    /// that aren't cached already
+    fn schedule_readahead(&self, ino: Inode, offset: u64) -> Result<()> {
+        let chunk_size = self.chunk_size_of(ino);
+        let budget_chunks = {
+            let read_cache = self.read_cache.lock();
+            if !read_cache.is_enabled() {
+                return Ok(());
+            }
+            read_cache.budget() / 2 / chunk_size
+        };
+        let file_size = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?.size;
+        let key = self.key_of(ino)?;
+        let (client, object) = self.remote(&key)?;
+
+        let first = offset.div_ceil(chunk_size);
+        let count = self.readahead_chunks.min(budget_chunks);
+        for index in first..first + count {
+            let chunk_start = index * chunk_size;
+            if chunk_start >= file_size {
+                break;
+            }
+            if self.read_cache.lock().contains(ino, index) {
+                continue;
+            }
+            self.readahead.schedule(Job {
+                ino,
+                index,
+                client: client.clone().into_owned(),
+                cipher: self.cipher.clone(),
+                key: object.to_string(),
+                offset: chunk_start,
+                len: chunk_size.min(file_size - chunk_start),
+                file_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Download chunk `index` of a file and add it to the read cache
+    fn download_chunk(&self, ino: Inode, index: u64, file_size: u64) -> Result<Arc<Vec<u8>>> {
+        let key = self.key_of(ino)?;
+        let (client, object) = self.remote(&key)?;
+        let chunk_size = self.chunk_size_of(ino);
+        let offset = index * chunk_size;
+        let len = chunk_size.min(file_size - offset);
+        tracing::debug!("downloading chunk {} of {} ({} bytes)", index, key, len);
+
+        let chunk = download_range(
+            &client,
+            self.cipher.as_deref(),
+            object,
+            offset,
+            len,
+            file_size,
+        )?;
+        let chunk = Arc::new(chunk);
+        self.read_cache.lock().insert(ino, index, chunk.clone());
+        Ok(chunk)
+    }
+
+    /// Download before reading. The read interface can't carry an error,
+    /// so a failed download is logged and the read fails with EIO.
+    fn fetch_for_read(&self, ino: Inode) -> bool {
+        let counter = if self.remote.lock().contains(&ino) {
+            &self.misses
+        } else {
+            &self.hits
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        match self.fetch(ino) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("failed to download inode {}: {}", ino, e);
+                false
+            }
+        }
+    }
+
+    /// Compare a file's size and mtime with renterd's if they were last
+    /// checked over `interval` ago, taking renterd's if the object changed.
+    /// Unsynced files, and files within the trust window after an upload,
+    /// keep their local attributes. Returns whether they were replaced.
+    fn refresh_attr(&self, ino: Inode, attr: &FileAttr, interval: Duration) -> bool {
+        if self.dirty.lock().contains(&ino) {
+            return false;
+        }
+        {
+            let mut freshness = self.freshness.lock();
+            let freshness = freshness.entry(ino).or_default();
+            let within = |at: Option<Instant>, window| at.is_some_and(|at| at.elapsed() < window);
+            if within(freshness.uploaded, self.trust_local) || within(freshness.checked, interval) {
+                return false;
+            }
+            freshness.checked = Some(Instant::now());
+        }
+
+        let Ok(key) = self.key_of(ino) else {
+            return false;
+        };
+        let info = match self
+            .remote(&key)
+            .and_then(|(client, object)| client.object_info(object))
+        {
+            Ok(info) => info,
+            Err(e) => {
+                tracing::debug!("not refreshing attributes of {}: {}", key, e);
+                return false;
+            }
+        };
+        let size = match self.cipher {
+            Some(_) => crypt::plain_len(info.size).unwrap_or(0),
+            None => info.size,
+        };
+
+        let reported = (info.size, info.mod_time);
+        let previous = match self.freshness.lock().get_mut(&ino) {
+            Some(freshness) => freshness.reported.replace(reported),
+            None => return false, // Unlinked meanwhile
+        };
+        // Without an earlier report, e.g. after an upload, only the size
+        // tells whether someone else changed the object since
+        let changed = match previous {
+            Some(previous) => previous != reported,
+            None => size != attr.size,
+        };
+        if !changed || self.dirty.lock().contains(&ino) {
+            return false;
+        }
+
+        tracing::debug!("{} changed in renterd, now {} bytes", key, size);
+        if let Some(file) = self.cache.files.write().get_mut(&ino) {
+            self.cache.resize(file, size);
+            if let Some(mod_time) = info.mod_time {
+                file.attr.mtime = mod_time;
+                file.attr.ctime = mod_time;
+                file.last_synced = mod_time;
+            }
+            *file.content.write() = Content::default();
+        }
+        self.remote.lock().insert(ino);
+        self.forget_chunks(ino);
+        self.dedup.lock().remove(&key);
+        true
+    }
+
+    /// Upload a file if it changed since its last upload
+    fn upload(&self, ino: Inode) -> Result<()> {
+        if !self.dirty.lock().contains(&ino) {
+            return Ok(());
+        }
+
+        // Unlinked but still open: there is no object to write it to
+        if self.is_unlinked(ino) {
+            self.dirty.lock().remove(&ino);
+            return Ok(());
+        }
+
+        self.check_writable()?;
+        let key = self.key_of(ino)?;
+        let hash = self.cache.checksum(ino).ok_or(SiaFuseError::NotFound)?;
+        let size = self.cache.get_attr(ino).map_or(0, |attr| attr.size);
+        let pending = self.pending.lock().remove(&ino);
+
+        if self.deduplicate(ino, &key, hash) {
+            if let Some(PendingUpload::Parts(upload)) = pending {
+                self.abort(upload);
+            }
+        } else {
+            let result = match pending {
+                // Encrypted content needs a last part to seal as its end
+                Some(PendingUpload::Parts(upload))
+                    if upload.key == key && (self.cipher.is_none() || size > upload.uploaded) =>
+                {
+                    self.complete(ino, upload)
+                }
+                pending => {
+                    // Renamed since the upload started
+                    if let Some(PendingUpload::Parts(upload)) = pending {
+                        self.abort(upload);
+                    }
+                    let content = self.cache.read_all(ino).ok_or(SiaFuseError::NotFound)?;
+                    tracing::debug!("uploading {} ({} bytes)", key, content.len());
+                    let content = match &self.cipher {
+                        Some(cipher) => cipher.seal(&content, 0, true),
+                        None => content,
+                    };
+                    self.remote(&key)
+                        .and_then(|(client, object)| client.upload(object, content))
+                }
+            };
+            self.upload_done(&key, result.as_ref().err());
+            result.map_err(upload_error)?;
+            self.dedup.lock().insert(&key, hash);
+        }
+
+        let mut freshness = self.freshness.lock();
+        let freshness = freshness.entry(ino).or_default();
+        freshness.reported = None;
+        freshness.uploaded = Some(Instant::now());
+        self.dirty.lock().remove(&ino);
+        Ok(())
+    }
+
+    /// Store content renterd already has without uploading it: skip it if
+    /// `key` still holds it, or copy it from another object that does.
+    /// Returns whether the content is stored.
+    fn deduplicate(&self, ino: Inode, key: &str, hash: blake3::Hash) -> bool {
+        let size = self.cache.get_attr(ino).map_or(0, |attr| attr.size);
+        if size == 0 {
+            return false;
+        }
+
+        let source = {
+            let mut dedup = self.dedup.lock();
+            if dedup.hash_of(key) == Some(hash) {
+                tracing::debug!("{} is unchanged, not uploading", key);
+                dedup.add_saved(size);
+                return true;
+            }
+            match dedup.source_for(&hash, key) {
+                Some(source) => source,
+                None => return false,
+            }
+        };
+
+        match self.copy_object(&source, key) {
+            Ok(()) => {
+                tracing::debug!("copied {} to {} instead of uploading", source, key);
+                let mut dedup = self.dedup.lock();
+                dedup.insert(key, hash);
+                dedup.add_saved(size);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("failed to copy {} to {}: {}", source, key, e);
+                if matches!(e, SiaFuseError::NotFound) {
+                    self.dedup.lock().remove(&source);
+                }
+                false
+            }
+        }
+    }
+
+    /// Copy the object at path `from` to path `to`, possibly across buckets
+    fn copy_object(&self, from: &str, to: &str) -> Result<()> {
+        let (source, from) = self.remote(from)?;
+        let (client, to) = self.remote(to)?;
+        client.copy_from(source.bucket(), from, to)
+    }
+
+    /// Switch a file to a whole upload if bytes from `offset` on may
+    /// already be uploaded as parts
+    fn check_rewrite(&self, ino: Inode, offset: u64) {
+        let rewritten = match self.pending.lock().get(&ino) {
+            Some(PendingUpload::Parts(upload)) => offset < upload.uploaded,
+            Some(PendingUpload::Sending) => true,
+            Some(PendingUpload::Whole) | None => false,
+        };
+        if rewritten {
+            self.upload_whole(ino);
+        }
+    }
+
+    /// Send a file whole on the next sync instead of in parts
+    fn upload_whole(&self, ino: Inode) {
+        let previous = self.pending.lock().insert(ino, PendingUpload::Whole);
+        if let Some(PendingUpload::Parts(upload)) = previous {
+            self.abort(upload);
+        }
+    }
+
+    /// After writing `offset..end`, upload the chunks it completed. The
+    /// upload is taken out of `pending` while parts go out, so other files'
+    /// writes and syncs don't wait for the network.
+    fn upload_chunks(&self, ino: Inode, offset: u64, end: u64) {
+        if self.is_unlinked(ino) {
+            return;
+        }
+        let upload = {
+            let mut pending = self.pending.lock();
+            match pending.insert(ino, PendingUpload::Sending) {
+                None => None,
+                Some(PendingUpload::Parts(upload)) => Some(upload),
+                // Going up whole, or parts already on their way
+                Some(other) => {
+                    pending.insert(ino, other);
+                    return;
+                }
+            }
+        };
+
+        let state = self.send_chunks(ino, upload, offset, end);
+
+        let mut pending = self.pending.lock();
+        if let Some(PendingUpload::Sending) = pending.get(&ino) {
+            match state {
+                Some(state) => pending.insert(ino, state),
+                None => pending.remove(&ino),
+            };
+            return;
+        }
+        // Taken over meanwhile, e.g. uploaded whole, rewritten or deleted
+        drop(pending);
+        if let Some(PendingUpload::Parts(upload)) = state {
+            self.abort(upload);
+        }
+    }
+
+    /// Upload the chunks of `offset..end` that are complete as parts of
+    /// `upload`, starting one if needed. Returns how the file's content
+    /// gets to renterd from now on.
+    fn send_chunks(
+        &self,
+        ino: Inode,
+        mut upload: Option<MultipartUpload>,
+        offset: u64,
+        end: u64,
+    ) -> Option<PendingUpload> {
+        // Parts of an upload not yet assembled can be replaced, so
+        // rewritten bytes only send the parts holding them again
+        if let Some(parts) = upload.as_mut().filter(|u| offset < u.uploaded) {
+            if let Err(e) = self.replace_parts(ino, parts, offset, end) {
+                tracing::warn!("replacing parts of inode {} failed: {}", ino, e);
+                if let Some(upload) = upload {
+                    self.abort(upload);
+                }
+                return Some(PendingUpload::Whole);
+            }
+        }
+        // An encrypted chunk is only sent once a byte follows it, as the
+        // chunk ending the file is sealed differently
+        let uploaded = upload.as_ref().map_or(0, |u| u.uploaded);
+        let chunk_size = self.chunk_size_of(ino);
+        let chunk = chunk_size + self.cipher.is_some() as u64;
+        if end < uploaded + chunk {
+            return upload.map(PendingUpload::Parts);
+        }
+
+        let upload = match upload {
+            Some(upload) => Ok(upload),
+            None => self.key_of(ino).and_then(|key| {
+                let id = {
+                    let (client, object) = self.remote(&key)?;
+                    client.create_multipart(object)?
+                };
+                Ok(MultipartUpload {
+                    key,
+                    id,
+                    parts: Vec::new(),
+                    uploaded: 0,
+                })
+            }),
+        };
+        let result = upload.and_then(|mut upload| {
+            while end >= upload.uploaded + chunk {
+                if let Err(e) = self.upload_part(ino, &mut upload, chunk_size, false) {
+                    self.abort(upload);
+                    return Err(e);
+                }
+            }
+            Ok(upload)
+        });
+
+        // A failed part isn't a failed write: the data is in the cache and
+        // the whole file goes up on the next sync instead
+        match result {
+            Ok(upload) => {
+                self.upload_done(&upload.key, None);
+                Some(PendingUpload::Parts(upload))
+            }
+            Err(e) => {
+                tracing::warn!("chunked upload of inode {} failed: {}", ino, e);
+                if let Ok(key) = self.key_of(ino) {
+                    self.upload_done(&key, Some(&e));
+                }
+                Some(PendingUpload::Whole)
+            }
+        }
+    }
+
+    /// Upload the next `len` bytes of a file as a part of `upload`, the
+    /// `last` one if set
+    fn upload_part(
+        &self,
+        ino: Inode,
+        upload: &mut MultipartUpload,
+        len: u64,
+        last: bool,
+    ) -> Result<()> {
+        let data = self
+            .cache
+            .read(ino, upload.uploaded as usize, len as usize)
+            .ok_or(SiaFuseError::NotFound)?;
+        let part_number = upload.parts.len() as u32 + 1;
+        tracing::debug!(
+            "uploading part {} of {} ({} bytes)",
+            part_number,
+            upload.key,
+            data.len()
+        );
+        let data = match &self.cipher {
+            Some(cipher) => cipher.seal(&data, upload.uploaded / crypt::BLOCK, last),
+            None => data,
+        };
+
+        let (client, object) = self.remote(&upload.key)?;
+        let part = client.upload_part(object, &upload.id, part_number, data)?;
+        upload.parts.push(part);
+        upload.uploaded += len;
+        Ok(())
+    }
+
+    /// Upload again the parts of `upload` holding bytes `offset..end`,
+    /// replacing the ones renterd has
+    fn replace_parts(
+        &self,
+        ino: Inode,
+        upload: &mut MultipartUpload,
+        offset: u64,
+        end: u64,
+    ) -> Result<()> {
+        let chunk_size = self.chunk_size_of(ino);
+        let end = end.min(upload.uploaded);
+        for index in offset / chunk_size..end.div_ceil(chunk_size) {
+            let start = index * chunk_size;
+            let data = self
+                .cache
+                .read(ino, start as usize, chunk_size as usize)
+                .ok_or(SiaFuseError::NotFound)?;
+            let part_number = index as u32 + 1;
+            tracing::debug!("replacing part {} of {}", part_number, upload.key);
+            let data = match &self.cipher {
+                Some(cipher) => cipher.seal(&data, start / crypt::BLOCK, false),
+                None => data,
+            };
+
+            let (client, object) = self.remote(&upload.key)?;
+            upload.parts[index as usize] =
+                client.upload_part(object, &upload.id, part_number, data)?;
+        }
+        Ok(())
+    }
+
+    /// Upload whatever follows the last full chunk and assemble the object
+    fn complete(&self, ino: Inode, mut upload: MultipartUpload) -> Result<()> {
+        let size = self.cache.get_attr(ino).map_or(0, |attr| attr.size);
+        let result = match size.checked_sub(upload.uploaded) {
+            Some(0) if !upload.parts.is_empty() => Ok(()),
+            Some(rest) => self.upload_part(ino, &mut upload, rest, true),
+            // Shrunk below the uploaded parts without going through truncate
+            None => Err(SiaFuseError::Backend(format!(
+                "{} shrank during upload",
+                upload.key
+            ))),
+        };
+        let result = result.and_then(|()| {
+            let (client, object) = self.remote(&upload.key)?;
+            client.complete_multipart(object, &upload.id, &upload.parts)
+        });
+
+        match result {
+            Ok(()) => {
+                tracing::debug!("uploaded {} in {} parts", upload.key, upload.parts.len());
+                Ok(())
+            }
+            Err(e) => {
+                self.abort(upload);
+                Err(e)
+            }
+        }
+    }
+
+    /// Give up on a multipart upload. Failures only leave stray parts
+    /// behind in renterd, so they are logged.
+    fn abort(&self, upload: MultipartUpload) {
+        let result = self
+            .remote(&upload.key)
+            .and_then(|(client, object)| client.abort_multipart(object, &upload.id));
+        if let Err(e) = result {
+            tracing::warn!("failed to abort upload of {}: {}", upload.key, e);
+        }
+    }
+
+    /// Track whether renterd takes uploads after one to `key` finished
+    /// with `error`, turning the mount read-only once too many in a row
+    /// were refused
+    fn upload_done(&self, key: &str, error: Option<&SiaFuseError>) {
+        let mut health = self.write_health.lock();
+        let Some(e) = error.filter(|e| refuses_uploads(e)) else {
+            if error.is_none() {
+                health.refused = 0;
+            }
+            return;
+        };
+
+        health.refused += 1;
+        if health.refused >= MAX_REFUSED_UPLOADS && health.probe.is_none() {
+            tracing::error!(
+                "renterd refused {} uploads in a row ({}); the mount is read-only \
+                 until it takes uploads again",
+                health.refused,
+                e
+            );
+            let probe = match bucket_of(key).filter(|_| self.buckets) {
+                Some(bucket) => format!("/{}{}", bucket, PROBE_OBJECT),
+                None => PROBE_OBJECT.to_string(),
+            };
+            health.probe = Some((probe, Instant::now()));
+        }
+    }
+
+    /// Once renterd is back after being down, check that the mounted
+    /// buckets still exist and upload the changes held meanwhile
+    fn resume_if_reconnected(&self) {
+        if !self.client.take_reconnected() {
+            return;
+        }
+        if let Err(e) = self.check_root() {
+            tracing::error!("failed to check the root after reconnecting: {}", e);
+        }
+        let held = self.dirty.lock().len();
+        match self.sync_all() {
+            Ok(()) => tracing::info!("uploaded {} files held while renterd was down", held),
+            Err(e) => tracing::error!("failed to upload files held while renterd was down: {}", e),
+        }
+    }
+
+    /// Warn about mounted buckets renterd no longer has
+    fn check_root(&self) -> Result<()> {
+        let known: HashSet<String> = self.client.list_buckets()?.into_iter().collect();
+        let mounted = if self.buckets {
+            self.cache
+                .read_dir(ROOT_INODE)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect()
+        } else {
+            vec![self.client.bucket().to_string()]
+        };
+        for bucket in mounted.iter().filter(|b| !known.contains(*b)) {
+            tracing::warn!("bucket '{}' is gone from renterd", bucket);
+        }
+        Ok(())
+    }
+
+    /// Fail with `ReadOnly` while renterd refuses uploads, checking again
+    /// with a probe object every `WRITE_PROBE_INTERVAL`
+    fn check_writable(&self) -> Result<()> {
+        self.resume_if_reconnected();
+        let probe = {
+            let mut health = self.write_health.lock();
+            match &mut health.probe {
+                None => return Ok(()),
+                Some((probe, tried)) if tried.elapsed() >= WRITE_PROBE_INTERVAL => {
+                    *tried = Instant::now();
+                    probe.clone()
+                }
+                Some(_) => return Err(SiaFuseError::ReadOnly),
+            }
+        };
+
+        let result = self.remote(&probe).and_then(|(client, object)| {
+            client.upload(object, Vec::new())?;
+            client.delete(object)
+        });
+        match result {
+            Ok(()) => {
+                tracing::warn!("renterd takes uploads again; the mount is writable");
+                *self.write_health.lock() = WriteHealth::default();
+                Ok(())
+            }
+            Err(e) => {
+                tracing::debug!("renterd still refuses uploads: {}", e);
+                Err(SiaFuseError::ReadOnly)
+            }
+        }
+    }
+
+    /// Drop a file's multipart upload, e.g. because the file is gone
+    fn discard_pending(&self, ino: Inode) {
+        if let Some(PendingUpload::Parts(upload)) = self.pending.lock().remove(&ino) {
+            self.abort(upload);
+        }
+    }
+}
+
+/// Download `len` bytes at `offset` of the file stored at `key`, which is
+/// `file_size` bytes long. With a cipher `offset` must start a block, and
+/// the blocks covering the range are downloaded and decrypted.
+pub(super) fn download_range(
+    client: &RenterdClient,
+    cipher: Option<&Cipher>,
+    key: &str,
+    offset: u64,
+    len: u64,
+    file_size: u64,
+) -> Result<Vec<u8>> {
+    let Some(cipher) = cipher else {
+        return client.download_range(key, offset, len);
+    };
+    let (sealed_offset, sealed_len) = crypt::sealed_range(offset, len);
+    let sealed = client.download_range(key, sealed_offset, sealed_len)?;
+    cipher.open(
+        &sealed,
+        offset / crypt::BLOCK,
+        crypt::block_count(file_size),
+    )
+}
+
+/// Whether an upload failure means renterd can't store data at the
+/// moment, e.g. without contracts or funds, rather than a bad request
+fn refuses_uploads(e: &SiaFuseError) -> bool {
+    matches!(e, SiaFuseError::Http { status, .. } if *status >= 500)
+}
+
+/// The file exists locally, so a 404 while uploading means renterd lost
+/// the bucket or upload; report it as an I/O failure rather than ENOENT
+fn upload_error(e: SiaFuseError) -> SiaFuseError {
+    match e {
+        SiaFuseError::NotFound => SiaFuseError::Backend("upload target not found".to_string()),
+        e => e,
+    }
+}
+
+impl Storage for SiaStorage {
+    fn now(&self) -> DateTime<Utc> {
+        self.cache.now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        let read_cache = self.read_cache.lock();
+        let dirty = self.dirty.lock();
+        StorageStats {
+            cache_hits: self.hits.load(Ordering::Relaxed),
+            cache_misses: self.misses.load(Ordering::Relaxed),
+            pending_uploads: dirty.len() as u64,
+            dirty_bytes: self.cache.total_size(dirty.iter()),
+            dedup_saved_bytes: self.dedup.lock().saved_bytes(),
+            backend_retries: self.client.retries(),
+            read_cache_hits: read_cache.hits(),
+            read_cache_misses: read_cache.misses(),
+            read_cache_bytes: read_cache.bytes(),
+            read_only: self.write_health.lock().probe.is_some(),
+            ..self.cache.stats()
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        let attr = self.cache.get_attr(ino)?;
+        match self.attr_refresh {
+            Some(interval)
+                if attr.kind == FileKind::File && self.refresh_attr(ino, &attr, interval) =>
+            {
+                self.cache.get_attr(ino)
+            }
+            _ => Some(attr),
+        }
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        self.cache.set_attr(ino, attr)
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        self.cache.touch(ino)
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.cache.last_synced(ino)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        self.cache.mark_synced(ino)
+    }
+
+    fn sync(&self, ino: Inode) -> Result<()> {
+        self.upload(ino)?;
+        self.cache.mark_synced(ino)
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        self.resume_if_reconnected();
+        // One lock at a time: the read cache is never locked under `remote`
+        let chunked = self.read_cache.lock().is_enabled();
+        if chunked && self.remote.lock().contains(&ino) {
+            return match self.read_chunks(ino, offset as u64, size as u64) {
+                Ok(data) => {
+                    f(&data);
+                    true
+                }
+                Err(e) => {
+                    tracing::error!("failed to download inode {}: {}", ino, e);
+                    false
+                }
+            };
+        }
+        self.fetch_for_read(ino) && self.cache.read_with(ino, offset, size, f)
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        if !self.fetch_for_read(ino) {
+            return None;
+        }
+        self.cache.read_all(ino)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        self.check_writable()?;
+        self.fetch(ino)?;
+        // A write past the end leaves a hole. The cache stores only the
+        // touched blocks, but parts can't skip the gap, so rather than
+        // uploading zero-filled chunks now the file goes up whole on sync.
+        let hole = self
+            .cache
+            .get_attr(ino)
+            .is_some_and(|attr| offset as u64 > attr.size);
+        let written = self.cache.write(ino, offset, data)?;
+        self.dirty.lock().insert(ino);
+        if hole {
+            self.upload_whole(ino);
+        } else {
+            self.upload_chunks(ino, offset as u64, (offset + written) as u64);
+        }
+        Ok(written)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        self.check_writable()?;
+        self.fetch(ino)?;
+        let (offset, written) = self.cache.append(ino, data)?;
+        self.dirty.lock().insert(ino);
+        self.upload_chunks(ino, offset as u64, (offset + written) as u64);
+        Ok((offset, written))
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        self.check_writable()?;
+        // Nothing of the old content survives truncation to zero
+        if size == 0 {
+            self.remote.lock().remove(&ino);
+            self.forget_chunks(ino);
+        } else {
+            self.fetch(ino)?;
+        }
+        self.cache.truncate(ino, size)?;
+        self.dirty.lock().insert(ino);
+        self.check_rewrite(ino, size);
+        Ok(())
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        self.check_writable()?;
+        self.fetch(ino)?;
+        self.cache.punch_hole(ino, offset, len)?;
+        self.dirty.lock().insert(ino);
+        self.check_rewrite(ino, offset);
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.check_writable()?;
+        if self.at_bucket_level(parent) {
+            return Err(SiaFuseError::NotPermitted);
+        }
+        self.check_reserved(parent, &name)?;
+        let attr = self.cache.create_file(parent, name, perm, owner)?;
+        self.dirty.lock().insert(attr.ino);
+        Ok(attr)
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        self.check_writable()?;
+        if self.at_bucket_level(parent) {
+            check_bucket_name(&name)?;
+            if self.cache.lookup(parent, &name).is_some() {
+                return Err(SiaFuseError::AlreadyExists);
+            }
+            self.client.create_bucket(&name)?;
+            return self.cache.create_dir(parent, name, perm, owner);
+        }
+
+        self.check_reserved(parent, &name)?;
+        let attr = self.cache.create_dir(parent, name, perm, owner)?;
+        let key = format!("{}/", self.key_of(attr.ino)?);
+        let (client, object) = self.remote(&key)?;
+        client.upload(object, Vec::new())?;
+        Ok(attr)
+    }
+
+    fn create_symlink(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _target: &str,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::NotPermitted)
+    }
+
+    fn create_special(
+        &self,
+        _parent: Inode,
+        _name: String,
+        _kind: FileKind,
+        _perm: u16,
+        _rdev: u32,
+        _owner: Owner,
+    ) -> Result<FileAttr> {
+        Err(SiaFuseError::NotPermitted)
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        self.cache.readlink(ino)
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        self.cache.read_dir(ino)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        self.cache.file_paths()
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        if !self.fetch_for_read(ino) {
+            return None;
+        }
+        self.cache.checksum(ino)
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        self.cache.allocated_size(ino)
+    }
+
+    // Content not downloaded yet would look like one big hole, and whoever
+    // is seeking for data is about to read it anyway
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.fetch(ino)?;
+        self.cache.seek_data(ino, offset)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.fetch(ino)?;
+        self.cache.seek_hole(ino, offset)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        let attr = self.cache.lookup(parent, name)?;
+        match self.attr_refresh {
+            Some(_) => self.get_attr(attr.ino),
+            None => Some(attr),
+        }
+    }
+
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let ino = self
+            .cache
+            .lookup(parent, name)
+            .ok_or(SiaFuseError::NotFound)?
+            .ino;
+        let key = self.key_of(ino)?;
+
+        // An open file stays readable, so it needs its content first
+        if self.cache.is_held(ino) {
+            self.fetch(ino)?;
+        }
+        self.cache.unlink(parent, name)?;
+
+        self.remote.lock().remove(&ino);
+        self.forget_chunks(ino);
+        self.dirty.lock().remove(&ino);
+        self.discard_pending(ino);
+        self.stored_objects.lock().remove(&ino);
+        if self.cache.get_attr(ino).is_none() {
+            self.chunk_sizes.lock().remove(&ino);
+            self.freshness.lock().remove(&ino);
+        }
+        self.dedup.lock().remove(&key);
+        let (client, object) = self.remote(&key)?;
+        client.delete(object)
+    }
+
+    fn hold(&self, ino: Inode) {
+        self.cache.hold(ino)
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        self.cache.release(ino)?;
+        if !self.cache.is_held(ino) {
+            self.readahead.cancel(ino);
+        }
+        if self.cache.get_attr(ino).is_none() {
+            self.dirty.lock().remove(&ino);
+            self.discard_pending(ino);
+            self.chunk_sizes.lock().remove(&ino);
+            self.freshness.lock().remove(&ino);
+        }
+        Ok(())
+    }
+
+    fn readahead(&self, ino: Inode, offset: u64) {
+        if self.readahead_chunks == 0 || !self.remote.lock().contains(&ino) {
+            return;
+        }
+        if let Err(e) = self.schedule_readahead(ino, offset) {
+            tracing::debug!("not reading ahead in inode {}: {}", ino, e);
+        }
+    }
+
+    fn link(&self, _ino: Inode, _newparent: Inode, _newname: &str) -> Result<FileAttr> {
+        Err(SiaFuseError::NotPermitted)
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        self.cache.path_of(ino)
+    }
+
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let ino = self
+            .cache
+            .lookup(parent, name)
+            .ok_or(SiaFuseError::NotFound)?
+            .ino;
+        let key = format!("{}/", self.key_of(ino)?);
+        self.cache.rmdir(parent, name)?;
+        if self.at_bucket_level(parent) {
+            return self.client.delete_bucket(name);
+        }
+        let (client, object) = self.remote(&key)?;
+        client.delete(object)
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        self.check_writable()?;
+        let attr = self
+            .cache
+            .lookup(parent, name)
+            .ok_or(SiaFuseError::NotFound)?;
+        let from = self.key_of(attr.ino)?;
+
+        // Buckets can't be renamed, and objects only move within one
+        if self.at_bucket_level(parent) || self.at_bucket_level(newparent) {
+            return Err(SiaFuseError::NotPermitted);
+        }
+        if self.buckets && bucket_of(&from) != bucket_of(&self.key_of(newparent)?) {
+            return Err(SiaFuseError::CrossDevice);
+        }
+        self.check_reserved(newparent, newname)?;
+
+        // A replaced file that is still open stays readable
+        if let Some(target) = self.cache.lookup(newparent, newname) {
+            if self.cache.is_held(target.ino) {
+                self.fetch(target.ino)?;
+            }
+        }
+        self.cache.rename(parent, name, newparent, newname)?;
+        // Keys under a renamed directory change too
+        self.stored_objects.lock().clear();
+        let to = self.key_of(attr.ino)?;
+
+        // Files never uploaded have nothing to rename in renterd yet
+        let (from, to, prefix) = match attr.kind {
+            FileKind::Directory => (format!("{}/", from), format!("{}/", to), true),
+            _ => (from, to, false),
+        };
+        let (client, from_key) = self.remote(&from)?;
+        let (_, to_key) = self.remote(&to)?;
+        let result = client
+            .rename(from_key, to_key, prefix)
+            .inspect(|()| self.dedup.lock().rename(&from, &to, prefix));
+        match result {
+            Err(SiaFuseError::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        if self.cache.lookup(newparent, newname).is_some() {
+            return Err(SiaFuseError::AlreadyExists);
+        }
+        self.rename(parent, name, newparent, newname)
+    }
+
+    fn exchange(
+        &self,
+        _parent: Inode,
+        _name: &str,
+        _newparent: Inode,
+        _newname: &str,
+    ) -> Result<()> {
+        Err(SiaFuseError::InvalidArgument(
+            "renterd cannot exchange objects atomically".to_string(),
+        ))
+    }
+
+    fn chunk_size(&self, ino: Inode) -> Option<u64> {
+        self.cache.get_attr(ino)?;
+        Some(self.chunk_size_of(ino))
+    }
+
+    fn set_chunk_size(&self, ino: Inode, chunk_size: u64) -> Result<()> {
+        let attr = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        if attr.kind != FileKind::File {
+            return Err(SiaFuseError::InvalidArgument(
+                "only files have a chunk size".to_string(),
+            ));
+        }
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(SiaFuseError::InvalidArgument(format!(
+                "chunk size must be between {} and {} bytes",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            )));
+        }
+        // Chunks already uploaded or cached fix the size
+        let remote = self.remote.lock().contains(&ino);
+        if attr.size > 0 || remote || self.pending.lock().contains_key(&ino) {
+            return Err(SiaFuseError::Busy);
+        }
+        let chunk_size = self.whole_blocks(chunk_size);
+        self.chunk_sizes.lock().insert(ino, chunk_size);
+        Ok(())
+    }
+
+    fn dedup_refs(&self, ino: Inode) -> Option<u64> {
+        let key = self.key_of(ino).ok()?;
+        let dedup = self.dedup.lock();
+        let hash = dedup.hash_of(&key)?;
+        Some(dedup.refs(&hash) as u64)
+    }
+
+    fn stored_object(&self, ino: Inode) -> Result<Option<StoredObject>> {
+        let attr = self.cache.get_attr(ino).ok_or(SiaFuseError::NotFound)?;
+        if attr.kind != FileKind::File {
+            return Ok(None);
+        }
+        if let Some((fetched, object)) = self.stored_objects.lock().get(&ino) {
+            if fetched.elapsed() < STORED_OBJECT_TTL {
+                return Ok(Some(object.clone()));
+            }
+        }
+
+        let key = self.key_of(ino)?;
+        let (client, path) = self.remote(&key)?;
+        // Not uploaded yet
+        let health = match client.object_health(path) {
+            Err(SiaFuseError::NotFound) => return Ok(None),
+            result => result?,
+        };
+        let contract_set = client
+            .contract_set()
+            .inspect_err(|e| tracing::debug!("failed to get the contract set: {}", e))
+            .ok();
+        let object = StoredObject {
+            key: client.object_key(path),
+            health: health.health,
+            redundancy: health.redundancy,
+            contract_set,
+        };
+        self.stored_objects
+            .lock()
+            .insert(ino, (Instant::now(), object.clone()));
+        Ok(Some(object))
+    }
+
+    fn bandwidth(&self) -> Option<Arc<Bandwidth>> {
+        Some(self.client.bandwidth().clone())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        SiaStorage::check_writable(self)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        let dirty: Vec<Inode> = self.dirty.lock().iter().copied().collect();
+        for ino in dirty {
+            self.upload(ino)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bucket named by the first component of an absolute path
+fn bucket_of(path: &str) -> Option<&str> {
+    path.split('/').find(|c| !c.is_empty())
+}
+
+/// renterd takes S3-style bucket names: 3 to 63 lowercase letters, digits,
+/// dots and hyphens
+fn check_bucket_name(name: &str) -> Result<()> {
+    let valid = (3..=63).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-');
+    if valid {
+        Ok(())
+    } else {
+        Err(SiaFuseError::InvalidArgument(format!(
+            "'{}' is not a valid bucket name",
+            name
+        )))
+    }
+}
+
+impl Drop for SiaStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_all() {
+            tracing::error!("failed to upload pending writes: {}", e);
+        }
+    }
+}
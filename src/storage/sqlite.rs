@@ -0,0 +1,828 @@
+use super::{
+    dir_size, Children, Content, DirEntry, FileAttr, FileData, FileKind, InMemoryStorage, Inode,
+    Owner, Storage, StorageStats, ROOT_INODE,
+};
+use crate::error::{Result, SiaFuseError};
+use crate::fsid::FsUuid;
+use chrono::{DateTime, TimeDelta, Utc};
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long inodes no entry leads to are kept before being reclaimed
+pub const DEFAULT_ORPHAN_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Settings table row holding the filesystem's UUID
+const UUID_SETTING: &str = "uuid";
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// have run, so only new ones are applied when an older database is opened.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE inodes (
+        ino         INTEGER PRIMARY KEY,
+        parent      INTEGER NOT NULL,
+        kind        TEXT NOT NULL,
+        size        INTEGER NOT NULL,
+        perm        INTEGER NOT NULL,
+        nlink       INTEGER NOT NULL,
+        uid         INTEGER NOT NULL,
+        gid         INTEGER NOT NULL,
+        rdev        INTEGER NOT NULL,
+        flags       INTEGER NOT NULL,
+        atime       TEXT NOT NULL,
+        mtime       TEXT NOT NULL,
+        ctime       TEXT NOT NULL,
+        last_synced TEXT NOT NULL,
+        next_cookie INTEGER NOT NULL,
+        content     BLOB NOT NULL DEFAULT x''
+    );
+    CREATE TABLE entries (
+        parent INTEGER NOT NULL,
+        name   TEXT NOT NULL,
+        ino    INTEGER NOT NULL,
+        kind   TEXT NOT NULL,
+        cookie INTEGER NOT NULL,
+        PRIMARY KEY (parent, name)
+    );
+",
+    "
+    CREATE TABLE settings (
+        name  TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+",
+];
+
+/// Storage persisted in a sqlite database: inode metadata and directory
+/// entries in tables, file content in blobs.
+///
+/// The whole tree is loaded into an `InMemoryStorage` on open and serves
+/// every read. Namespace and attribute changes are written through as they
+/// happen; written content is saved when the file is flushed (see
+/// `mark_synced`) or the storage is synced, like a page cache.
+pub struct SqliteStorage {
+    cache: InMemoryStorage,
+    conn: Mutex<Connection>,
+    dirty: Mutex<HashSet<Inode>>, // Inodes whose content hasn't been saved
+}
+
+impl SqliteStorage {
+    /// Open the database at `path`, creating it if needed. `cache` supplies
+    /// limits such as the quota; its tree is replaced by the database's.
+    pub fn open(path: &Path, cache: InMemoryStorage) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        let storage = Self {
+            cache,
+            conn: Mutex::new(conn),
+            dirty: Mutex::new(HashSet::new()),
+        };
+
+        let conn = storage.conn.lock();
+        if !storage.load(&conn)? {
+            tracing::info!("Initializing new database at {}", path.display());
+            storage.save(&conn, &[ROOT_INODE])?;
+        }
+        if setting(&conn, UUID_SETTING)?.is_none() {
+            let uuid = FsUuid::generate()?;
+            tracing::info!("Generated filesystem UUID {}", uuid);
+            set_setting(&conn, UUID_SETTING, &uuid.to_string())?;
+        }
+        drop(conn);
+
+        Ok(storage)
+    }
+
+    /// The filesystem's UUID, generated when the database was created
+    pub fn uuid(&self) -> Result<FsUuid> {
+        let conn = self.conn.lock();
+        let value = setting(&conn, UUID_SETTING)?
+            .ok_or_else(|| SiaFuseError::Corrupted("filesystem UUID is missing".into()))?;
+        value.parse()
+    }
+
+    /// Replace the filesystem's UUID, e.g. to match an fstab entry
+    pub fn set_uuid(&self, uuid: FsUuid) -> Result<()> {
+        set_setting(&self.conn.lock(), UUID_SETTING, &uuid.to_string())
+    }
+
+    /// Delete inodes that no directory entry leads to, as a crash can
+    /// leave behind, once they have gone `grace` without a change. Younger
+    /// ones are kept for now. Returns how many were reclaimed.
+    pub fn reclaim_orphans(&self, grace: Duration) -> Result<usize> {
+        let conn = self.conn.lock();
+        let cutoff = TimeDelta::from_std(grace)
+            .ok()
+            .and_then(|grace| Utc::now().checked_sub_signed(grace))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+        let expired: Vec<Inode> = {
+            let mut files = self.cache.files.write();
+            let mut reachable = HashSet::new();
+            let mut stack = vec![ROOT_INODE];
+            while let Some(ino) = stack.pop() {
+                if let Some(file) = files.get(&ino) {
+                    if reachable.insert(ino) {
+                        stack.extend(file.children.values().map(|entry| entry.ino));
+                    }
+                }
+            }
+
+            let orphans: Vec<Inode> = files
+                .keys()
+                .filter(|ino| !reachable.contains(ino))
+                .copied()
+                .collect();
+            let expired: Vec<Inode> = orphans
+                .iter()
+                .filter(|ino| files[ino].attr.ctime <= cutoff)
+                .copied()
+                .collect();
+            if !orphans.is_empty() {
+                tracing::warn!(
+                    "found {} orphaned inodes, reclaiming {}",
+                    orphans.len(),
+                    expired.len()
+                );
+            }
+            for ino in &expired {
+                self.cache.remove_inode(&mut files, *ino);
+            }
+            expired
+        };
+
+        self.save(&conn, &expired)?;
+        Ok(expired.len())
+    }
+
+    /// Check the database at `path` for entries pointing at missing inodes,
+    /// inodes unreachable from the root and wrong link counts. With
+    /// `repair`, dangling entries are deleted and link counts corrected;
+    /// orphaned inodes are only reported, and reclaimed by the next mount
+    /// (see `reclaim_orphans`). The database must not be mounted.
+    pub fn fsck(path: &Path, repair: bool) -> Result<FsckReport> {
+        let flags = if repair {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        };
+        let conn = Connection::open_with_flags(path, flags)?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != MIGRATIONS.len() {
+            return Err(SiaFuseError::Config(format!(
+                "database schema version {} doesn't match this build ({}); mount it once first",
+                version,
+                MIGRATIONS.len()
+            )));
+        }
+
+        let mut inodes: HashMap<Inode, (FileKind, u32)> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT ino, kind, nlink FROM inodes")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let kind = kind_from_sql(&row.get::<_, String>(1)?)?;
+            inodes.insert(row.get(0)?, (kind, row.get(2)?));
+        }
+
+        let mut report = FsckReport::default();
+        let mut children: HashMap<Inode, Vec<Inode>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT parent, name, ino FROM entries")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let entry = DanglingEntry {
+                parent: row.get(0)?,
+                name: row.get(1)?,
+                ino: row.get(2)?,
+            };
+            let parent_is_dir = matches!(inodes.get(&entry.parent), Some((FileKind::Directory, _)));
+            if parent_is_dir && inodes.contains_key(&entry.ino) {
+                children.entry(entry.parent).or_default().push(entry.ino);
+            } else {
+                report.dangling_entries.push(entry);
+            }
+        }
+
+        // Walk from the root; whatever isn't reached is orphaned
+        let mut reachable = HashSet::new();
+        let mut stack = vec![ROOT_INODE];
+        while let Some(ino) = stack.pop() {
+            if inodes.contains_key(&ino) && reachable.insert(ino) {
+                stack.extend(children.get(&ino).into_iter().flatten());
+            }
+        }
+        report.orphaned_inodes = inodes
+            .keys()
+            .filter(|ino| !reachable.contains(ino))
+            .copied()
+            .collect();
+        report.orphaned_inodes.sort_unstable();
+
+        // A file has one link per entry; a directory 2 plus one per subdirectory
+        let mut links: HashMap<Inode, u32> = HashMap::new();
+        for (parent, inos) in &children {
+            for ino in inos {
+                match inodes[ino].0 {
+                    FileKind::Directory => *links.entry(*parent).or_default() += 1,
+                    _ => *links.entry(*ino).or_default() += 1,
+                }
+            }
+        }
+        for &ino in &reachable {
+            let (kind, nlink) = inodes[&ino];
+            let links = links.get(&ino).copied().unwrap_or(0);
+            let expected = match kind {
+                FileKind::Directory => 2 + links,
+                _ => links,
+            };
+            if nlink != expected {
+                report.wrong_nlink.push(WrongNlink {
+                    ino,
+                    nlink,
+                    expected,
+                });
+            }
+        }
+        report.wrong_nlink.sort_unstable_by_key(|wrong| wrong.ino);
+
+        if repair && !report.is_clean() {
+            let tx = conn.unchecked_transaction()?;
+            for entry in &report.dangling_entries {
+                tx.execute(
+                    "DELETE FROM entries WHERE parent = ?1 AND name = ?2",
+                    params![entry.parent, entry.name],
+                )?;
+            }
+            for wrong in &report.wrong_nlink {
+                tx.execute(
+                    "UPDATE inodes SET nlink = ?2 WHERE ino = ?1",
+                    params![wrong.ino, wrong.expected],
+                )?;
+            }
+            tx.commit()?;
+            report.repaired = true;
+        }
+
+        Ok(report)
+    }
+
+    /// Replace the cached tree with the database contents. Returns false
+    /// if the database holds no inodes yet.
+    fn load(&self, conn: &Connection) -> Result<bool> {
+        let mut files = HashMap::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT ino, parent, kind, size, perm, nlink, uid, gid, rdev, flags,
+                    atime, mtime, ctime, last_synced, next_cookie, content
+             FROM inodes",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let ino: Inode = row.get(0)?;
+            let attr = FileAttr {
+                ino,
+                size: row.get(3)?,
+                kind: kind_from_sql(&row.get::<_, String>(2)?)?,
+                perm: row.get(4)?,
+                nlink: row.get(5)?,
+                uid: row.get(6)?,
+                gid: row.get(7)?,
+                rdev: row.get(8)?,
+                flags: row.get(9)?,
+                atime: row.get(10)?,
+                mtime: row.get(11)?,
+                ctime: row.get(12)?,
+            };
+            files.insert(
+                ino,
+                FileData {
+                    attr,
+                    content: Arc::new(RwLock::new(Content::from_vec(row.get(15)?))),
+                    parent: row.get(1)?,
+                    children: Children::new(),
+                    next_cookie: row.get(14)?,
+                    last_synced: row.get(13)?,
+                },
+            );
+        }
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        let mut stmt =
+            conn.prepare("SELECT parent, name, ino, kind, cookie FROM entries ORDER BY cookie")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let parent: Inode = row.get(0)?;
+            let entry = DirEntry {
+                name: row.get(1)?,
+                ino: row.get(2)?,
+                kind: kind_from_sql(&row.get::<_, String>(3)?)?,
+                cookie: row.get(4)?,
+            };
+            match files.get_mut(&parent) {
+                Some(dir) => {
+                    dir.children.insert(entry.name.clone(), entry);
+                }
+                None => tracing::warn!(
+                    "dropping entry '{}' of missing inode {}",
+                    entry.name,
+                    parent
+                ),
+            }
+        }
+
+        // Databases written before directory sizes were tracked hold 0
+        for dir in files.values_mut() {
+            if dir.attr.kind == FileKind::Directory {
+                dir.attr.size = dir_size(&dir.children);
+            }
+        }
+
+        let next_inode = files.keys().max().map_or(ROOT_INODE, |max| max + 1);
+        tracing::info!("Loaded {} inodes from database", files.len());
+        self.cache.recount_used(&files);
+        *self.cache.files.write() = files;
+        *self.cache.next_inode.write() = next_inode;
+        Ok(true)
+    }
+
+    /// Write the current state of `inos` to the database in one
+    /// transaction, deleting those that no longer exist
+    fn save(&self, conn: &Connection, inos: &[Inode]) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+        let files = self.cache.files.read();
+        let mut dirty = self.dirty.lock();
+
+        for &ino in inos {
+            // Files unlinked while open are gone for good once unmounted
+            let Some(file) = files.get(&ino).filter(|file| file.attr.nlink > 0) else {
+                tx.execute("DELETE FROM inodes WHERE ino = ?1", [ino])?;
+                tx.execute("DELETE FROM entries WHERE parent = ?1", [ino])?;
+                dirty.remove(&ino);
+                continue;
+            };
+
+            let attr = &file.attr;
+            tx.execute(
+                "INSERT INTO inodes (ino, parent, kind, size, perm, nlink, uid, gid, rdev,
+                                     flags, atime, mtime, ctime, last_synced, next_cookie)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+                 ON CONFLICT (ino) DO UPDATE SET
+                     parent = excluded.parent, kind = excluded.kind, size = excluded.size,
+                     perm = excluded.perm, nlink = excluded.nlink, uid = excluded.uid,
+                     gid = excluded.gid, rdev = excluded.rdev, flags = excluded.flags,
+                     atime = excluded.atime, mtime = excluded.mtime, ctime = excluded.ctime,
+                     last_synced = excluded.last_synced, next_cookie = excluded.next_cookie",
+                params![
+                    ino,
+                    file.parent,
+                    kind_to_sql(attr.kind),
+                    attr.size,
+                    attr.perm,
+                    attr.nlink,
+                    attr.uid,
+                    attr.gid,
+                    attr.rdev,
+                    attr.flags,
+                    attr.atime,
+                    attr.mtime,
+                    attr.ctime,
+                    file.last_synced,
+                    file.next_cookie,
+                ],
+            )?;
+
+            if dirty.remove(&ino) {
+                tx.execute(
+                    "UPDATE inodes SET content = ?2 WHERE ino = ?1",
+                    params![ino, file.content.read().to_vec()],
+                )?;
+            }
+
+            if attr.kind == FileKind::Directory {
+                tx.execute("DELETE FROM entries WHERE parent = ?1", [ino])?;
+                let mut insert = tx.prepare_cached(
+                    "INSERT INTO entries (parent, name, ino, kind, cookie)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for entry in file.children.values() {
+                    insert.execute(params![
+                        ino,
+                        entry.name,
+                        entry.ino,
+                        kind_to_sql(entry.kind),
+                        entry.cookie,
+                    ])?;
+                }
+            }
+        }
+
+        drop(dirty);
+        drop(files);
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Save both directories and both entries involved in a rename
+    fn save_rename(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+        rename: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let conn = self.conn.lock();
+        let mut inos = vec![parent, newparent];
+        inos.extend(self.cache.lookup(parent, name).map(|a| a.ino));
+        inos.extend(self.cache.lookup(newparent, newname).map(|a| a.ino));
+        rename()?;
+        self.save(&conn, &inos)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn now(&self) -> DateTime<Utc> {
+        self.cache.now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        let dirty = self.dirty.lock();
+        StorageStats {
+            pending_uploads: dirty.len() as u64,
+            dirty_bytes: self.cache.total_size(dirty.iter()),
+            ..self.cache.stats()
+        }
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        self.cache.get_attr(ino)
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        let conn = self.conn.lock();
+        self.cache.set_attr(ino, attr)?;
+        self.save(&conn, &[ino])
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        let conn = self.conn.lock();
+        self.cache.touch(ino)?;
+        self.save(&conn, &[ino])
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        self.cache.last_synced(ino)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        let conn = self.conn.lock();
+        self.cache.mark_synced(ino)?;
+        self.save(&conn, &[ino])
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        self.cache.read_with(ino, offset, size, f)
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        self.cache.read_all(ino)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        let written = self.cache.write(ino, offset, data)?;
+        self.dirty.lock().insert(ino);
+        Ok(written)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        let appended = self.cache.append(ino, data)?;
+        self.dirty.lock().insert(ino);
+        Ok(appended)
+    }
+
+    fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: usize,
+        ino_out: Inode,
+        off_out: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let copied = self
+            .cache
+            .copy_range(ino_in, off_in, ino_out, off_out, len)?;
+        self.dirty.lock().insert(ino_out);
+        Ok(copied)
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        self.cache.truncate(ino, size)?;
+        self.dirty.lock().insert(ino);
+        Ok(())
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        self.cache.punch_hole(ino, offset, len)?;
+        self.dirty.lock().insert(ino);
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let conn = self.conn.lock();
+        let attr = self.cache.create_file(parent, name, perm, owner)?;
+        self.save(&conn, &[parent, attr.ino])?;
+        Ok(attr)
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        let conn = self.conn.lock();
+        let attr = self.cache.create_dir(parent, name, perm, owner)?;
+        self.save(&conn, &[parent, attr.ino])?;
+        Ok(attr)
+    }
+
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let conn = self.conn.lock();
+        let attr = self.cache.create_symlink(parent, name, target, owner)?;
+        self.dirty.lock().insert(attr.ino);
+        self.save(&conn, &[parent, attr.ino])?;
+        Ok(attr)
+    }
+
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        let conn = self.conn.lock();
+        let attr = self
+            .cache
+            .create_special(parent, name, kind, perm, rdev, owner)?;
+        self.save(&conn, &[parent, attr.ino])?;
+        Ok(attr)
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        self.cache.readlink(ino)
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        self.cache.read_dir(ino)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        self.cache.file_paths()
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        self.cache.checksum(ino)
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        self.cache.allocated_size(ino)
+    }
+
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.cache.seek_data(ino, offset)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        self.cache.seek_hole(ino, offset)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        self.cache.lookup(parent, name)
+    }
+
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        let mut inos = vec![parent];
+        inos.extend(self.cache.lookup(parent, name).map(|a| a.ino));
+        self.cache.unlink(parent, name)?;
+        self.save(&conn, &inos)
+    }
+
+    fn hold(&self, ino: Inode) {
+        self.cache.hold(ino)
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        self.cache.release(ino)
+    }
+
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr> {
+        let conn = self.conn.lock();
+        let attr = self.cache.link(ino, newparent, newname)?;
+        self.save(&conn, &[ino, newparent])?;
+        Ok(attr)
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        self.cache.path_of(ino)
+    }
+
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        let mut inos = vec![parent];
+        inos.extend(self.cache.lookup(parent, name).map(|a| a.ino));
+        self.cache.rmdir(parent, name)?;
+        self.save(&conn, &inos)
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        self.save_rename(parent, name, newparent, newname, || {
+            self.cache.rename(parent, name, newparent, newname)
+        })
+    }
+
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        self.save_rename(parent, name, newparent, newname, || {
+            self.cache
+                .rename_noreplace(parent, name, newparent, newname)
+        })
+    }
+
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        self.save_rename(parent, name, newparent, newname, || {
+            self.cache.exchange(parent, name, newparent, newname)
+        })
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        let conn = self.conn.lock();
+        let dirty: Vec<Inode> = self.dirty.lock().iter().copied().collect();
+        self.save(&conn, &dirty)
+    }
+}
+
+impl Drop for SqliteStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_all() {
+            tracing::error!("failed to save pending writes: {}", e);
+        }
+    }
+}
+
+/// Problems found by `SqliteStorage::fsck`
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub dangling_entries: Vec<DanglingEntry>,
+    pub orphaned_inodes: Vec<Inode>,
+    pub wrong_nlink: Vec<WrongNlink>,
+    pub repaired: bool, // Dangling entries and link counts were fixed
+}
+
+/// Directory entry whose inode or parent directory doesn't exist
+#[derive(Debug)]
+pub struct DanglingEntry {
+    pub parent: Inode,
+    pub name: String,
+    pub ino: Inode,
+}
+
+/// Inode whose stored link count doesn't match the entries naming it
+#[derive(Debug)]
+pub struct WrongNlink {
+    pub ino: Inode,
+    pub nlink: u32,
+    pub expected: u32,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.dangling_entries.is_empty()
+            && self.orphaned_inodes.is_empty()
+            && self.wrong_nlink.is_empty()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.dangling_entries {
+            writeln!(
+                f,
+                "dangling entry '{}' in inode {} -> inode {}",
+                entry.name, entry.parent, entry.ino
+            )?;
+        }
+        for ino in &self.orphaned_inodes {
+            writeln!(f, "orphaned inode {}", ino)?;
+        }
+        for wrong in &self.wrong_nlink {
+            writeln!(
+                f,
+                "inode {} has nlink {}, expected {}",
+                wrong.ino, wrong.nlink, wrong.expected
+            )?;
+        }
+
+        write!(
+            f,
+            "{} dangling entries, {} orphaned inodes, {} wrong link counts",
+            self.dangling_entries.len(),
+            self.orphaned_inodes.len(),
+            self.wrong_nlink.len()
+        )?;
+        if self.repaired {
+            write!(f, " (entries and link counts repaired)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring the schema up to date
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(SiaFuseError::Config(format!(
+            "database schema version {} is newer than this build supports ({})",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    let tx = conn.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("Applying database migration {}", i + 1);
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Value of a row in the settings table
+fn setting(conn: &Connection, name: &str) -> Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT value FROM settings WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn set_setting(conn: &Connection, name: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO settings (name, value) VALUES (?1, ?2)",
+        params![name, value],
+    )?;
+    Ok(())
+}
+
+fn kind_to_sql(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::File => "file",
+        FileKind::Directory => "dir",
+        FileKind::Symlink => "symlink",
+        FileKind::Fifo => "fifo",
+        FileKind::CharDevice => "chardev",
+        FileKind::BlockDevice => "blockdev",
+        FileKind::Socket => "socket",
+    }
+}
+
+fn kind_from_sql(kind: &str) -> Result<FileKind> {
+    match kind {
+        "file" => Ok(FileKind::File),
+        "dir" => Ok(FileKind::Directory),
+        "symlink" => Ok(FileKind::Symlink),
+        "fifo" => Ok(FileKind::Fifo),
+        "chardev" => Ok(FileKind::CharDevice),
+        "blockdev" => Ok(FileKind::BlockDevice),
+        "socket" => Ok(FileKind::Socket),
+        _ => Err(SiaFuseError::Backend(format!(
+            "unknown inode kind '{}' in database",
+            kind
+        ))),
+    }
+}
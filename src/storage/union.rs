@@ -0,0 +1,423 @@
+use super::{
+    DirEntry, FileAttr, FileKind, Inode, Owner, Storage, StorageStats, StoredObject, ROOT_INODE,
+};
+use crate::error::{Result, SiaFuseError};
+use crate::throttle::Bandwidth;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Set in the inode numbers of the overlay, keeping them apart from the
+/// base's
+const OVERLAY_BIT: Inode = 1 << 62;
+
+/// Cookie of the overlay's entry in the root, after any the base hands out
+const OVERLAY_COOKIE: u64 = i64::MAX as u64 - 2;
+
+/// A storage mounted as a directory in the root of another, e.g. a host
+/// directory served by `PassthroughStorage` next to the files kept in Sia.
+///
+/// Each inode belongs to one of the two and operations go to that one. The
+/// overlay's inode numbers have `OVERLAY_BIT` set so they can't collide
+/// with the base's. Renames and links between the two fail with EXDEV.
+pub struct UnionStorage {
+    base: Box<dyn Storage>,
+    overlay: Box<dyn Storage>,
+    name: String, // Directory in the base's root the overlay appears as
+}
+
+impl UnionStorage {
+    /// Show `overlay` as the directory `name` in the root of `base`. An
+    /// entry of that name in the base is hidden while mounted.
+    pub fn new(base: Box<dyn Storage>, overlay: Box<dyn Storage>, name: &str) -> Self {
+        if base.lookup(ROOT_INODE, name).is_some() {
+            tracing::warn!("'{}' is hidden by the overlay mounted over it", name);
+        }
+        Self {
+            base,
+            overlay,
+            name: name.to_string(),
+        }
+    }
+
+    /// The storage holding `ino`, and its number there
+    fn side(&self, ino: Inode) -> (&dyn Storage, Inode) {
+        if ino & OVERLAY_BIT != 0 {
+            (&*self.overlay, ino & !OVERLAY_BIT)
+        } else {
+            (&*self.base, ino)
+        }
+    }
+
+    fn is_overlay(&self, ino: Inode) -> bool {
+        ino & OVERLAY_BIT != 0
+    }
+
+    /// Whether `name` in `parent` is where the overlay is mounted
+    fn is_mount_point(&self, parent: Inode, name: &str) -> bool {
+        parent == ROOT_INODE && name == self.name
+    }
+
+    /// Number seen from outside of an inode of the storage `from`
+    fn outer(&self, from: &dyn Storage, ino: Inode) -> Inode {
+        if std::ptr::addr_eq(from, &*self.overlay) {
+            ino | OVERLAY_BIT
+        } else {
+            ino
+        }
+    }
+
+    fn outer_attr(&self, from: &dyn Storage, attr: FileAttr) -> FileAttr {
+        FileAttr {
+            ino: self.outer(from, attr.ino),
+            ..attr
+        }
+    }
+
+    /// The storage both `a` and `b` belong to, or EXDEV
+    fn same_side(&self, a: Inode, b: Inode) -> Result<(&dyn Storage, Inode, Inode)> {
+        if self.is_overlay(a) != self.is_overlay(b) {
+            return Err(SiaFuseError::CrossDevice);
+        }
+        let (storage, a) = self.side(a);
+        Ok((storage, a, self.side(b).1))
+    }
+
+    /// Resolve both ends of a rename, refusing to move the mount point
+    fn rename_sides(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<(&dyn Storage, Inode, Inode)> {
+        if self.is_mount_point(parent, name) || self.is_mount_point(newparent, newname) {
+            return Err(SiaFuseError::Busy);
+        }
+        self.same_side(parent, newparent)
+    }
+
+    /// Check a name about to be created in `parent`
+    fn check_new(&self, parent: Inode, name: &str) -> Result<()> {
+        if self.is_mount_point(parent, name) {
+            return Err(SiaFuseError::AlreadyExists);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for UnionStorage {
+    fn now(&self) -> DateTime<Utc> {
+        self.base.now()
+    }
+
+    fn stats(&self) -> StorageStats {
+        self.base.stats()
+    }
+
+    fn get_attr(&self, ino: Inode) -> Option<FileAttr> {
+        let (storage, ino) = self.side(ino);
+        storage
+            .get_attr(ino)
+            .map(|attr| self.outer_attr(storage, attr))
+    }
+
+    fn set_attr(&self, ino: Inode, attr: FileAttr) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.set_attr(ino, FileAttr { ino, ..attr })
+    }
+
+    fn touch(&self, ino: Inode) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.touch(ino)
+    }
+
+    fn last_synced(&self, ino: Inode) -> Option<DateTime<Utc>> {
+        let (storage, ino) = self.side(ino);
+        storage.last_synced(ino)
+    }
+
+    fn mark_synced(&self, ino: Inode) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.mark_synced(ino)
+    }
+
+    fn sync(&self, ino: Inode) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.sync(ino)
+    }
+
+    fn read(&self, ino: Inode, offset: usize, size: usize) -> Option<Vec<u8>> {
+        let (storage, ino) = self.side(ino);
+        storage.read(ino, offset, size)
+    }
+
+    fn read_with(&self, ino: Inode, offset: usize, size: usize, f: &mut dyn FnMut(&[u8])) -> bool {
+        let (storage, ino) = self.side(ino);
+        storage.read_with(ino, offset, size, f)
+    }
+
+    fn read_all(&self, ino: Inode) -> Option<Vec<u8>> {
+        let (storage, ino) = self.side(ino);
+        storage.read_all(ino)
+    }
+
+    fn write(&self, ino: Inode, offset: usize, data: &[u8]) -> Result<usize> {
+        let (storage, ino) = self.side(ino);
+        storage.write(ino, offset, data)
+    }
+
+    fn append(&self, ino: Inode, data: &[u8]) -> Result<(usize, usize)> {
+        let (storage, ino) = self.side(ino);
+        storage.append(ino, data)
+    }
+
+    fn truncate(&self, ino: Inode, size: u64) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.truncate(ino, size)
+    }
+
+    fn punch_hole(&self, ino: Inode, offset: u64, len: u64) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.punch_hole(ino, offset, len)
+    }
+
+    // Copies between the two fail with EXDEV, and callers such as cp fall
+    // back to reading and writing
+    fn copy_range(
+        &self,
+        ino_in: Inode,
+        off_in: usize,
+        ino_out: Inode,
+        off_out: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let (storage, ino_in, ino_out) = self.same_side(ino_in, ino_out)?;
+        storage.copy_range(ino_in, off_in, ino_out, off_out, len)
+    }
+
+    fn create_file(
+        &self,
+        parent: Inode,
+        name: String,
+        perm: u16,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.check_new(parent, &name)?;
+        let (storage, parent) = self.side(parent);
+        let attr = storage.create_file(parent, name, perm, owner)?;
+        Ok(self.outer_attr(storage, attr))
+    }
+
+    fn create_dir(&self, parent: Inode, name: String, perm: u16, owner: Owner) -> Result<FileAttr> {
+        self.check_new(parent, &name)?;
+        let (storage, parent) = self.side(parent);
+        let attr = storage.create_dir(parent, name, perm, owner)?;
+        Ok(self.outer_attr(storage, attr))
+    }
+
+    fn create_symlink(
+        &self,
+        parent: Inode,
+        name: String,
+        target: &str,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.check_new(parent, &name)?;
+        let (storage, parent) = self.side(parent);
+        let attr = storage.create_symlink(parent, name, target, owner)?;
+        Ok(self.outer_attr(storage, attr))
+    }
+
+    fn create_special(
+        &self,
+        parent: Inode,
+        name: String,
+        kind: FileKind,
+        perm: u16,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr> {
+        self.check_new(parent, &name)?;
+        let (storage, parent) = self.side(parent);
+        let attr = storage.create_special(parent, name, kind, perm, rdev, owner)?;
+        Ok(self.outer_attr(storage, attr))
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<Vec<u8>> {
+        let (storage, ino) = self.side(ino);
+        storage.readlink(ino)
+    }
+
+    fn read_dir(&self, ino: Inode) -> Option<Vec<DirEntry>> {
+        let (storage, inner) = self.side(ino);
+        let mut entries: Vec<DirEntry> = storage
+            .read_dir(inner)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                ino: self.outer(storage, entry.ino),
+                ..entry
+            })
+            .collect();
+        if ino == ROOT_INODE {
+            entries.retain(|entry| entry.name != self.name);
+            entries.push(DirEntry {
+                ino: ROOT_INODE | OVERLAY_BIT,
+                name: self.name.clone(),
+                kind: FileKind::Directory,
+                cookie: OVERLAY_COOKIE,
+            });
+        }
+        Some(entries)
+    }
+
+    fn file_paths(&self) -> Vec<(String, Inode)> {
+        let mut paths: Vec<(String, Inode)> = self
+            .base
+            .file_paths()
+            .into_iter()
+            .filter(|(path, _)| path.split('/').next() != Some(self.name.as_str()))
+            .collect();
+        paths.extend(
+            self.overlay
+                .file_paths()
+                .into_iter()
+                .map(|(path, ino)| (format!("{}/{}", self.name, path), ino | OVERLAY_BIT)),
+        );
+        paths.sort();
+        paths
+    }
+
+    fn checksum(&self, ino: Inode) -> Option<blake3::Hash> {
+        let (storage, ino) = self.side(ino);
+        storage.checksum(ino)
+    }
+
+    fn allocated_size(&self, ino: Inode) -> Option<u64> {
+        let (storage, ino) = self.side(ino);
+        storage.allocated_size(ino)
+    }
+
+    fn seek_data(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let (storage, ino) = self.side(ino);
+        storage.seek_data(ino, offset)
+    }
+
+    fn seek_hole(&self, ino: Inode, offset: u64) -> Result<Option<u64>> {
+        let (storage, ino) = self.side(ino);
+        storage.seek_hole(ino, offset)
+    }
+
+    fn lookup(&self, parent: Inode, name: &str) -> Option<FileAttr> {
+        if self.is_mount_point(parent, name) {
+            return self.get_attr(ROOT_INODE | OVERLAY_BIT);
+        }
+        let (storage, parent) = self.side(parent);
+        storage
+            .lookup(parent, name)
+            .map(|attr| self.outer_attr(storage, attr))
+    }
+
+    fn unlink(&self, parent: Inode, name: &str) -> Result<()> {
+        if self.is_mount_point(parent, name) {
+            return Err(SiaFuseError::IsADirectory);
+        }
+        let (storage, parent) = self.side(parent);
+        storage.unlink(parent, name)
+    }
+
+    fn link(&self, ino: Inode, newparent: Inode, newname: &str) -> Result<FileAttr> {
+        self.check_new(newparent, newname)?;
+        let (storage, ino, newparent) = self.same_side(ino, newparent)?;
+        let attr = storage.link(ino, newparent, newname)?;
+        Ok(self.outer_attr(storage, attr))
+    }
+
+    fn path_of(&self, ino: Inode) -> Option<PathBuf> {
+        let (storage, inner) = self.side(ino);
+        let path = storage.path_of(inner)?;
+        if !self.is_overlay(ino) {
+            return Some(path);
+        }
+        let relative = path.strip_prefix("/").unwrap_or(&path);
+        Some(Path::new("/").join(&self.name).join(relative))
+    }
+
+    fn rmdir(&self, parent: Inode, name: &str) -> Result<()> {
+        if self.is_mount_point(parent, name) {
+            return Err(SiaFuseError::Busy);
+        }
+        let (storage, parent) = self.side(parent);
+        storage.rmdir(parent, name)
+    }
+
+    fn rename(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        let (storage, parent, newparent) = self.rename_sides(parent, name, newparent, newname)?;
+        storage.rename(parent, name, newparent, newname)
+    }
+
+    fn rename_noreplace(
+        &self,
+        parent: Inode,
+        name: &str,
+        newparent: Inode,
+        newname: &str,
+    ) -> Result<()> {
+        let (storage, parent, newparent) = self.rename_sides(parent, name, newparent, newname)?;
+        storage.rename_noreplace(parent, name, newparent, newname)
+    }
+
+    fn exchange(&self, parent: Inode, name: &str, newparent: Inode, newname: &str) -> Result<()> {
+        let (storage, parent, newparent) = self.rename_sides(parent, name, newparent, newname)?;
+        storage.exchange(parent, name, newparent, newname)
+    }
+
+    fn hold(&self, ino: Inode) {
+        let (storage, ino) = self.side(ino);
+        storage.hold(ino)
+    }
+
+    fn release(&self, ino: Inode) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.release(ino)
+    }
+
+    fn readahead(&self, ino: Inode, offset: u64) {
+        let (storage, ino) = self.side(ino);
+        storage.readahead(ino, offset)
+    }
+
+    fn chunk_size(&self, ino: Inode) -> Option<u64> {
+        let (storage, ino) = self.side(ino);
+        storage.chunk_size(ino)
+    }
+
+    fn set_chunk_size(&self, ino: Inode, chunk_size: u64) -> Result<()> {
+        let (storage, ino) = self.side(ino);
+        storage.set_chunk_size(ino, chunk_size)
+    }
+
+    fn dedup_refs(&self, ino: Inode) -> Option<u64> {
+        let (storage, ino) = self.side(ino);
+        storage.dedup_refs(ino)
+    }
+
+    fn stored_object(&self, ino: Inode) -> Result<Option<StoredObject>> {
+        let (storage, ino) = self.side(ino);
+        storage.stored_object(ino)
+    }
+
+    fn bandwidth(&self) -> Option<Arc<Bandwidth>> {
+        self.base.bandwidth()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.base.check_writable()
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        let base = self.base.sync_all();
+        let overlay = self.overlay.sync_all();
+        base.and(overlay)
+    }
+}
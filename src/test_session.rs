@@ -0,0 +1,330 @@
+use crate::error::{Result, SiaFuseError};
+use crate::fuse_impl::{AttrChanges, SiaFuseFilesystem, ROOT_INODE};
+use crate::storage::{DirEntry, FileAttr, FileKind, Inode};
+use chrono::{DateTime, Utc};
+
+/// Drive a filesystem through plain method calls instead of a kernel mount,
+/// for integration tests in environments where FUSE is unavailable
+///
+/// An editor saving atomically writes a temporary file, then renames it
+/// over the original:
+///
+/// ```
+/// use sia_fuse_rs::{test_session, SiaFuseFilesystem};
+///
+/// let mut session = test_session(SiaFuseFilesystem::new());
+/// session.create("notes.txt", 0o644)?;
+/// session.write("notes.txt", 0, b"draft")?;
+///
+/// session.create(".notes.txt.swp", 0o600)?;
+/// session.write(".notes.txt.swp", 0, b"final")?;
+/// session.rename(".notes.txt.swp", "notes.txt")?;
+///
+/// assert_eq!(session.read_all("notes.txt")?, b"final");
+/// assert!(session.lookup(".notes.txt.swp").is_err());
+/// # Ok::<(), sia_fuse_rs::SiaFuseError>(())
+/// ```
+pub fn test_session(fs: SiaFuseFilesystem) -> TestSession {
+    TestSession {
+        fs,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        umask: 0o022,
+    }
+}
+
+/// Filesystem operations addressed by `/`-separated paths from the mount
+/// root. Each call goes through the same code as the matching FUSE handler.
+pub struct TestSession {
+    fs: SiaFuseFilesystem,
+    uid: u32,
+    gid: u32,
+    umask: u32,
+}
+
+impl TestSession {
+    /// Issue subsequent creates, writes and attribute changes as `uid`
+    pub fn as_uid(mut self, uid: u32) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    /// Check subsequent access calls and attribute changes against `gid`,
+    /// and create nodes in it
+    pub fn as_gid(mut self, gid: u32) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Umask the kernel would pass with subsequent creates
+    pub fn with_umask(mut self, umask: u32) -> Self {
+        self.umask = umask;
+        self
+    }
+
+    /// The filesystem being driven
+    pub fn filesystem(&self) -> &SiaFuseFilesystem {
+        &self.fs
+    }
+
+    pub fn lookup(&self, path: &str) -> Result<FileAttr> {
+        let mut attr = self.fs.attr(ROOT_INODE)?;
+        for name in components(path) {
+            if attr.kind != FileKind::Directory {
+                return Err(SiaFuseError::NotADirectory);
+            }
+            attr = self.fs.lookup_entry(attr.ino, name)?;
+        }
+        Ok(attr)
+    }
+
+    /// access(2) with `mask` for the session's uid and gid
+    pub fn access(&self, path: &str, mask: i32) -> Result<()> {
+        let attr = self.lookup(path)?;
+        self.fs.check_access(self.uid, self.gid, attr.ino, mask)
+    }
+
+    /// Create a file, and its missing parents when the filesystem has
+    /// `auto_mkdir` set
+    pub fn create(&self, path: &str, mode: u32) -> Result<FileAttr> {
+        self.fs.create_file_at(
+            self.fs.creator(self.uid, self.gid),
+            ROOT_INODE,
+            path,
+            mode,
+            self.umask,
+        )
+    }
+
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<FileAttr> {
+        self.create_node(path, FileKind::Directory, mode)
+    }
+
+    /// mknod(2): the file type comes from `mode`'s format bits
+    pub fn mknod(&self, path: &str, mode: u32, rdev: u32) -> Result<FileAttr> {
+        let (parent, name) = self.parent(path)?;
+        self.fs.make_node(
+            self.fs.creator(self.uid, self.gid),
+            parent,
+            name,
+            mode,
+            self.umask,
+            rdev,
+        )
+    }
+
+    pub fn symlink(&self, path: &str, target: &str) -> Result<FileAttr> {
+        let (parent, name) = self.parent(path)?;
+        self.fs
+            .create_symlink(self.fs.creator(self.uid, self.gid), parent, name, target)
+    }
+
+    pub fn readlink(&self, path: &str) -> Result<Vec<u8>> {
+        let attr = self.lookup(path)?;
+        self.fs.storage().readlink(attr.ino)
+    }
+
+    pub fn read(&self, path: &str, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let attr = self.lookup(path)?;
+        self.fs.read_data(0, attr.ino, offset, size)
+    }
+
+    /// Read the whole file
+    pub fn read_all(&self, path: &str) -> Result<Vec<u8>> {
+        let attr = self.lookup(path)?;
+        self.fs.read_to_end(attr.ino)
+    }
+
+    /// open(2) an existing file with `flags`, returning the handle
+    pub fn open(&mut self, path: &str, flags: i32) -> Result<u64> {
+        let attr = self.lookup(path)?;
+        self.fs.open_file(self.uid, self.gid, attr.ino, flags)
+    }
+
+    /// Close a handle from `open`
+    pub fn release(&mut self, fh: u64) -> Result<()> {
+        self.fs.release_file(fh)
+    }
+
+    /// Inode opened as `fh`, which works even after its names are gone
+    fn handle_ino(&self, fh: u64) -> Result<Inode> {
+        self.fs
+            .file_handle(fh)
+            .map(|handle| handle.ino)
+            .ok_or(SiaFuseError::BadFileHandle)
+    }
+
+    /// Read through a handle from `open`
+    pub fn read_handle(&mut self, fh: u64, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let data = self.fs.read_data(fh, self.handle_ino(fh)?, offset, size)?;
+        self.fs.track_read(fh, offset as u64, data.len());
+        Ok(data)
+    }
+
+    /// Write through a handle from `open`
+    pub fn write_handle(&self, fh: u64, offset: usize, data: &[u8]) -> Result<usize> {
+        self.fs
+            .write_handle(self.uid, fh, self.handle_ino(fh)?, offset, data)
+    }
+
+    /// Open the file for writing, write at `offset` and close it again
+    pub fn write(&mut self, path: &str, offset: usize, data: &[u8]) -> Result<usize> {
+        let fh = self.open(path, libc::O_WRONLY)?;
+        let written = self.write_handle(fh, offset, data);
+        self.release(fh)?;
+        written
+    }
+
+    pub fn truncate(&self, path: &str, size: u64) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let changes = AttrChanges {
+            size: Some(size),
+            ..Default::default()
+        };
+        self.fs.update_attr(self.uid, self.gid, attr.ino, changes)
+    }
+
+    /// fallocate(2) with `mode` over `offset..offset + length`
+    pub fn fallocate(&self, path: &str, offset: i64, length: i64, mode: i32) -> Result<()> {
+        let attr = self.lookup(path)?;
+        self.fs.allocate(attr.ino, offset, length, mode)
+    }
+
+    /// lseek(2) with SEEK_DATA or SEEK_HOLE
+    pub fn seek(&self, path: &str, offset: i64, whence: i32) -> Result<i64> {
+        let attr = self.lookup(path)?;
+        self.fs.seek(0, attr.ino, offset, whence)
+    }
+
+    /// copy_file_range(2) between two files, returning the bytes copied
+    pub fn copy_file_range(
+        &self,
+        from: &str,
+        off_in: i64,
+        to: &str,
+        off_out: i64,
+        len: u64,
+    ) -> Result<usize> {
+        let ino_in = self.lookup(from)?.ino;
+        let ino_out = self.lookup(to)?.ino;
+        self.fs.drop_setid(self.uid, ino_out)?;
+        self.fs.copy_range(ino_in, off_in, ino_out, off_out, len, 0)
+    }
+
+    pub fn chmod(&self, path: &str, mode: u32) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let changes = AttrChanges {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        self.fs.update_attr(self.uid, self.gid, attr.ino, changes)
+    }
+
+    /// Change the owner and/or group, like chown(2)
+    pub fn chown(&self, path: &str, uid: Option<u32>, gid: Option<u32>) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let changes = AttrChanges {
+            uid,
+            gid,
+            ..Default::default()
+        };
+        self.fs.update_attr(self.uid, self.gid, attr.ino, changes)
+    }
+
+    /// Set access and/or modification time, like utimensat(2)
+    pub fn set_times(
+        &self,
+        path: &str,
+        atime: Option<DateTime<Utc>>,
+        mtime: Option<DateTime<Utc>>,
+    ) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let changes = AttrChanges {
+            atime,
+            mtime,
+            ..Default::default()
+        };
+        self.fs.update_attr(self.uid, self.gid, attr.ino, changes)
+    }
+
+    /// Set both times to now, like utimensat(2) with UTIME_NOW
+    pub fn touch(&self, path: &str) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let now = self.fs.storage().now();
+        let changes = AttrChanges {
+            atime: Some(now),
+            mtime: Some(now),
+            times_now: true,
+            ..Default::default()
+        };
+        self.fs.update_attr(self.uid, self.gid, attr.ino, changes)
+    }
+
+    pub fn readdir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let attr = self.lookup(path)?;
+        self.fs
+            .dir_entries(attr.ino)
+            .ok_or(SiaFuseError::NotADirectory)
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<()> {
+        let (parent, name) = self.parent(path)?;
+        self.fs.storage().unlink(parent, name)
+    }
+
+    pub fn rmdir(&self, path: &str) -> Result<()> {
+        let (parent, name) = self.parent(path)?;
+        self.fs.storage().rmdir(parent, name)
+    }
+
+    /// Hard link `path` as `newpath`
+    pub fn link(&self, path: &str, newpath: &str) -> Result<FileAttr> {
+        let attr = self.lookup(path)?;
+        let (newparent, newname) = self.parent(newpath)?;
+        self.fs.link_entry(attr.ino, newparent, newname)
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.rename_with_flags(from, to, 0)
+    }
+
+    /// Rename with rename(2) flags such as `RENAME_EXCHANGE`
+    pub fn rename_with_flags(&self, from: &str, to: &str, flags: u32) -> Result<()> {
+        let (parent, name) = self.parent(from)?;
+        let (newparent, newname) = self.parent(to)?;
+        self.fs
+            .rename_entry(parent, name, newparent, newname, flags)
+    }
+
+    fn create_node(&self, path: &str, kind: FileKind, mode: u32) -> Result<FileAttr> {
+        let (parent, name) = self.parent(path)?;
+        self.fs.create_node(
+            self.fs.creator(self.uid, self.gid),
+            parent,
+            name,
+            kind,
+            mode,
+            self.umask,
+        )
+    }
+
+    /// Resolve the directory holding the last component of `path`
+    fn parent<'a>(&self, path: &'a str) -> Result<(Inode, &'a str)> {
+        // Keep a trailing `.` so it reaches the handler like it would from
+        // the kernel
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let name = components
+            .pop()
+            .ok_or_else(|| SiaFuseError::InvalidArgument(format!("no file name in '{}'", path)))?;
+
+        let dir = self.lookup(&components.join("/"))?;
+        if dir.kind != FileKind::Directory {
+            return Err(SiaFuseError::NotADirectory);
+        }
+        Ok((dir.ino, name))
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
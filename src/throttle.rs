@@ -0,0 +1,184 @@
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Bytes moved between checks of the limiter, so a transfer notices a
+/// changed rate quickly
+pub const PIECE: usize = 64 * 1024;
+
+/// Longest a transfer sleeps before looking at the rate again
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+thread_local! {
+    static BACKGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Mark the calling thread as a background worker, such as readahead or
+/// write-back uploads. Only transfers on such threads wait for the
+/// limits; others, like a read or fsync the FUSE session thread is
+/// serving, never sleep and only use up the rate the workers get.
+pub fn set_background() {
+    BACKGROUND.with(|background| background.set(true));
+}
+
+/// Whether the calling thread was marked with `set_background`
+pub fn is_background() -> bool {
+    BACKGROUND.with(Cell::get)
+}
+
+/// Token bucket limiting the bytes per second of every transfer sharing
+/// it. Up to a second's worth of unused rate is saved up as a burst. A
+/// rate of 0 means unlimited.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,   // Bytes per second, 0 for unlimited
+    tokens: f64, // Bytes that may go out now; negative after a large piece
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes per second allowed, 0 for unlimited
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().rate
+    }
+
+    /// Change the rate. Transfers in progress pick it up from their next
+    /// piece on.
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate as f64);
+    }
+
+    /// Count `bytes` transferred without waiting for them, leaving less of
+    /// the rate to transfers that do wait
+    pub fn consume(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock();
+        if bucket.rate == 0 {
+            return;
+        }
+        bucket.refill(Instant::now());
+        bucket.tokens = (bucket.tokens - bytes as f64).max(-(bucket.rate as f64));
+    }
+
+    /// Wait until `bytes` may be transferred. Pieces larger than the burst
+    /// go out once the bucket is full and leave it in debt.
+    pub fn acquire(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock();
+                if bucket.rate == 0 {
+                    return;
+                }
+                bucket.refill(Instant::now());
+                let needed = (bytes as f64).min(bucket.rate as f64);
+                if bucket.tokens >= needed {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64((needed - bucket.tokens) / bucket.rate as f64)
+            };
+            std::thread::sleep(wait.min(MAX_WAIT));
+        }
+    }
+}
+
+/// Upload and download limits of a backend, shared by all its transfers
+#[derive(Debug)]
+pub struct Bandwidth {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl Bandwidth {
+    /// Limits in bytes per second; 0 leaves a direction unlimited
+    pub fn new(upload: u64, download: u64) -> Self {
+        Self {
+            upload: RateLimiter::new(upload),
+            download: RateLimiter::new(download),
+        }
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+/// Reader handing out its inner reader's bytes no faster than one of the
+/// limits allows, a piece at a time. Created off a background thread (see
+/// `set_background`) it only counts the bytes against the limit.
+pub struct ThrottledReader<R> {
+    inner: R,
+    bandwidth: Arc<Bandwidth>,
+    upload: bool,    // Which of the limits applies
+    throttled: bool, // Waits for the limit, rather than only counting
+}
+
+impl<R> ThrottledReader<R> {
+    /// Read `inner` within the upload limit of `bandwidth`
+    pub fn upload(inner: R, bandwidth: Arc<Bandwidth>) -> Self {
+        Self {
+            inner,
+            bandwidth,
+            upload: true,
+            throttled: is_background(),
+        }
+    }
+
+    /// Read `inner` within the download limit of `bandwidth`
+    pub fn download(inner: R, bandwidth: Arc<Bandwidth>) -> Self {
+        Self {
+            inner,
+            bandwidth,
+            upload: false,
+            throttled: is_background(),
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(PIECE);
+        let read = self.inner.read(&mut buf[..len])?;
+        let limiter = match self.upload {
+            true => &self.bandwidth.upload,
+            false => &self.bandwidth.download,
+        };
+        match self.throttled {
+            true => limiter.acquire(read),
+            false => limiter.consume(read),
+        }
+        Ok(read)
+    }
+}